@@ -0,0 +1,135 @@
+//! Who may query us: allow and deny lists of CIDR prefixes, one set for
+//! every listener plus optional replacements for single listeners and
+//! plain DNS addresses, so an exposed resolver doesn't answer the whole
+//! internet.
+
+use std::net::{IpAddr, SocketAddr};
+
+use clap::ValueEnum;
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::transport::Transport;
+
+/// What a client that may not query gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DenyAction {
+    /// Answer with REFUSED.
+    Refuse,
+    /// Send nothing back, so spoofed sources get no traffic either.
+    Drop,
+}
+
+/// Why a client was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DenyReason {
+    /// The client is on a deny list.
+    Denied,
+    /// There is an allow list and the client is not on it.
+    NotAllowed,
+}
+
+impl DenyReason {
+    pub fn label(self) -> &'static str {
+        match self {
+            DenyReason::Denied => "denied",
+            DenyReason::NotAllowed => "not_allowed",
+        }
+    }
+}
+
+/// Allow and deny lists for one listener. Deny entries win over allow
+/// entries, and an empty allow list lets everyone in.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl Acl {
+    pub fn new(allow: Vec<IpNet>, deny: Vec<IpNet>) -> Self {
+        Self {
+            allow: allow.into_iter().map(|net| net.trunc()).collect(),
+            deny: deny.into_iter().map(|net| net.trunc()).collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn check(&self, ip: IpAddr) -> Option<DenyReason> {
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return Some(DenyReason::Denied);
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|net| net.contains(&ip)) {
+            return Some(DenyReason::NotAllowed);
+        }
+        None
+    }
+}
+
+/// The lists for every listener.
+#[derive(Debug, Clone)]
+pub struct AccessControl {
+    default: Acl,
+    /// Listeners with lists of their own, used instead of the default.
+    listeners: Vec<(Transport, Acl)>,
+    /// Plain DNS addresses with lists of their own, used instead of any
+    /// others.
+    addresses: Vec<(SocketAddr, Acl)>,
+    pub action: DenyAction,
+}
+
+impl AccessControl {
+    pub fn new(default: Acl, action: DenyAction) -> Self {
+        Self {
+            default,
+            listeners: Vec::new(),
+            addresses: Vec::new(),
+            action,
+        }
+    }
+
+    /// Gives `transport` its own lists, unless both are empty.
+    pub fn with_listener(mut self, transport: Transport, acl: Acl) -> Self {
+        if !acl.is_empty() {
+            self.listeners.push((transport, acl));
+        }
+        self
+    }
+
+    /// Gives the plain DNS socket bound to `addr` its own lists, unless
+    /// both are empty.
+    pub fn with_address(mut self, addr: SocketAddr, acl: Acl) -> Self {
+        if !acl.is_empty() {
+            self.addresses.push((addr, acl));
+        }
+        self
+    }
+
+    /// Whether `ip` may query over `transport`, arriving on the plain DNS
+    /// address `local` if it did, and if not, why.
+    pub fn check(
+        &self,
+        transport: Transport,
+        local: Option<SocketAddr>,
+        ip: IpAddr,
+    ) -> Option<DenyReason> {
+        let address = local.and_then(|local| {
+            self.addresses
+                .iter()
+                .find(|(addr, _)| *addr == local)
+                .map(|(_, acl)| acl)
+        });
+        let listener = || {
+            self.listeners
+                .iter()
+                .find(|(listener, _)| *listener == transport)
+                .map(|(_, acl)| acl)
+        };
+        address.or_else(listener).unwrap_or(&self.default).check(ip)
+    }
+}
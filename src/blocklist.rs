@@ -1,55 +1,738 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeSet, HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
     sync::{Arc, RwLock},
+    time::Duration,
 };
 
-use crate::packet::Question;
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use ipnet::IpNet;
+use reqwest::{StatusCode, header};
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::sync::{Mutex, Notify, broadcast};
 
+use crate::metrics;
+use crate::packet::{
+    Answer, DNSPacket, EDE_BLOCKED, Question, RCODE_NOERROR, RCODE_NXDOMAIN, RCODE_REFUSED, TYPE_A,
+    TYPE_AAAA, TYPE_CNAME, name_to_string,
+};
+use crate::rewrite::RewriteRules;
+use crate::schedule::TimeWindow;
+
+/// How long connecting to a list's server may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long downloading a list may take in all.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How a query for a blocked domain is answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BlockMode {
+    /// Pretend the domain does not exist.
+    Nxdomain,
+    /// Refuse to answer the query.
+    Refused,
+    /// Answer with no records.
+    Nodata,
+    /// Answer A with 0.0.0.0 and AAAA with ::.
+    NullIp,
+    /// Answer A and AAAA with the configured sink addresses.
+    Sink,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockResponse {
+    pub mode: BlockMode,
+    pub sink_ipv4: Ipv4Addr,
+    pub sink_ipv6: Ipv6Addr,
+    pub ttl: u32,
+    /// Attach an Extended DNS Error "Blocked" option for EDNS clients.
+    pub ede: bool,
+}
+
+impl BlockResponse {
+    /// Rewrites the query in `packet` into the configured blocked response.
+    pub fn apply(&self, packet: &mut DNSPacket) {
+        let question = packet.questions[0].clone();
+        let client_edns = packet.edns().map(|opt| opt.class);
+
+        let (rcode, answers) = match self.mode {
+            BlockMode::Nxdomain => (RCODE_NXDOMAIN, Vec::new()),
+            BlockMode::Refused => (RCODE_REFUSED, Vec::new()),
+            BlockMode::Nodata => (RCODE_NOERROR, Vec::new()),
+            BlockMode::NullIp => (
+                RCODE_NOERROR,
+                self.address_answers(&question, Ipv4Addr::UNSPECIFIED, Ipv6Addr::UNSPECIFIED),
+            ),
+            BlockMode::Sink => (
+                RCODE_NOERROR,
+                self.address_answers(&question, self.sink_ipv4, self.sink_ipv6),
+            ),
+        };
+
+        packet.make_response(rcode, answers);
+
+        // Negative answers carry an SOA so downstream resolvers cache them
+        // for the block TTL rather than their own default.
+        if packet.answers.is_empty() && rcode != RCODE_REFUSED {
+            packet
+                .authorities
+                .push(Answer::soa(&question.name, self.ttl));
+        }
+
+        if self.ede
+            && let Some(payload_size) = client_edns
+        {
+            packet.resources.push(Answer::opt(
+                payload_size.max(512),
+                Answer::ede_option(EDE_BLOCKED, ""),
+            ));
+        }
+
+        packet.update_counts();
+    }
+
+    fn address_answers(&self, q: &Question, ipv4: Ipv4Addr, ipv6: Ipv6Addr) -> Vec<Answer> {
+        match q.tp {
+            TYPE_A => vec![Answer::new(
+                q.name.clone(),
+                TYPE_A,
+                self.ttl,
+                ipv4.octets().to_vec(),
+            )],
+            TYPE_AAAA => vec![Answer::new(
+                q.name.clone(),
+                TYPE_AAAA,
+                self.ttl,
+                ipv6.octets().to_vec(),
+            )],
+            // There is no meaningful address for MX, TXT, HTTPS, ... so
+            // those get an empty NOERROR answer.
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Error raised while refreshing a single blocklist source.
+#[derive(Debug, Error)]
+pub enum BlocklistError {
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("unexpected HTTP status {0}")]
+    Status(StatusCode),
+    #[error("failed to read list: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to write cache: {0}")]
+    CacheWrite(std::io::Error),
+}
+
+/// Result of refreshing one blocklist source.
+#[derive(Debug)]
+pub enum UpdateOutcome {
+    /// The list was downloaded and parsed into this many entries.
+    Updated(usize),
+    /// The server answered 304, the previous copy is still current.
+    NotModified,
+}
+
+/// A list the blocklist is built from, together with the validators
+/// needed for conditional requests and its last successfully parsed set.
+struct BlocklistSource {
+    list: String,
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    entries: ListEntries,
+}
+
+/// The contents of a list: domain names, and networks that answers must
+/// not point into.
+#[derive(Debug, Default)]
+struct ListEntries {
+    domains: HashSet<String>,
+    networks: HashSet<IpNet>,
+    /// Prefix lengths present in `networks`.
+    prefix_lens: BTreeSet<u8>,
+}
+
+impl ListEntries {
+    fn len(&self) -> usize {
+        self.domains.len() + self.networks.len()
+    }
+
+    fn insert_network(&mut self, net: IpNet) {
+        self.prefix_lens.insert(net.prefix_len());
+        self.networks.insert(net.trunc());
+    }
+
+    fn extend(&mut self, other: &ListEntries) {
+        self.domains.extend(other.domains.iter().cloned());
+        self.networks.extend(other.networks.iter().copied());
+        self.prefix_lens.extend(other.prefix_lens.iter().copied());
+    }
+
+    /// Whether `ip` falls inside a listed network. Probes each prefix
+    /// length in use, so the cost does not grow with the list size.
+    fn contains_ip(&self, ip: IpAddr) -> bool {
+        self.prefix_lens
+            .iter()
+            .any(|&len| IpNet::new(ip, len).is_ok_and(|net| self.networks.contains(&net.trunc())))
+    }
+}
+
+/// Why an upstream answer was blocked even though the question was not.
+#[derive(Debug)]
+pub enum AnswerBlock {
+    /// The CNAME chain passes through a blocked name.
+    Cname(String),
+    /// An A/AAAA record points at a blocked address.
+    Ip(IpAddr),
+}
+
+impl AnswerBlock {
+    /// Metric label for the kind of block.
+    pub fn label(&self) -> &'static str {
+        match self {
+            AnswerBlock::Cname(_) => "cname",
+            AnswerBlock::Ip(_) => "ip",
+        }
+    }
+}
+
+impl std::fmt::Display for AnswerBlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AnswerBlock::Cname(name) => write!(f, "via CNAME {}", name),
+            AnswerBlock::Ip(ip) => write!(f, "via address {}", ip),
+        }
+    }
+}
+
+impl BlocklistSource {
+    fn new(list: String, url: String) -> Self {
+        Self {
+            list,
+            url,
+            etag: None,
+            last_modified: None,
+            entries: ListEntries::default(),
+        }
+    }
+
+    /// A copy with the validators but none of the entries, to fetch into.
+    fn without_entries(&self) -> Self {
+        Self {
+            list: self.list.clone(),
+            url: self.url.clone(),
+            etag: self.etag.clone(),
+            last_modified: self.last_modified.clone(),
+            entries: ListEntries::default(),
+        }
+    }
+
+    fn is_remote(&self) -> bool {
+        self.url.starts_with("http://") || self.url.starts_with("https://")
+    }
+
+    /// File in `dir` holding this source's last good domains and validators.
+    fn cache_path(&self, dir: &Path) -> PathBuf {
+        let name: String = self
+            .url
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        dir.join(format!("{}.list", name))
+    }
+
+    /// Reads a local source's file in place of the first refresh.
+    fn load_local(&mut self) -> std::io::Result<usize> {
+        let body = std::fs::read_to_string(&self.url)?;
+        self.entries = parse_list(&body);
+        Ok(self.entries.len())
+    }
+
+    fn load_cache(&mut self, dir: &Path) -> std::io::Result<usize> {
+        let body = std::fs::read_to_string(self.cache_path(dir))?;
+        for line in body.lines().take_while(|l| l.starts_with('#')) {
+            if let Some(etag) = line.strip_prefix("# etag: ") {
+                self.etag = Some(etag.to_string());
+            } else if let Some(last_modified) = line.strip_prefix("# last-modified: ") {
+                self.last_modified = Some(last_modified.to_string());
+            }
+        }
+        self.entries = parse_list(&body);
+        Ok(self.entries.len())
+    }
+
+    async fn save_cache(&self, dir: &Path) -> std::io::Result<()> {
+        let mut body = format!("# rdns blocklist cache\n# url: {}\n", self.url);
+        if let Some(etag) = &self.etag {
+            body.push_str(&format!("# etag: {}\n", etag));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            body.push_str(&format!("# last-modified: {}\n", last_modified));
+        }
+        for domain in &self.entries.domains {
+            body.push_str(domain);
+            body.push('\n');
+        }
+        for net in &self.entries.networks {
+            body.push_str(&net.to_string());
+            body.push('\n');
+        }
+
+        // Write then rename so a crash mid-write never leaves a truncated list.
+        tokio::fs::create_dir_all(dir).await?;
+        let path = self.cache_path(dir);
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, body).await?;
+        tokio::fs::rename(&tmp, &path).await
+    }
+}
+
+/// Which lists apply to a client and how its blocked queries are answered.
+#[derive(Debug, Clone)]
+pub struct FilterPolicy {
+    pub blocklists: Vec<String>,
+    /// Lists of domains that are never blocked, whatever the blocklists say.
+    pub allowlists: Vec<String>,
+    /// Blocklists that are only in force during the given windows.
+    pub schedules: HashMap<String, Vec<TimeWindow>>,
+    /// Names answered with a CNAME to another name instead.
+    pub rewrites: RewriteRules,
+    pub response: BlockResponse,
+}
+
+impl FilterPolicy {
+    /// Whether blocklist `name` is in force at `now` according to its schedule.
+    pub fn is_scheduled(&self, name: &str, now: &DateTime<Local>) -> bool {
+        self.schedules
+            .get(name)
+            .is_none_or(|windows| windows.iter().any(|w| w.contains(now)))
+    }
+}
+
+/// Named domain lists, each built from one or more sources. Whether a list
+/// blocks or allows is decided by the `FilterPolicy` that references it.
 pub struct DNSBlocklist {
-    store: Arc<RwLock<HashSet<String>>>,
+    store: Arc<RwLock<HashMap<String, ListEntries>>>,
+    sources: Mutex<Vec<BlocklistSource>>,
+    cache_dir: Option<PathBuf>,
+    client: reqwest::Client,
+    paused_until: RwLock<Option<DateTime<Local>>>,
+    /// Wakes `run_refresh` before its next scheduled refresh.
+    refresh: Notify,
 }
 
 impl DNSBlocklist {
-    pub fn new() -> Self {
+    pub fn new(lists: Vec<(String, Vec<String>)>, cache_dir: Option<PathBuf>) -> Self {
+        let sources = lists
+            .into_iter()
+            .flat_map(|(list, urls)| {
+                urls.into_iter()
+                    .map(move |url| BlocklistSource::new(list.clone(), url))
+            })
+            .collect();
         Self {
-            store: Arc::new(RwLock::new(HashSet::new())),
+            store: Arc::new(RwLock::new(HashMap::new())),
+            sources: Mutex::new(sources),
+            cache_dir,
+            client: reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .timeout(FETCH_TIMEOUT)
+                .build()
+                .expect("HTTP client with default settings"),
+            paused_until: RwLock::new(None),
+            refresh: Notify::new(),
         }
     }
 
-    pub async fn update(&self) {
-        let url = "https://raw.githubusercontent.com/StevenBlack/hosts/master/hosts";
+    /// Replaces the configured lists and asks for a refresh. Sources that
+    /// were already configured keep their entries and validators; new ones
+    /// start from their on-disk copy until the refresh fetches them, and
+    /// new local files are read straight away.
+    pub async fn set_lists(
+        &self,
+        lists: Vec<(String, Vec<String>)>,
+    ) -> Vec<(String, std::io::Result<usize>)> {
+        let mut sources = self.sources.lock().await;
+        let mut previous = std::mem::take(&mut *sources);
+        let mut results = Vec::new();
 
-        match reqwest::get(url).await {
-            Ok(res) => {
-                if let Ok(body) = res.text().await {
-                    let mut new_domains = HashSet::new();
-                    for line in body.lines() {
-                        let line = line.trim();
-                        if line.is_empty() || line.starts_with('#') {
-                            continue;
-                        }
-                        // Hosts file format: 0.0.0.0 domain.com
-                        let parts: Vec<&str> = line.split_whitespace().collect();
-                        if parts.len() >= 2 && parts[0] == "0.0.0.0" {
-                            new_domains.insert(parts[1].to_string());
+        for (list, urls) in lists {
+            for url in urls {
+                let kept = previous
+                    .iter()
+                    .position(|source| source.list == list && source.url == url);
+                let source = match kept {
+                    Some(index) => previous.swap_remove(index),
+                    None => {
+                        let mut source = BlocklistSource::new(list.clone(), url);
+                        if !source.is_remote() {
+                            // The refresh reports a file that cannot be read.
+                            let _ = source.load_local();
+                        } else if let Some(dir) = &self.cache_dir {
+                            results.push((source.url.clone(), source.load_cache(dir)));
                         }
+                        source
                     }
-                    let mut store = self.store.write().unwrap();
-                    *store = new_domains;
-                }
+                };
+                sources.push(source);
+            }
+        }
+
+        self.replace(merge(&sources));
+        self.refresh.notify_one();
+        results
+    }
+
+    /// Synchronously reads the local sources and the on-disk copies of the
+    /// remote ones so filtering works before the first (possibly failing)
+    /// download. Only the remote sources' results are returned; the
+    /// refresh reports a local file that cannot be read.
+    pub fn load_cache(&mut self) -> Vec<(String, std::io::Result<usize>)> {
+        let dir = self.cache_dir.clone();
+        let sources = self.sources.get_mut();
+        let mut results = Vec::new();
+        for source in sources.iter_mut() {
+            if !source.is_remote() {
+                let _ = source.load_local();
+            } else if let Some(dir) = &dir {
+                results.push((source.url.clone(), source.load_cache(dir)));
+            }
+        }
+        let domains = merge(sources);
+        self.replace(domains);
+        results
+    }
+
+    /// Refreshes every source and rebuilds the active set. Sources that
+    /// fail keep contributing the entries from their last good fetch.
+    /// Downloads happen without holding the sources, so a reload is not
+    /// held up by a slow server.
+    pub async fn update(&self) -> Vec<(String, Result<UpdateOutcome, BlocklistError>)> {
+        let fetching: Vec<_> = self
+            .sources
+            .lock()
+            .await
+            .iter()
+            .map(BlocklistSource::without_entries)
+            .collect();
+        let mut results = Vec::with_capacity(fetching.len());
+
+        for mut fetched in fetching {
+            let result = self.fetch(&mut fetched).await;
+            let updated = matches!(result, Ok(UpdateOutcome::Updated(_)));
+            results.push((fetched.url.clone(), result));
+            if !updated {
+                continue;
             }
-            Err(_) => {
+
+            // A reload may have dropped the source in the meantime.
+            let mut sources = self.sources.lock().await;
+            let Some(source) = sources
+                .iter_mut()
+                .find(|source| source.list == fetched.list && source.url == fetched.url)
+            else {
+                continue;
+            };
+            *source = fetched;
+            if source.is_remote()
+                && let Some(dir) = &self.cache_dir
+                && let Err(e) = source.save_cache(dir).await
+            {
+                results.push((source.url.clone(), Err(BlocklistError::CacheWrite(e))));
             }
         }
+
+        self.replace(merge(&self.sources.lock().await));
+        results
+    }
+
+    fn replace(&self, lists: HashMap<String, ListEntries>) {
+        metrics::BLOCKLIST_DOMAINS.set(lists.values().map(ListEntries::len).sum::<usize>() as f64);
+        let mut store = self.store.write().unwrap();
+        *store = lists;
+    }
+
+    async fn fetch(&self, source: &mut BlocklistSource) -> Result<UpdateOutcome, BlocklistError> {
+        if !source.is_remote() {
+            let body = tokio::fs::read_to_string(&source.url).await?;
+            source.entries = parse_list(&body);
+            return Ok(UpdateOutcome::Updated(source.entries.len()));
+        }
+
+        let mut request = self.client.get(&source.url);
+        if let Some(etag) = &source.etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &source.last_modified {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+
+        let res = request.send().await?;
+        if res.status() == StatusCode::NOT_MODIFIED {
+            return Ok(UpdateOutcome::NotModified);
+        }
+        if !res.status().is_success() {
+            return Err(BlocklistError::Status(res.status()));
+        }
+
+        let header_value = |name| {
+            res.headers()
+                .get(name)
+                .and_then(|v: &header::HeaderValue| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header_value(header::ETAG);
+        let last_modified = header_value(header::LAST_MODIFIED);
+
+        let body = res.text().await?;
+        source.entries = parse_list(&body);
+        source.etag = etag;
+        source.last_modified = last_modified;
+        Ok(UpdateOutcome::Updated(source.entries.len()))
+    }
+
+    /// Whether `q` is on one of the policy's currently scheduled blocklists
+    /// and none of its allowlists. Nothing is blocked while paused.
+    pub fn is_blocked(&self, q: &Question, policy: &FilterPolicy) -> bool {
+        if self.paused_until().is_some() {
+            return false;
+        }
+        // The lists are lowercased, while clients may randomize case.
+        let domain = q.domain().to_ascii_lowercase();
+        let lists = self.store.read().unwrap();
+        Self::matches(&lists, policy, |entries| entries.domains.contains(&domain))
     }
 
-    pub fn contains(&self, q: &Question) -> bool {
-        let blocklist = self.store.read().unwrap();
-        return blocklist.contains(&q.to_string().replace("question=", ""));
+    /// Checks the answers to an allowed question for trackers hiding behind
+    /// a CNAME to a blocked name, or records pointing at a blocked address.
+    /// Cached answers are checked again on every hit, so a cloaked reply
+    /// stays blocked for as long as it is cached.
+    pub fn check_answers(&self, answers: &[Answer], policy: &FilterPolicy) -> Option<AnswerBlock> {
+        if self.paused_until().is_some() {
+            return None;
+        }
+        let lists = self.store.read().unwrap();
+
+        for answer in answers {
+            match answer.tp {
+                TYPE_CNAME => {
+                    let target = name_to_string(&answer.data).to_ascii_lowercase();
+                    if Self::matches(&lists, policy, |entries| entries.domains.contains(&target)) {
+                        return Some(AnswerBlock::Cname(target));
+                    }
+                }
+                TYPE_A | TYPE_AAAA => {
+                    let Some(ip) = answer.ip() else {
+                        continue;
+                    };
+                    if Self::matches(&lists, policy, |entries| entries.contains_ip(ip)) {
+                        return Some(AnswerBlock::Ip(ip));
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Whether `listed` holds for a scheduled blocklist and no allowlist.
+    fn matches(
+        lists: &HashMap<String, ListEntries>,
+        policy: &FilterPolicy,
+        listed: impl Fn(&ListEntries) -> bool,
+    ) -> bool {
+        let now = Local::now();
+        let listed = |name: &String| lists.get(name).is_some_and(&listed);
+
+        !policy.allowlists.iter().any(listed)
+            && policy
+                .blocklists
+                .iter()
+                .filter(|name| policy.is_scheduled(name, &now))
+                .any(listed)
+    }
+
+    /// Stops blocking for every client until `until`.
+    pub fn pause_until(&self, until: DateTime<Local>) {
+        *self.paused_until.write().unwrap() = Some(until);
+    }
+
+    pub fn resume(&self) {
+        *self.paused_until.write().unwrap() = None;
+    }
+
+    /// When blocking resumes, if it is currently paused.
+    pub fn paused_until(&self) -> Option<DateTime<Local>> {
+        let until = *self.paused_until.read().unwrap();
+        until.filter(|until| *until > Local::now())
     }
 
     pub fn len(&self) -> usize {
-        let blocklist = self.store.read().unwrap();
-        blocklist.len()
+        let lists = self.store.read().unwrap();
+        lists.values().map(ListEntries::len).sum()
+    }
+}
+
+/// Unions the entries of each list's sources.
+fn merge(sources: &[BlocklistSource]) -> HashMap<String, ListEntries> {
+    let mut lists: HashMap<String, ListEntries> = HashMap::new();
+    for source in sources.iter() {
+        lists
+            .entry(source.list.clone())
+            .or_default()
+            .extend(&source.entries);
+    }
+    lists
+}
+
+/// Parses a hosts-style (`0.0.0.0 domain.com`) or plain one-entry-per-line
+/// list. A line holding only an IP address or CIDR range blocks answers
+/// pointing into it.
+fn parse_list(body: &str) -> ListEntries {
+    let mut entries = ListEntries::default();
+    for line in body.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let domain = match parts.as_slice() {
+            [ip, domain, ..] if ip.parse::<IpAddr>().is_ok() => domain,
+            [entry] => {
+                if let Ok(ip) = entry.parse::<IpAddr>() {
+                    entries.insert_network(IpNet::from(ip.to_canonical()));
+                    continue;
+                }
+                if let Ok(net) = entry.parse::<IpNet>() {
+                    entries.insert_network(net);
+                    continue;
+                }
+                entry
+            }
+            _ => continue,
+        };
+        if domain.parse::<IpAddr>().is_ok() || *domain == "localhost" {
+            continue;
+        }
+        entries
+            .domains
+            .insert(domain.trim_end_matches('.').to_ascii_lowercase());
+    }
+    entries
+}
+
+/// Periodically refreshes `blocklist`, and whenever `set_lists` asks for
+/// it. After a failed refresh the next attempt is retried with exponential
+/// backoff, capped at `interval`.
+pub async fn run_refresh(
+    blocklist: Arc<DNSBlocklist>,
+    interval: Duration,
+    log_tx: broadcast::Sender<String>,
+) {
+    let min_backoff = Duration::from_secs(30).min(interval);
+    let mut backoff = min_backoff;
+
+    loop {
+        let results = blocklist.update().await;
+        let mut failed = false;
+
+        for (url, result) in results {
+            let timestamp = Local::now().format("%H:%M:%S");
+            let message = match result {
+                Ok(UpdateOutcome::Updated(count)) => {
+                    metrics::BLOCKLIST_UPDATES
+                        .with_label_values(&["updated"])
+                        .inc();
+                    format!("Blocklist {} updated ({} entries)", url, count)
+                }
+                Ok(UpdateOutcome::NotModified) => {
+                    metrics::BLOCKLIST_UPDATES
+                        .with_label_values(&["not_modified"])
+                        .inc();
+                    format!("Blocklist {} not modified", url)
+                }
+                // The list itself was updated, so this is no reason to
+                // retry sooner.
+                Err(e @ BlocklistError::CacheWrite(_)) => {
+                    format!("Blocklist {}: {}", url, e)
+                }
+                Err(e) => {
+                    failed = true;
+                    metrics::BLOCKLIST_UPDATES
+                        .with_label_values(&["failed"])
+                        .inc();
+                    format!("Blocklist {} update failed: {}", url, e)
+                }
+            };
+            let _ = log_tx.send(format!("[{}] {}", timestamp, message));
+        }
+
+        let delay = if failed {
+            let delay = backoff;
+            backoff = (backoff * 2).min(interval);
+            delay
+        } else {
+            metrics::BLOCKLIST_LAST_SUCCESS.set(Local::now().timestamp() as f64);
+            backoff = min_backoff;
+            interval
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = blocklist.refresh.notified() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{CLASS_IN, name_from_str};
+
+    fn question(name: &str) -> Question {
+        Question {
+            name: name_from_str(name),
+            tp: TYPE_A,
+            class: CLASS_IN,
+        }
+    }
+
+    #[test]
+    fn blocks_names_in_any_case() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ads.txt");
+        std::fs::write(&path, "Ads.Example.com\n").unwrap();
+        let list = path.to_string_lossy().into_owned();
+        let mut blocklist = DNSBlocklist::new(vec![("default".to_string(), vec![list])], None);
+        blocklist.load_cache();
+
+        let policy = FilterPolicy {
+            blocklists: vec!["default".to_string()],
+            allowlists: Vec::new(),
+            schedules: HashMap::new(),
+            rewrites: RewriteRules::default(),
+            response: BlockResponse {
+                mode: BlockMode::Nxdomain,
+                sink_ipv4: Ipv4Addr::UNSPECIFIED,
+                sink_ipv6: Ipv6Addr::UNSPECIFIED,
+                ttl: 60,
+                ede: false,
+            },
+        };
+        for name in ["ads.example.com", "ADS.Example.com", "aDs.eXaMpLe.CoM"] {
+            assert!(blocklist.is_blocked(&question(name), &policy), "{}", name);
+        }
+        assert!(!blocklist.is_blocked(&question("example.com"), &policy));
     }
 }
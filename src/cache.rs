@@ -1,10 +1,15 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, RwLock},
-    time::{Duration, Instant},
+    io::{Error, ErrorKind},
+    path::Path,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crate::packet::{Answer, Question};
+use crate::packet::{Answer, DNSPacket, Question};
 
 struct CacheEntry {
     answers: Vec<Answer>,
@@ -13,21 +18,24 @@ struct CacheEntry {
 
 pub struct DNSCache {
     store: Arc<RwLock<HashMap<Question, CacheEntry>>>,
+    /// Shortest time in seconds an answer is kept, whatever its TTL.
+    min_ttl: AtomicU32,
 }
 
 impl DNSCache {
-    pub fn new() -> Self {
+    pub fn new(min_ttl: u32) -> Self {
         Self {
             store: Arc::new(RwLock::new(HashMap::new())),
+            min_ttl: AtomicU32::new(min_ttl),
         }
     }
 
     pub fn get(&self, q: &Question) -> Option<Vec<Answer>> {
         let cache = self.store.read().unwrap();
-        if let Some(entry) = cache.get(q) {
-            if entry.expiration > Instant::now() {
-                return Some(entry.answers.clone());
-            }
+        if let Some(entry) = cache.get(q)
+            && entry.expiration > Instant::now()
+        {
+            return Some(entry.answers.clone());
         }
         None
     }
@@ -36,7 +44,7 @@ impl DNSCache {
         let mut cache = self.store.write().unwrap();
 
         let min_ttl = answers.iter().map(|a| a.ttl).min().unwrap_or(300);
-        let effective_ttl = std::cmp::max(min_ttl, 300);
+        let effective_ttl = std::cmp::max(min_ttl, self.min_ttl.load(Ordering::Relaxed));
 
         cache.insert(
             q,
@@ -47,8 +55,96 @@ impl DNSCache {
         );
     }
 
+    /// Changes the shortest caching time for answers inserted from now on.
+    pub fn set_min_ttl(&self, min_ttl: u32) {
+        self.min_ttl.store(min_ttl, Ordering::Relaxed);
+    }
+
     pub fn cleanup(&self, expiration: Instant) {
         let mut cache = self.store.write().unwrap();
         cache.retain(|_, entry| entry.expiration > expiration);
     }
+
+    /// Writes the unexpired entries to `path` so a restart can begin with
+    /// a warm cache. Each entry is a DNS response holding the question and
+    /// answers, preceded by its expiry in Unix seconds and its length.
+    pub fn save(&self, path: &Path) -> std::io::Result<usize> {
+        let now = Instant::now();
+        let unix_now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut body = Vec::new();
+        let mut count = 0;
+        {
+            let cache = self.store.read().unwrap();
+            for (q, entry) in cache.iter().filter(|(_, entry)| entry.expiration > now) {
+                let mut packet = DNSPacket::query(0, q.clone());
+                packet.make_response(0, entry.answers.clone());
+                let bytes = packet.to_bytes();
+                let Ok(len) = u16::try_from(bytes.len()) else {
+                    continue;
+                };
+                let expires = unix_now + (entry.expiration - now);
+                body.extend_from_slice(&expires.as_secs().to_be_bytes());
+                body.extend_from_slice(&len.to_be_bytes());
+                body.extend_from_slice(&bytes);
+                count += 1;
+            }
+        }
+
+        // Write then rename so a crash mid-write never leaves a truncated file.
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, body)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(count)
+    }
+
+    /// Loads the entries of a snapshot written by `save` that have not
+    /// expired since.
+    pub fn load(&self, path: &Path) -> std::io::Result<usize> {
+        let body = std::fs::read(path)?;
+        let unix_now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let truncated = || Error::new(ErrorKind::InvalidData, "truncated cache snapshot");
+        let mut cache = self.store.write().unwrap();
+        let mut rest = body.as_slice();
+        let mut count = 0;
+
+        while !rest.is_empty() {
+            let (expires, tail) = rest.split_first_chunk::<8>().ok_or_else(truncated)?;
+            let (len, tail) = tail.split_first_chunk::<2>().ok_or_else(truncated)?;
+            let len = u16::from_be_bytes(*len) as usize;
+            if tail.len() < len {
+                return Err(truncated());
+            }
+            let (bytes, tail) = tail.split_at(len);
+            rest = tail;
+
+            let remaining = u64::from_be_bytes(*expires).saturating_sub(unix_now);
+            if remaining == 0 {
+                continue;
+            }
+            let Ok(packet) = DNSPacket::from_bytes(bytes) else {
+                continue;
+            };
+            if packet.questions.len() != 1 {
+                continue;
+            }
+            let question = packet.questions.into_iter().next().unwrap();
+            cache.insert(
+                question,
+                CacheEntry {
+                    answers: packet.answers,
+                    expiration: Instant::now() + Duration::from_secs(remaining),
+                },
+            );
+            count += 1;
+        }
+        Ok(count)
+    }
 }
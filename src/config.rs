@@ -0,0 +1,666 @@
+//! The TOML configuration file. Every setting in it has a command-line
+//! flag of the same meaning, and a flag given on the command line wins.
+
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use clap::{ArgMatches, parser::ValueSource};
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+use toml::Spanned;
+
+use crate::acl::{Acl, DenyAction};
+use crate::blocklist::BlockMode;
+use crate::dhcp;
+use crate::groups::{GroupConfig, GroupsError, GroupsFile};
+use crate::listen::ListenAddr;
+use crate::packet::check_name;
+use crate::rewrite::RewriteRules;
+use crate::tls::{self, SpkiPin};
+use crate::upstream;
+use crate::{Args, parse_net};
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to read {path}: {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("{path}: {source}")]
+    Parse {
+        path: String,
+        source: toml::de::Error,
+    },
+    #[error("{path}:{line}: {message}")]
+    Invalid {
+        path: String,
+        line: usize,
+        message: String,
+    },
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    listen: ListenConfig,
+    #[serde(default)]
+    access: AccessConfig,
+    #[serde(default)]
+    ratelimit: RateLimitConfig,
+    #[serde(default)]
+    upstream: UpstreamConfig,
+    #[serde(default)]
+    cache: CacheConfig,
+    #[serde(default)]
+    blocking: BlockingConfig,
+    #[serde(default)]
+    local: LocalConfig,
+    #[serde(default)]
+    metrics: MetricsConfig,
+    #[serde(default)]
+    log: LogConfig,
+    #[serde(default)]
+    process: ProcessConfig,
+    /// Extra lists, rewrite sets and client groups, as in a `--groups` file.
+    #[serde(default)]
+    lists: BTreeMap<Spanned<String>, Vec<String>>,
+    #[serde(default)]
+    rewrites: BTreeMap<String, BTreeMap<String, String>>,
+    #[serde(default)]
+    groups: Vec<Spanned<GroupConfig>>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListenConfig {
+    port: Option<u16>,
+    addresses: Option<Vec<ListenEntry>>,
+    udp_buffer_size: Option<Spanned<usize>>,
+    /// Queries handled at once before the rest are refused.
+    max_inflight: Option<usize>,
+    tcp_max_connections: Option<usize>,
+    tcp_idle_timeout: Option<u64>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    /// Seconds to wait on shutdown for replies to forwarded queries.
+    shutdown_timeout: Option<u64>,
+    #[serde(default)]
+    dot: StreamListener,
+    #[serde(default)]
+    doh: DohListener,
+    #[serde(default)]
+    doq: DoqListener,
+    #[serde(default)]
+    dnscrypt: DnsCryptListener,
+}
+
+/// An `addresses` entry: an address as `--listen` takes it, or a table
+/// giving the address access lists of its own.
+#[derive(Clone)]
+struct ListenEntry {
+    address: ListenAddr,
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListenTable {
+    address: ListenAddr,
+    #[serde(default, deserialize_with = "deserialize_nets")]
+    allow: Option<Vec<IpNet>>,
+    #[serde(default, deserialize_with = "deserialize_nets")]
+    deny: Option<Vec<IpNet>>,
+}
+
+impl<'de> Deserialize<'de> for ListenEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntryVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for EntryVisitor {
+            type Value = ListenEntry;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("an address or a table with an address")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<ListenEntry, E> {
+                Ok(ListenEntry {
+                    address: s.parse().map_err(E::custom)?,
+                    allow: Vec::new(),
+                    deny: Vec::new(),
+                })
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                map: A,
+            ) -> Result<ListenEntry, A::Error> {
+                let table =
+                    ListenTable::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+                Ok(ListenEntry {
+                    address: table.address,
+                    allow: table.allow.unwrap_or_default(),
+                    deny: table.deny.unwrap_or_default(),
+                })
+            }
+        }
+
+        deserializer.deserialize_any(EntryVisitor)
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StreamListener {
+    address: Option<SocketAddr>,
+    max_connections: Option<usize>,
+    idle_timeout: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_nets")]
+    allow: Option<Vec<IpNet>>,
+    #[serde(default, deserialize_with = "deserialize_nets")]
+    deny: Option<Vec<IpNet>>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DohListener {
+    address: Option<SocketAddr>,
+    /// Serve HTTPS, or plain HTTP behind a proxy when false.
+    tls: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_nets")]
+    trusted_proxies: Option<Vec<IpNet>>,
+    max_connections: Option<usize>,
+    idle_timeout: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_nets")]
+    allow: Option<Vec<IpNet>>,
+    #[serde(default, deserialize_with = "deserialize_nets")]
+    deny: Option<Vec<IpNet>>,
+    odoh_target: Option<bool>,
+    odoh_key: Option<PathBuf>,
+    odoh_relay_targets: Option<Vec<String>>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DoqListener {
+    address: Option<SocketAddr>,
+    max_connections: Option<usize>,
+    idle_timeout: Option<u64>,
+    early_data: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_nets")]
+    allow: Option<Vec<IpNet>>,
+    #[serde(default, deserialize_with = "deserialize_nets")]
+    deny: Option<Vec<IpNet>>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DnsCryptListener {
+    address: Option<SocketAddr>,
+    provider_name: Option<String>,
+    key: Option<PathBuf>,
+    max_connections: Option<usize>,
+    idle_timeout: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_nets")]
+    allow: Option<Vec<IpNet>>,
+    #[serde(default, deserialize_with = "deserialize_nets")]
+    deny: Option<Vec<IpNet>>,
+}
+
+/// Who may query any listener without lists of its own.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccessConfig {
+    #[serde(default, deserialize_with = "deserialize_nets")]
+    allow: Option<Vec<IpNet>>,
+    #[serde(default, deserialize_with = "deserialize_nets")]
+    deny: Option<Vec<IpNet>>,
+    action: Option<DenyAction>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateLimitConfig {
+    queries_per_second: Option<u32>,
+    burst: Option<u32>,
+    ipv4_prefix: Option<Spanned<u8>>,
+    ipv6_prefix: Option<Spanned<u8>>,
+    #[serde(default, deserialize_with = "deserialize_nets")]
+    exempt: Option<Vec<IpNet>>,
+    /// Identical responses per second for response rate limiting.
+    responses_per_second: Option<u32>,
+    slip: Option<u32>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamConfig {
+    resolver: Option<Spanned<String>>,
+    bootstrap: Option<SocketAddr>,
+    ca: Option<PathBuf>,
+    #[serde(default, deserialize_with = "deserialize_pins")]
+    pins: Option<Vec<SpkiPin>>,
+    connections: Option<usize>,
+    /// Queries waiting for a reply at once before the rest get SERVFAIL.
+    max_pending: Option<Spanned<usize>>,
+    odoh_relay: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CacheConfig {
+    /// Shortest time in seconds an answer is cached for.
+    min_ttl: Option<u32>,
+    snapshot: Option<PathBuf>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BlockingConfig {
+    blocklists: Option<Vec<String>>,
+    refresh: Option<u64>,
+    cache_dir: Option<PathBuf>,
+    /// Keep downloaded lists on disk, as opposed to `--no-blocklist-cache`.
+    disk_cache: Option<bool>,
+    mode: Option<BlockMode>,
+    ttl: Option<u32>,
+    sink_ipv4: Option<Ipv4Addr>,
+    sink_ipv6: Option<Ipv6Addr>,
+    ede: Option<bool>,
+    rebind_protection: Option<bool>,
+    rebind_allow: Option<Vec<String>>,
+    rewrites: Option<Spanned<Vec<String>>>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LocalConfig {
+    zones: Option<Vec<String>>,
+    domains: Option<Spanned<Vec<String>>>,
+    hosts: Option<Vec<String>>,
+    dhcp_leases: Option<Vec<PathBuf>>,
+    dhcp_domain: Option<Spanned<String>>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MetricsConfig {
+    listen: Option<SocketAddr>,
+    /// Token the API requires; kept here, it stays out of `ps` output.
+    api_token: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LogConfig {
+    /// Lines kept for slow readers of the query log.
+    buffer_size: Option<usize>,
+    tui: Option<bool>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProcessConfig {
+    user: Option<String>,
+    group: Option<String>,
+    chroot: Option<PathBuf>,
+}
+
+fn deserialize_pins<'de, D>(deserializer: D) -> Result<Option<Vec<SpkiPin>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(pins) = Option::<Vec<String>>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    pins.iter()
+        .map(|pin| tls::parse_pin(pin).map_err(serde::de::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// Reads addresses and CIDR prefixes, as the flags accept them.
+fn deserialize_nets<'de, D>(deserializer: D) -> Result<Option<Vec<IpNet>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(nets) = Option::<Vec<String>>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    nets.iter()
+        .map(|net| parse_net(net).map_err(serde::de::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// A configuration file that has been read and validated.
+pub struct ConfigFile {
+    path: String,
+    body: String,
+    config: Config,
+}
+
+impl ConfigFile {
+    /// Reads and validates the file at `path`. Errors point at the line
+    /// of the offending setting.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let display = path.display().to_string();
+        let body = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: display.clone(),
+            source,
+        })?;
+        let mut file = ConfigFile {
+            path: display,
+            body,
+            config: Config::default(),
+        };
+        file.config = match toml::from_str(&file.body) {
+            Ok(config) => config,
+            Err(e) if e.span().is_some() => return Err(file.invalid(e.span(), e.message())),
+            Err(source) => {
+                return Err(ConfigError::Parse {
+                    path: file.path,
+                    source,
+                });
+            }
+        };
+        file.validate()?;
+        Ok(file)
+    }
+
+    /// Checks what the types alone cannot: settings that refer to each
+    /// other or need more than parsing.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(resolver) = &self.config.upstream.resolver
+            && let Err(e) = upstream::check_url(resolver.get_ref())
+        {
+            return Err(self.invalid(Some(resolver.span()), &e.to_string()));
+        }
+        if let Some(names) = &self.config.blocking.rewrites
+            && let Err(e) = RewriteRules::from_sets(names.get_ref(), &self.config.rewrites)
+        {
+            return Err(self.invalid(Some(names.span()), &e.to_string()));
+        }
+        if let Some(domains) = &self.config.local.domains
+            && let Some(e) = domains.get_ref().iter().find_map(|d| check_name(d).err())
+        {
+            return Err(self.invalid(Some(domains.span()), &e));
+        }
+        if let Some(domain) = &self.config.local.dhcp_domain
+            && let Err(e) = dhcp::check_domain(domain.get_ref())
+        {
+            return Err(self.invalid(Some(domain.span()), &e));
+        }
+        if let Some(max_pending) = &self.config.upstream.max_pending
+            && !(1..=65535).contains(max_pending.get_ref())
+        {
+            let message = "max_pending must be between 1 and 65535";
+            return Err(self.invalid(Some(max_pending.span()), message));
+        }
+        if let Some(size) = &self.config.listen.udp_buffer_size
+            && !(512..=65535).contains(size.get_ref())
+        {
+            let message = "udp_buffer_size must be between 512 and 65535";
+            return Err(self.invalid(Some(size.span()), message));
+        }
+        let ratelimit = &self.config.ratelimit;
+        if let Some(prefix) = &ratelimit.ipv4_prefix
+            && *prefix.get_ref() > 32
+        {
+            let message = "ipv4_prefix must be at most 32";
+            return Err(self.invalid(Some(prefix.span()), message));
+        }
+        if let Some(prefix) = &ratelimit.ipv6_prefix
+            && *prefix.get_ref() > 128
+        {
+            let message = "ipv6_prefix must be at most 128";
+            return Err(self.invalid(Some(prefix.span()), message));
+        }
+        Ok(())
+    }
+
+    fn invalid(&self, span: Option<Range<usize>>, message: &str) -> ConfigError {
+        let offset = span.map_or(0, |span| span.start).min(self.body.len());
+        ConfigError::Invalid {
+            path: self.path.clone(),
+            line: self.body[..offset].matches('\n').count() + 1,
+            message: message.to_string(),
+        }
+    }
+
+    /// The lists, rewrite sets and groups defined in the file.
+    pub fn groups_file(&self) -> GroupsFile {
+        GroupsFile {
+            lists: self
+                .config
+                .lists
+                .iter()
+                .map(|(name, urls)| (name.get_ref().clone(), urls.clone()))
+                .collect(),
+            rewrites: self.config.rewrites.clone(),
+            groups: self
+                .config
+                .groups
+                .iter()
+                .map(|group| group.get_ref().clone())
+                .collect(),
+        }
+    }
+
+    /// Points an error in one of the file's groups at the group, or at
+    /// the list it is about.
+    pub fn group_error(&self, error: GroupsError) -> ConfigError {
+        let span = match &error {
+            GroupsError::ReservedList(name) => self
+                .config
+                .lists
+                .keys()
+                .find(|list| list.get_ref() == name)
+                .map(|list| list.span()),
+            _ => self
+                .config
+                .groups
+                .iter()
+                .find(|group| Some(group.get_ref().name.as_str()) == error.group())
+                .map(|group| group.span()),
+        };
+        self.invalid(span, &error.to_string())
+    }
+
+    /// Copies every setting in the file into `args`, except those whose
+    /// flag was given on the command line.
+    pub fn apply(&self, args: &mut Args, matches: &ArgMatches) {
+        let config = &self.config;
+        let listen = &config.listen;
+        let blocking = &config.blocking;
+
+        macro_rules! apply {
+            ($($value:expr => $field:ident),* $(,)?) => {
+                $(
+                    if let Some(value) = $value
+                        && matches.value_source(stringify!($field)) != Some(ValueSource::CommandLine)
+                    {
+                        args.$field = value.into();
+                    }
+                )*
+            };
+        }
+
+        apply! {
+            listen.port => port,
+            listen.addresses.as_ref().map(|entries| {
+                entries.iter().map(|entry| entry.address.clone()).collect::<Vec<_>>()
+            }) => listen,
+            listen.udp_buffer_size.as_ref().map(|size| *size.get_ref() as u16) => udp_buffer_size,
+            listen.max_inflight => max_inflight,
+            listen.tcp_max_connections => tcp_max_connections,
+            listen.tcp_idle_timeout => tcp_idle_timeout,
+            listen.tls_cert.clone() => tls_cert,
+            listen.tls_key.clone() => tls_key,
+            listen.shutdown_timeout => shutdown_timeout,
+            config.access.allow.clone() => allow,
+            config.access.deny.clone() => deny,
+            config.access.action => deny_action,
+            config.ratelimit.queries_per_second => ratelimit,
+            config.ratelimit.burst => ratelimit_burst,
+            config.ratelimit.ipv4_prefix.as_ref().map(|p| *p.get_ref()) => ratelimit_ipv4_prefix,
+            config.ratelimit.ipv6_prefix.as_ref().map(|p| *p.get_ref()) => ratelimit_ipv6_prefix,
+            config.ratelimit.exempt.clone() => ratelimit_exempt,
+            config.ratelimit.responses_per_second => rrl_rate,
+            config.ratelimit.slip => rrl_slip,
+            listen.dot.address => dot_listen,
+            listen.dot.max_connections => dot_max_connections,
+            listen.dot.idle_timeout => dot_idle_timeout,
+            listen.dot.allow.clone() => dot_allow,
+            listen.dot.deny.clone() => dot_deny,
+            listen.doh.address => doh_listen,
+            listen.doh.tls.map(|tls| !tls) => doh_no_tls,
+            listen.doh.trusted_proxies.clone() => doh_trusted_proxy,
+            listen.doh.max_connections => doh_max_connections,
+            listen.doh.idle_timeout => doh_idle_timeout,
+            listen.doh.allow.clone() => doh_allow,
+            listen.doh.deny.clone() => doh_deny,
+            listen.doh.odoh_target => odoh_target,
+            listen.doh.odoh_key.clone() => odoh_key,
+            listen.doh.odoh_relay_targets.clone() => odoh_relay_target,
+            listen.doq.address => doq_listen,
+            listen.doq.max_connections => doq_max_connections,
+            listen.doq.idle_timeout => doq_idle_timeout,
+            listen.doq.allow.clone() => doq_allow,
+            listen.doq.deny.clone() => doq_deny,
+            listen.doq.early_data => doq_early_data,
+            listen.dnscrypt.address => dnscrypt_listen,
+            listen.dnscrypt.provider_name.clone() => dnscrypt_provider_name,
+            listen.dnscrypt.key.clone() => dnscrypt_key,
+            listen.dnscrypt.max_connections => dnscrypt_max_connections,
+            listen.dnscrypt.idle_timeout => dnscrypt_idle_timeout,
+            listen.dnscrypt.allow.clone() => dnscrypt_allow,
+            listen.dnscrypt.deny.clone() => dnscrypt_deny,
+            config.upstream.resolver.as_ref().map(|r| r.get_ref().clone()) => resolver,
+            config.upstream.bootstrap => bootstrap,
+            config.upstream.ca.clone() => upstream_ca,
+            config.upstream.pins.clone() => upstream_pin,
+            config.upstream.connections => upstream_connections,
+            config.upstream.max_pending.as_ref().map(|m| *m.get_ref() as u16) => upstream_max_pending,
+            config.upstream.odoh_relay.clone() => odoh_relay,
+            config.cache.min_ttl => cache_min_ttl,
+            config.cache.snapshot.clone() => cache_snapshot,
+            blocking.blocklists.clone() => blocklists,
+            blocking.refresh => blocklist_refresh,
+            blocking.cache_dir.clone() => blocklist_cache_dir,
+            blocking.disk_cache.map(|cache| !cache) => no_blocklist_cache,
+            blocking.mode => block_mode,
+            blocking.ttl => block_ttl,
+            blocking.sink_ipv4 => sink_ipv4,
+            blocking.sink_ipv6 => sink_ipv6,
+            blocking.ede => block_ede,
+            blocking.rebind_protection => rebind_protection,
+            blocking.rebind_allow.clone() => rebind_allow,
+            blocking.rewrites.as_ref().map(|r| r.get_ref().clone()) => rewrites,
+            config.local.zones.clone() => zones,
+            config.local.domains.as_ref().map(|d| d.get_ref().clone()) => local_domains,
+            config.local.hosts.clone() => hosts_files,
+            config.local.dhcp_leases.clone() => dhcp_leases,
+            config.local.dhcp_domain.as_ref().map(|d| d.get_ref().clone()) => dhcp_domain,
+            config.metrics.listen => metrics_listen,
+            config.metrics.api_token.clone() => api_token,
+            config.log.buffer_size => log_buffer_size,
+            config.log.tui.map(|tui| !tui) => no_tui,
+            config.process.user.clone() => user,
+            config.process.group.clone() => group,
+            config.process.chroot.clone() => chroot,
+        }
+
+        // Access lists belong to the file's addresses, not to --listen ones.
+        if let Some(entries) = &listen.addresses
+            && matches.value_source("listen") != Some(ValueSource::CommandLine)
+        {
+            args.listen_access = entries
+                .iter()
+                .map(|entry| {
+                    let acl = Acl::new(entry.allow.clone(), entry.deny.clone());
+                    (entry.address.clone(), acl)
+                })
+                .collect();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    fn load(body: &str) -> Result<ConfigFile, ConfigError> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rdns.toml");
+        std::fs::write(&path, body).unwrap();
+        ConfigFile::load(&path)
+    }
+
+    fn error_line(error: ConfigError) -> usize {
+        match error {
+            ConfigError::Invalid { line, .. } => line,
+            other => panic!("unexpected error: {}", other),
+        }
+    }
+
+    #[test]
+    fn reports_the_line_of_a_bad_value() {
+        let body = "[listen]\nport = 5353\nudp_buffer_size = 100\n";
+        assert_eq!(error_line(load(body).err().unwrap()), 3);
+
+        let body = "[listen]\nport = 5353\n\n[blocking]\nmode = \"sinkhole\"\n";
+        assert_eq!(error_line(load(body).err().unwrap()), 5);
+
+        let body = "[upstream]\nresolver = \"ftp://example.com\"\n";
+        assert_eq!(error_line(load(body).err().unwrap()), 2);
+
+        let body = "[blocking]\nttl = 60\nrewrites = [\"nope\"]\n";
+        let error = load(body).err().unwrap();
+        assert!(error.to_string().contains("unknown rewrite set nope"));
+        assert_eq!(error_line(error), 3);
+    }
+
+    #[test]
+    fn reports_the_line_of_a_bad_group() {
+        let body = "[lists]\nads = [\"ads.txt\"]\n\n\
+                    [[groups]]\nname = \"office\"\nclients = [\"10.0.0.0/8\"]\n\n\
+                    [[groups]]\nname = \"kids\"\nclients = [\"192.168.1.0/24\"]\n";
+        let config = load(body).unwrap();
+        let error = GroupsError::UnknownList {
+            group: "kids".to_string(),
+            list: "games".to_string(),
+        };
+        assert_eq!(error_line(config.group_error(error)), 8);
+
+        let error = GroupsError::ReservedList("ads".to_string());
+        assert_eq!(error_line(config.group_error(error)), 2);
+    }
+
+    #[test]
+    fn command_line_flags_win_over_the_file() {
+        let config = load("[listen]\nport = 5353\n\n[blocking]\nttl = 60\n").unwrap();
+        let matches = Args::command()
+            .try_get_matches_from(["rdns", "--port", "5400"])
+            .unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        config.apply(&mut args, &matches);
+        assert_eq!(args.port, 5400);
+        assert_eq!(args.block_ttl, 60);
+
+        // A default is not a choice made on the command line.
+        let matches = Args::command().try_get_matches_from(["rdns"]).unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        config.apply(&mut args, &matches);
+        assert_eq!(args.port, 5353);
+    }
+}
@@ -0,0 +1,330 @@
+use std::{
+    net::IpAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Local, NaiveDateTime, Utc};
+use tokio::sync::broadcast;
+
+use crate::metrics;
+use crate::packet::{MAX_LABEL_LENGTH, check_name};
+use crate::zone::LocalZones;
+
+/// How often the lease files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// TTL of records made from leases; short, since leases come and go.
+const LEASE_TTL: u32 = 60;
+
+/// An active lease with a usable hostname.
+struct Lease {
+    hostname: String,
+    ip: IpAddr,
+    /// When the lease runs out, or `None` for infinite leases.
+    expires: Option<DateTime<Utc>>,
+}
+
+/// Parses a dnsmasq or ISC dhcpd lease file, telling them apart by content,
+/// and returns the leases still active at `now`.
+fn parse_leases(body: &str, now: DateTime<Utc>) -> Vec<Lease> {
+    let is_isc = body
+        .lines()
+        .any(|line| line.trim_start().starts_with("lease "));
+    let leases = if is_isc {
+        parse_isc(body)
+    } else {
+        parse_dnsmasq(body)
+    };
+    leases
+        .into_iter()
+        .filter(|lease| lease.expires.is_none_or(|expires| expires > now))
+        .collect()
+}
+
+/// dnsmasq writes one lease per line: `expiry mac ip hostname client-id`,
+/// with `*` for an unknown hostname and an expiry of 0 for infinite leases.
+/// DHCPv6 leases follow a `duid` line and put the IAID where the MAC was.
+fn parse_dnsmasq(body: &str) -> Vec<Lease> {
+    body.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 4 {
+                return None;
+            }
+            let expires = match fields[0].parse::<i64>().ok()? {
+                0 => None,
+                secs => Some(DateTime::from_timestamp(secs, 0)?),
+            };
+            Some(Lease {
+                hostname: sanitize_hostname(fields[3])?,
+                ip: fields[2].parse().ok()?,
+                expires,
+            })
+        })
+        .collect()
+}
+
+/// A `lease { ... }` block of an ISC lease file being read.
+struct IscBlock {
+    ip: IpAddr,
+    hostname: Option<String>,
+    expires: Option<DateTime<Utc>>,
+    active: bool,
+}
+
+/// ISC dhcpd appends `lease <ip> { ... }` blocks, so a later block for the
+/// same address supersedes earlier ones.
+fn parse_isc(body: &str) -> Vec<Lease> {
+    let mut leases: Vec<Lease> = Vec::new();
+    let mut current: Option<IscBlock> = None;
+
+    for line in body.lines() {
+        let line = line.trim().trim_end_matches(';');
+        if let Some(rest) = line.strip_prefix("lease ") {
+            current = rest
+                .trim_end_matches('{')
+                .trim()
+                .parse()
+                .ok()
+                .map(|ip| IscBlock {
+                    ip,
+                    hostname: None,
+                    expires: None,
+                    active: true,
+                });
+            continue;
+        }
+        let Some(block) = current.as_mut() else {
+            continue;
+        };
+
+        if line == "}" {
+            let block = current.take().unwrap();
+            leases.retain(|lease| lease.ip != block.ip);
+            if block.active
+                && let Some(hostname) = block.hostname
+            {
+                leases.push(Lease {
+                    hostname,
+                    ip: block.ip,
+                    expires: block.expires,
+                });
+            }
+        } else if let Some(state) = line.strip_prefix("binding state ") {
+            block.active = state == "active";
+        } else if let Some(name) = line.strip_prefix("client-hostname ") {
+            block.hostname = sanitize_hostname(name.trim_matches('"'));
+        } else if let Some(ends) = line.strip_prefix("ends ") {
+            // `ends 4 2024/01/01 12:00:00` in UTC, or `ends never`.
+            block.expires = ends
+                .split_once(' ')
+                .and_then(|(_, date)| NaiveDateTime::parse_from_str(date, "%Y/%m/%d %H:%M:%S").ok())
+                .map(|date| date.and_utc());
+        }
+    }
+    leases
+}
+
+/// Reduces a client-supplied hostname to a single valid DNS label.
+fn sanitize_hostname(name: &str) -> Option<String> {
+    let label = name.split('.').next()?.to_ascii_lowercase();
+    let valid = !label.is_empty()
+        && label.len() <= 63
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && !label.starts_with('-')
+        && !label.ends_with('-');
+    valid.then_some(label)
+}
+
+/// Checks that `domain` is a name with room under it for any hostname a
+/// lease may carry.
+pub fn check_domain(domain: &str) -> Result<(), String> {
+    check_name(domain)?;
+    check_name(&format!("{}.{}", "a".repeat(MAX_LABEL_LENGTH), domain)).map_err(|_| {
+        format!(
+            "domain {} leaves no room for {}-byte hostnames",
+            domain, MAX_LABEL_LENGTH
+        )
+    })
+}
+
+/// Keeps the leases in `paths` published as `<hostname>.<domain>` in
+/// `zones`, re-reading them when a file changes or a lease expires. A
+/// file that cannot be read keeps the leases last read from it until they
+/// expire, so a server rewriting it does not make hosts vanish.
+pub async fn run_watcher(
+    paths: Vec<PathBuf>,
+    domain: String,
+    zones: Arc<LocalZones>,
+    log_tx: broadcast::Sender<String>,
+) {
+    let mut last_seen: Option<Vec<Option<SystemTime>>> = None;
+    let mut next_expiry: Option<DateTime<Utc>> = None;
+    // The leases last read from each of `paths`.
+    let mut known: Vec<Vec<Lease>> = paths.iter().map(|_| Vec::new()).collect();
+
+    loop {
+        let mut seen = Vec::with_capacity(paths.len());
+        for path in &paths {
+            let modified = tokio::fs::metadata(path)
+                .await
+                .and_then(|m| m.modified())
+                .ok();
+            seen.push(modified);
+        }
+        let expired = next_expiry.is_some_and(|expiry| expiry <= Utc::now());
+
+        if last_seen.as_ref() != Some(&seen) || expired {
+            let now = Utc::now();
+            for (path, leases) in paths.iter().zip(&mut known) {
+                match tokio::fs::read_to_string(path).await {
+                    Ok(body) => *leases = parse_leases(&body, now),
+                    Err(e) => {
+                        leases.retain(|lease| lease.expires.is_none_or(|expires| expires > now));
+                        let timestamp = Local::now().format("%H:%M:%S");
+                        let _ = log_tx.send(format!(
+                            "[{}] Failed to read DHCP leases {}, keeping {} read before: {}",
+                            timestamp,
+                            path.display(),
+                            leases.len(),
+                            e
+                        ));
+                    }
+                }
+            }
+            let leases: Vec<&Lease> = known.iter().flatten().collect();
+
+            next_expiry = leases.iter().filter_map(|lease| lease.expires).min();
+            let hosts: Vec<(String, IpAddr)> = leases
+                .iter()
+                .map(|lease| (format!("{}.{}", lease.hostname, domain), lease.ip))
+                .collect();
+            zones.set_leases(&hosts, LEASE_TTL);
+            metrics::DHCP_LEASES.set(hosts.len() as f64);
+
+            let timestamp = Local::now().format("%H:%M:%S");
+            let _ = log_tx.send(format!(
+                "[{}] DHCP leases loaded ({} hosts under {})",
+                timestamp,
+                hosts.len(),
+                domain
+            ));
+            last_seen = Some(seen);
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    fn hosts(leases: &[Lease]) -> Vec<(&str, IpAddr)> {
+        leases
+            .iter()
+            .map(|lease| (lease.hostname.as_str(), lease.ip))
+            .collect()
+    }
+
+    #[test]
+    fn parses_dnsmasq_leases() {
+        let body = "\
+1700000100 aa:bb:cc:dd:ee:01 192.168.1.50 Laptop.lan 01:aa:bb:cc:dd:ee:01
+1700000000 aa:bb:cc:dd:ee:02 192.168.1.51 oldphone *
+0 aa:bb:cc:dd:ee:03 192.168.1.52 * *
+0 aa:bb:cc:dd:ee:04 192.168.1.53 bad_name *
+duid 00:01:00:01:2c:aa:bb:cc
+1700000100 1234 fd00::50 laptop 00:01
+";
+        let leases = parse_leases(body, at(1700000050));
+        assert_eq!(
+            hosts(&leases),
+            [
+                ("laptop", IpAddr::from([192, 168, 1, 50])),
+                ("laptop", "fd00::50".parse().unwrap()),
+            ]
+        );
+        assert_eq!(leases[0].expires, Some(at(1700000100)));
+    }
+
+    #[test]
+    fn parses_isc_leases() {
+        let body = r#"
+# The format of this file is documented in the dhcpd.leases(5) manual page.
+lease 192.168.1.60 {
+  starts 4 2023/11/14 22:00:00;
+  ends 4 2023/11/14 23:00:00;
+  binding state active;
+  client-hostname "desktop";
+}
+lease 192.168.1.61 {
+  ends never;
+  binding state active;
+  client-hostname "nas";
+}
+lease 192.168.1.62 {
+  ends 4 2023/11/14 23:00:00;
+  binding state active;
+  client-hostname "tv";
+}
+lease 192.168.1.62 {
+  ends 4 2023/11/14 23:00:00;
+  binding state free;
+}
+lease 192.168.1.63 {
+  ends 4 2023/11/14 22:30:00;
+  binding state active;
+  client-hostname "phone";
+}
+"#;
+        let now = at(1700000000); // 2023-11-14 22:13:20 UTC
+        let leases = parse_leases(body, now);
+        assert_eq!(
+            hosts(&leases),
+            [
+                ("desktop", IpAddr::from([192, 168, 1, 60])),
+                ("nas", IpAddr::from([192, 168, 1, 61])),
+                ("phone", IpAddr::from([192, 168, 1, 63])),
+            ]
+        );
+        assert_eq!(leases[1].expires, None);
+
+        let later = parse_leases(body, at(1700001000));
+        assert_eq!(
+            hosts(&later),
+            [
+                ("desktop", IpAddr::from([192, 168, 1, 60])),
+                ("nas", IpAddr::from([192, 168, 1, 61])),
+            ]
+        );
+    }
+
+    #[test]
+    fn hostnames_become_single_labels() {
+        assert_eq!(
+            sanitize_hostname("Printer.local").as_deref(),
+            Some("printer")
+        );
+        assert_eq!(sanitize_hostname("my-pc").as_deref(), Some("my-pc"));
+        assert_eq!(sanitize_hostname("-pc"), None);
+        assert_eq!(sanitize_hostname("*"), None);
+        assert_eq!(sanitize_hostname(&"a".repeat(64)), None);
+    }
+
+    #[test]
+    fn domains_leave_room_for_hostnames() {
+        assert!(check_domain("lan").is_ok());
+        assert!(check_domain(&"a".repeat(64)).is_err());
+        // A 63-byte hostname under two 63-byte labels takes 3 * 64 + 1 = 193
+        // bytes; one more label makes it 257.
+        let domain = format!("{}.{}", "a".repeat(63), "b".repeat(63));
+        assert!(check_domain(&domain).is_ok());
+        assert!(check_domain(&format!("{}.{}", domain, "c".repeat(63))).is_err());
+    }
+}
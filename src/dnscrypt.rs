@@ -0,0 +1,945 @@
+//! DNSCrypt version 2: queries and replies are boxed with short-term X25519
+//! keys the resolver publishes in certificates over TXT, which are signed
+//! by a long-term provider key the client learns from an `sdns://` stamp.
+
+use std::{
+    io,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+use chrono::Local;
+use crypto_box::{
+    ChaChaBox, SalsaBox,
+    aead::{AeadInPlace, generic_array::GenericArray},
+};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use thiserror::Error;
+use tokio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{OwnedSemaphorePermit, Semaphore, mpsc},
+};
+
+use crate::acl::DenyAction;
+use crate::hpke::random_bytes;
+use crate::odoh::write_private;
+use crate::packet::{Answer, DNSPacket, RCODE_NOERROR, RCODE_REFUSED, TYPE_TXT};
+use crate::transport::{self, ConnectionLimits, QUERY_TIMEOUT, Responder, Transport, is_transient};
+use crate::{Context, admit, handle_dns_request, metrics};
+
+const CERT_MAGIC: &[u8] = b"DNSC";
+const RESOLVER_MAGIC: &[u8] = b"r6fnvWj8";
+/// Encryption systems a certificate can name.
+const ES_XSALSA20POLY1305: u16 = 1;
+const ES_XCHACHA20POLY1305: u16 = 2;
+/// A certificate without extensions, and where its signed part starts.
+const CERT_LEN: usize = 124;
+const CERT_SIGNED: usize = 72;
+/// The `sdns://` stamp protocol identifier for DNSCrypt.
+const STAMP_DNSCRYPT: u8 = 0x01;
+const PROVIDER_PREFIX: &str = "2.dnscrypt-cert.";
+
+pub const HALF_NONCE: usize = 12;
+const TAG_LEN: usize = 16;
+/// Client magic, client public key and client nonce.
+const QUERY_HEADER: usize = 8 + 32 + HALF_NONCE;
+/// Resolver magic and the full nonce.
+const RESPONSE_HEADER: usize = 8 + 2 * HALF_NONCE;
+/// UDP queries are padded to at least this size so that replies, which
+/// may not be larger, are worth little for amplification.
+pub const MIN_UDP_QUERY: usize = 256;
+const PADDING_BLOCK: usize = 64;
+
+/// How long a certificate is valid, and how often a new one is issued.
+const CERT_LIFETIME: Duration = Duration::from_secs(24 * 3600);
+const CERT_ROTATION: Duration = Duration::from_secs(12 * 3600);
+const CERT_TTL: u32 = 3600;
+
+#[derive(Debug, Error)]
+pub enum DnsCryptError {
+    #[error("malformed DNSCrypt message")]
+    Malformed,
+    #[error("DNSCrypt message failed to decrypt")]
+    Decrypt,
+    #[error("invalid DNSCrypt stamp '{0}'")]
+    InvalidStamp(String),
+    #[error("no valid DNSCrypt certificate offered")]
+    NoCertificate,
+    #[error("failed to access DNSCrypt key {path}: {source}")]
+    KeyFile { path: String, source: io::Error },
+    #[error("{0} does not hold a base64 Ed25519 secret key")]
+    InvalidKeyFile(String),
+}
+
+/// A box keyed with the shared secret of a client and a resolver key.
+enum Cipher {
+    XSalsa20(SalsaBox),
+    XChaCha20(ChaChaBox),
+}
+
+impl Cipher {
+    fn new(
+        es_version: u16,
+        public: &[u8; 32],
+        secret: &crypto_box::SecretKey,
+    ) -> Result<Self, DnsCryptError> {
+        let public = crypto_box::PublicKey::from_bytes(*public);
+        match es_version {
+            ES_XSALSA20POLY1305 => Ok(Cipher::XSalsa20(SalsaBox::new(&public, secret))),
+            ES_XCHACHA20POLY1305 => Ok(Cipher::XChaCha20(ChaChaBox::new(&public, secret))),
+            _ => Err(DnsCryptError::NoCertificate),
+        }
+    }
+
+    /// Encrypts `plaintext` in libsodium's layout, with the tag in front.
+    fn seal(&self, nonce: &[u8; 24], plaintext: &[u8]) -> Vec<u8> {
+        let nonce = GenericArray::from_slice(nonce);
+        let mut body = plaintext.to_vec();
+        let tag = match self {
+            Cipher::XSalsa20(cipher) => cipher.encrypt_in_place_detached(nonce, b"", &mut body),
+            Cipher::XChaCha20(cipher) => cipher.encrypt_in_place_detached(nonce, b"", &mut body),
+        }
+        .expect("encryption of a bounded message");
+        [tag.as_slice(), &body].concat()
+    }
+
+    fn open(&self, nonce: &[u8; 24], boxed: &[u8]) -> Result<Vec<u8>, DnsCryptError> {
+        if boxed.len() < TAG_LEN {
+            return Err(DnsCryptError::Malformed);
+        }
+        let nonce = GenericArray::from_slice(nonce);
+        let (tag, body) = boxed.split_at(TAG_LEN);
+        let tag = GenericArray::from_slice(tag);
+        let mut body = body.to_vec();
+        match self {
+            Cipher::XSalsa20(cipher) => {
+                cipher.decrypt_in_place_detached(nonce, b"", &mut body, tag)
+            }
+            Cipher::XChaCha20(cipher) => {
+                cipher.decrypt_in_place_detached(nonce, b"", &mut body, tag)
+            }
+        }
+        .map_err(|_| DnsCryptError::Decrypt)?;
+        Ok(body)
+    }
+}
+
+/// Pads `message` with 0x80 and zeros to a multiple of 64 bytes, and to at
+/// least `min_len`.
+fn pad(message: &[u8], min_len: usize) -> Vec<u8> {
+    let len = (message.len() + 1)
+        .max(min_len)
+        .next_multiple_of(PADDING_BLOCK);
+    let mut padded = Vec::with_capacity(len);
+    padded.extend_from_slice(message);
+    padded.push(0x80);
+    padded.resize(len, 0);
+    padded
+}
+
+fn unpad(padded: &[u8]) -> Result<&[u8], DnsCryptError> {
+    let end = padded
+        .iter()
+        .rposition(|&b| b != 0)
+        .ok_or(DnsCryptError::Malformed)?;
+    if padded[end] != 0x80 {
+        return Err(DnsCryptError::Malformed);
+    }
+    Ok(&padded[..end])
+}
+
+fn unix_now() -> u32 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32)
+}
+
+/// A resolver certificate, binding a short-term key to the provider.
+pub struct Certificate {
+    es_version: u16,
+    resolver_pk: [u8; 32],
+    client_magic: [u8; 8],
+    serial: u32,
+    ts_start: u32,
+    ts_end: u32,
+}
+
+impl Certificate {
+    fn sign(&self, provider: &SigningKey) -> Vec<u8> {
+        let signed = [
+            self.resolver_pk.as_slice(),
+            &self.client_magic,
+            &self.serial.to_be_bytes(),
+            &self.ts_start.to_be_bytes(),
+            &self.ts_end.to_be_bytes(),
+        ]
+        .concat();
+        let signature = provider.sign(&signed);
+        [
+            CERT_MAGIC,
+            &self.es_version.to_be_bytes(),
+            &[0, 0],
+            &signature.to_bytes(),
+            &signed,
+        ]
+        .concat()
+    }
+
+    /// Parses a certificate, checking it was signed by the provider.
+    fn verify(bytes: &[u8], provider: &VerifyingKey) -> Result<Self, DnsCryptError> {
+        if bytes.len() < CERT_LEN || &bytes[..4] != CERT_MAGIC {
+            return Err(DnsCryptError::Malformed);
+        }
+        let field = |start: usize, len: usize| &bytes[start..start + len];
+        let signature =
+            Signature::from_slice(field(8, 64)).map_err(|_| DnsCryptError::Malformed)?;
+        provider
+            .verify_strict(&bytes[CERT_SIGNED..], &signature)
+            .map_err(|_| DnsCryptError::NoCertificate)?;
+
+        let u32_at = |start| u32::from_be_bytes(field(start, 4).try_into().unwrap_or_default());
+        Ok(Certificate {
+            es_version: u16::from_be_bytes([bytes[4], bytes[5]]),
+            resolver_pk: field(72, 32)
+                .try_into()
+                .map_err(|_| DnsCryptError::Malformed)?,
+            client_magic: field(104, 8)
+                .try_into()
+                .map_err(|_| DnsCryptError::Malformed)?,
+            serial: u32_at(112),
+            ts_start: u32_at(116),
+            ts_end: u32_at(120),
+        })
+    }
+
+    fn is_valid_at(&self, now: u32) -> bool {
+        self.ts_start <= now && now <= self.ts_end
+    }
+}
+
+/// Picks the newest valid certificate from the TXT records answering a
+/// certificate query, preferring XChaCha20 over XSalsa20.
+pub fn best_certificate(
+    records: &[Answer],
+    provider: &VerifyingKey,
+) -> Result<Certificate, DnsCryptError> {
+    let now = unix_now();
+    records
+        .iter()
+        .filter(|record| record.tp == TYPE_TXT)
+        .filter_map(|record| Certificate::verify(&txt_data(&record.data), provider).ok())
+        .filter(|cert| {
+            cert.is_valid_at(now)
+                && matches!(cert.es_version, ES_XSALSA20POLY1305 | ES_XCHACHA20POLY1305)
+        })
+        .max_by_key(|cert| (cert.es_version, cert.serial))
+        .ok_or(DnsCryptError::NoCertificate)
+}
+
+/// Joins the character strings of a TXT record.
+fn txt_data(rdata: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(rdata.len());
+    let mut rest = rdata;
+    while let Some((&len, tail)) = rest.split_first() {
+        let len = (len as usize).min(tail.len());
+        data.extend_from_slice(&tail[..len]);
+        rest = &tail[len..];
+    }
+    data
+}
+
+/// The name certificates are published under, e.g.
+/// `2.dnscrypt-cert.example.com` for `example.com`.
+pub fn provider_name(name: &str) -> String {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    if name.starts_with(PROVIDER_PREFIX) {
+        name
+    } else {
+        format!("{}{}", PROVIDER_PREFIX, name)
+    }
+}
+
+/// A DNSCrypt server stamp, telling a client where the resolver is and
+/// which provider key signs its certificates.
+pub struct Stamp {
+    /// `ip[:port]`, with port 443 if none is given.
+    pub addr: String,
+    pub provider_pk: VerifyingKey,
+    pub provider_name: String,
+}
+
+impl Stamp {
+    pub fn parse(s: &str) -> Result<Self, DnsCryptError> {
+        let invalid = || DnsCryptError::InvalidStamp(s.to_string());
+        let bytes = s
+            .strip_prefix("sdns://")
+            .and_then(|encoded| URL_SAFE_NO_PAD.decode(encoded).ok())
+            .ok_or_else(invalid)?;
+        // The protocol byte is followed by eight bytes of properties.
+        if bytes.first() != Some(&STAMP_DNSCRYPT) || bytes.len() < 9 {
+            return Err(invalid());
+        }
+
+        let mut rest = &bytes[9..];
+        let mut field = || {
+            let (&len, tail) = rest.split_first()?;
+            let (value, tail) = tail.split_at_checked(len as usize)?;
+            rest = tail;
+            Some(value)
+        };
+        let addr = field().and_then(|addr| String::from_utf8(addr.to_vec()).ok());
+        let provider_pk = field()
+            .and_then(|pk| pk.try_into().ok())
+            .and_then(|pk| VerifyingKey::from_bytes(pk).ok());
+        let provider_name = field().and_then(|name| String::from_utf8(name.to_vec()).ok());
+        match (addr, provider_pk, provider_name) {
+            (Some(addr), Some(provider_pk), Some(provider_name)) => Ok(Stamp {
+                addr,
+                provider_pk,
+                provider_name,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Encodes the stamp, claiming none of the DNSSEC, no-log or no-filter
+    /// properties.
+    pub fn encode(&self) -> String {
+        let mut bytes = vec![STAMP_DNSCRYPT];
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+        for field in [
+            self.addr.as_bytes(),
+            self.provider_pk.as_bytes(),
+            self.provider_name.as_bytes(),
+        ] {
+            bytes.push(field.len() as u8);
+            bytes.extend_from_slice(field);
+        }
+        format!("sdns://{}", URL_SAFE_NO_PAD.encode(bytes))
+    }
+}
+
+/// Generates a new provider signing key.
+pub fn generate_provider_key() -> SigningKey {
+    SigningKey::from_bytes(&random_bytes())
+}
+
+/// Loads the provider key stored at `path`, creating the file with a new
+/// key if it does not exist yet, so the stamp stays the same across
+/// restarts.
+pub fn load_or_create_provider_key(path: &Path) -> Result<SigningKey, DnsCryptError> {
+    let key_error = |source| DnsCryptError::KeyFile {
+        path: path.display().to_string(),
+        source,
+    };
+    match std::fs::read_to_string(path) {
+        Ok(body) => {
+            let bytes: [u8; 32] = STANDARD
+                .decode(body.trim())
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| DnsCryptError::InvalidKeyFile(path.display().to_string()))?;
+            Ok(SigningKey::from_bytes(&bytes))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let key = generate_provider_key();
+            let encoded = format!("{}\n", STANDARD.encode(key.to_bytes()));
+            write_private(path, encoded.as_bytes()).map_err(key_error)?;
+            Ok(key)
+        }
+        Err(e) => Err(key_error(e)),
+    }
+}
+
+/// A client's session with a resolver, keyed by one of its certificates.
+pub struct ClientSession {
+    certificate: Certificate,
+    client_pk: [u8; 32],
+    cipher: Cipher,
+}
+
+impl ClientSession {
+    pub fn new(
+        certificate: Certificate,
+        secret: &crypto_box::SecretKey,
+    ) -> Result<Self, DnsCryptError> {
+        let cipher = Cipher::new(certificate.es_version, &certificate.resolver_pk, secret)?;
+        Ok(ClientSession {
+            certificate,
+            client_pk: secret.public_key().to_bytes(),
+            cipher,
+        })
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.certificate.is_valid_at(unix_now())
+    }
+
+    /// Encrypts `query`, padded to at least `min_len`, returning the
+    /// message and the client half of its nonce.
+    pub fn encrypt_query(&self, query: &[u8], min_len: usize) -> (Vec<u8>, [u8; HALF_NONCE]) {
+        let client_nonce = random_bytes::<HALF_NONCE>();
+        let mut nonce = [0u8; 24];
+        nonce[..HALF_NONCE].copy_from_slice(&client_nonce);
+        let message = [
+            self.certificate.client_magic.as_slice(),
+            &self.client_pk,
+            &client_nonce,
+            &self.cipher.seal(&nonce, &pad(query, min_len)),
+        ]
+        .concat();
+        (message, client_nonce)
+    }
+
+    pub fn decrypt_response(
+        &self,
+        client_nonce: &[u8; HALF_NONCE],
+        response: &[u8],
+    ) -> Result<Vec<u8>, DnsCryptError> {
+        if response_nonce(response) != Some(*client_nonce) {
+            return Err(DnsCryptError::Malformed);
+        }
+        let nonce: [u8; 24] = response[8..RESPONSE_HEADER]
+            .try_into()
+            .map_err(|_| DnsCryptError::Malformed)?;
+        let padded = self.cipher.open(&nonce, &response[RESPONSE_HEADER..])?;
+        Ok(unpad(&padded)?.to_vec())
+    }
+}
+
+/// The client half of a response's nonce, which says which query it
+/// answers.
+pub fn response_nonce(response: &[u8]) -> Option<[u8; HALF_NONCE]> {
+    if response.len() < RESPONSE_HEADER + TAG_LEN || &response[..8] != RESOLVER_MAGIC {
+        return None;
+    }
+    response[8..8 + HALF_NONCE].try_into().ok()
+}
+
+/// A short-term key pair and the certificate publishing it.
+struct ResolverKey {
+    certificate: Certificate,
+    signed: Vec<u8>,
+    secret: crypto_box::SecretKey,
+}
+
+/// A query decrypted by the resolver and what it needs to seal the reply.
+struct Exchange {
+    cipher: Cipher,
+    client_nonce: [u8; HALF_NONCE],
+    query: Vec<u8>,
+}
+
+impl ResolverKey {
+    fn open_query(&self, data: &[u8]) -> Result<Exchange, DnsCryptError> {
+        if data.len() < QUERY_HEADER + TAG_LEN {
+            return Err(DnsCryptError::Malformed);
+        }
+        let client_pk: [u8; 32] = data[8..40]
+            .try_into()
+            .map_err(|_| DnsCryptError::Malformed)?;
+        let client_nonce: [u8; HALF_NONCE] = data[40..QUERY_HEADER]
+            .try_into()
+            .map_err(|_| DnsCryptError::Malformed)?;
+        let mut nonce = [0u8; 24];
+        nonce[..HALF_NONCE].copy_from_slice(&client_nonce);
+
+        let cipher = Cipher::new(self.certificate.es_version, &client_pk, &self.secret)?;
+        let padded = cipher.open(&nonce, &data[QUERY_HEADER..])?;
+        let query = unpad(&padded)?.to_vec();
+        Ok(Exchange {
+            cipher,
+            client_nonce,
+            query,
+        })
+    }
+}
+
+impl Exchange {
+    fn seal_reply(&self, reply: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; 24];
+        nonce[..HALF_NONCE].copy_from_slice(&self.client_nonce);
+        nonce[HALF_NONCE..].copy_from_slice(&random_bytes::<HALF_NONCE>());
+        [
+            RESOLVER_MAGIC,
+            &nonce,
+            &self.cipher.seal(&nonce, &pad(reply, 0)),
+        ]
+        .concat()
+    }
+}
+
+/// The resolver's short-term keys and their certificates. A new one is
+/// issued halfway through the life of the last, and both stay usable so
+/// that clients have time to switch over.
+pub struct ResolverCerts {
+    provider: SigningKey,
+    provider_name: String,
+    keys: RwLock<Vec<Arc<ResolverKey>>>,
+}
+
+impl ResolverCerts {
+    pub fn new(provider: SigningKey, provider_name: &str) -> Self {
+        let certs = ResolverCerts {
+            provider,
+            provider_name: provider_name.to_string(),
+            keys: RwLock::new(Vec::new()),
+        };
+        certs.rotate();
+        certs
+    }
+
+    /// The stamp clients use to reach us at `addr`.
+    pub fn stamp(&self, addr: SocketAddr) -> String {
+        Stamp {
+            addr: addr.to_string(),
+            provider_pk: self.provider.verifying_key(),
+            provider_name: self.provider_name.clone(),
+        }
+        .encode()
+    }
+
+    fn rotate(&self) {
+        let secret = crypto_box::SecretKey::from_bytes(random_bytes());
+        let resolver_pk = secret.public_key().to_bytes();
+        let now = unix_now();
+        let certificate = Certificate {
+            es_version: ES_XCHACHA20POLY1305,
+            resolver_pk,
+            client_magic: resolver_pk[..8].try_into().unwrap_or_default(),
+            serial: now,
+            ts_start: now,
+            ts_end: now.saturating_add(CERT_LIFETIME.as_secs() as u32),
+        };
+        let signed = certificate.sign(&self.provider);
+
+        let mut keys = self.keys.write().unwrap();
+        keys.retain(|key| key.certificate.ts_end > now);
+        keys.push(Arc::new(ResolverKey {
+            certificate,
+            signed,
+            secret,
+        }));
+        let excess = keys.len().saturating_sub(2);
+        keys.drain(..excess);
+    }
+
+    fn key_for(&self, client_magic: &[u8]) -> Option<Arc<ResolverKey>> {
+        self.keys
+            .read()
+            .unwrap()
+            .iter()
+            .find(|key| key.certificate.client_magic == client_magic)
+            .cloned()
+    }
+
+    /// Answers a plain query for our certificates, and refuses any other
+    /// plain query or, unless `allowed`, any plain query at all.
+    fn certificate_reply(&self, data: &[u8], allowed: bool) -> Option<Vec<u8>> {
+        let mut packet = DNSPacket::from_bytes(data).ok()?;
+        if packet.header.qr != 0 || packet.questions.len() != 1 {
+            return None;
+        }
+        let question = &packet.questions[0];
+        if !allowed
+            || question.tp != TYPE_TXT
+            || !question.domain().eq_ignore_ascii_case(&self.provider_name)
+        {
+            packet.make_response(RCODE_REFUSED, Vec::new());
+            return Some(packet.to_bytes());
+        }
+        let answers = self
+            .keys
+            .read()
+            .unwrap()
+            .iter()
+            .map(|key| {
+                let mut rdata = vec![key.signed.len() as u8];
+                rdata.extend_from_slice(&key.signed);
+                Answer::new(question.name.clone(), TYPE_TXT, CERT_TTL, rdata)
+            })
+            .collect();
+        packet.make_response(RCODE_NOERROR, answers);
+        Some(packet.to_bytes())
+    }
+}
+
+/// Issues a new certificate every twelve hours.
+pub async fn run_rotation(certs: Arc<ResolverCerts>) {
+    loop {
+        tokio::time::sleep(CERT_ROTATION).await;
+        certs.rotate();
+    }
+}
+
+/// Answers DNSCrypt queries on `socket` through the same pipeline as
+/// plain UDP queries, along with plain queries for our certificates.
+pub async fn run_udp(socket: Arc<UdpSocket>, certs: Arc<ResolverCerts>, ctx: Context) {
+    let mut buf = vec![0; 4096];
+    loop {
+        let (size, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                let timestamp = Local::now().format("%H:%M:%S");
+                let _ = ctx
+                    .log_tx
+                    .send(format!("[{}] DNSCrypt receive failed: {}", timestamp, e));
+                if !is_transient(&e) {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                continue;
+            }
+        };
+
+        // Shed queries are dropped: a reply would have to be sealed first.
+        let Some(permit) = admit(&ctx) else {
+            continue;
+        };
+        let data = buf[..size].to_vec();
+        let socket = socket.clone();
+        let certs = certs.clone();
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Some(reply) = answer(data, peer, true, &certs, ctx, permit).await {
+                let _ = socket.send_to(&reply, peer).await;
+            }
+        });
+    }
+}
+
+/// Accepts DNSCrypt connections on `listener`, where queries and replies
+/// are length-prefixed as in plain DNS over TCP.
+pub async fn run_tcp(
+    listener: TcpListener,
+    certs: Arc<ResolverCerts>,
+    limits: ConnectionLimits,
+    ctx: Context,
+) {
+    let slots = Arc::new(Semaphore::new(limits.max_connections));
+    let label = Transport::DnsCrypt.label();
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                let timestamp = Local::now().format("%H:%M:%S");
+                let _ = ctx
+                    .log_tx
+                    .send(format!("[{}] DNSCrypt accept failed: {}", timestamp, e));
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let Ok(slot) = slots.clone().try_acquire_owned() else {
+            metrics::CONNECTION_ERRORS
+                .with_label_values(&[label, "limit"])
+                .inc();
+            continue;
+        };
+
+        let certs = certs.clone();
+        let ctx = ctx.clone();
+        let idle_timeout = limits.idle_timeout;
+        tokio::spawn(async move {
+            metrics::OPEN_CONNECTIONS.with_label_values(&[label]).inc();
+            serve_connection(stream, peer, certs, ctx, idle_timeout).await;
+            metrics::OPEN_CONNECTIONS.with_label_values(&[label]).dec();
+            drop(slot);
+        });
+    }
+}
+
+/// Answers queries on one connection until the client closes it or stays
+/// quiet for `idle_timeout`, writing replies back as they complete.
+async fn serve_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    certs: Arc<ResolverCerts>,
+    ctx: Context,
+    idle_timeout: Duration,
+) {
+    let transport = Transport::DnsCrypt;
+    let shutdown = ctx.shutdown.clone();
+    transport::serve_frames(
+        stream,
+        transport,
+        None,
+        idle_timeout,
+        shutdown,
+        move |message, responder| {
+            let (certs, ctx) = (certs.clone(), ctx.clone());
+            async move {
+                let Some(permit) = admit(&ctx) else {
+                    return;
+                };
+                tokio::spawn(async move {
+                    if let Some(reply) = answer(message, peer, false, &certs, ctx, permit).await {
+                        let _ = responder.send(reply, peer).await;
+                    }
+                });
+            }
+        },
+    )
+    .await;
+}
+
+/// Answers one message from a client: an encrypted query, or a plain
+/// request for our certificates. Over UDP the reply may be no larger than
+/// the query, so a reply that would be is replaced with a truncated one
+/// and the client retries over TCP.
+async fn answer(
+    data: Vec<u8>,
+    peer: SocketAddr,
+    udp: bool,
+    certs: &ResolverCerts,
+    ctx: Context,
+    permit: OwnedSemaphorePermit,
+) -> Option<Vec<u8>> {
+    let Some(key) = data.get(..8).and_then(|magic| certs.key_for(magic)) else {
+        // Encrypted queries go through the ACL in the pipeline; plain ones
+        // for our certificates are checked here.
+        let access = ctx.filters.read().unwrap().access.clone();
+        let denied = access.check(Transport::DnsCrypt, None, peer.ip());
+        if let Some(reason) = denied {
+            metrics::REFUSED_QUERIES
+                .with_label_values(&[Transport::DnsCrypt.label(), reason.label()])
+                .inc();
+            if access.action == DenyAction::Drop {
+                return None;
+            }
+        }
+        return certs.certificate_reply(&data, denied.is_none());
+    };
+    let Ok(exchange) = key.open_query(&data) else {
+        metrics::CONNECTION_ERRORS
+            .with_label_values(&[Transport::DnsCrypt.label(), "decrypt"])
+            .inc();
+        return None;
+    };
+    if exchange.query.len() < 12 {
+        return None;
+    }
+
+    let (tx, mut rx) = mpsc::channel(1);
+    let responder = Responder::Channel(Transport::DnsCrypt, None, tx);
+    handle_dns_request(exchange.query.clone(), peer, responder, ctx, permit).await;
+    let mut reply = tokio::time::timeout(QUERY_TIMEOUT, rx.recv())
+        .await
+        .ok()??;
+
+    let room = data.len().saturating_sub(RESPONSE_HEADER + TAG_LEN);
+    if udp
+        && pad(&reply, 0).len() > room
+        && let Ok(mut packet) = DNSPacket::from_bytes(&reply)
+    {
+        packet.header.tc = 1;
+        packet.answers.clear();
+        packet.authorities.clear();
+        packet.resources.clear();
+        packet.update_counts();
+        reply = packet.to_bytes();
+    }
+    Some(exchange.seal_reply(&reply))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{Question, RCODE_REFUSED, name_from_str};
+
+    /// AdGuard's public DNSCrypt resolver, as listed for dnscrypt-proxy.
+    const ADGUARD_STAMP: &str = "sdns://AQcAAAAAAAAAFDE3Ni4xMDMuMTMwLjEzMDo1NDQzINErR_JS3PLCu_iZEIbq95zkSV2LFsigxDIuUso_OQhzIjIuZG5zY3J5cHQuZGVmYXVsdC5uczEuYWRndWFyZC5jb20";
+
+    const PROVIDER: &str = "2.dnscrypt-cert.example.com";
+
+    fn certificate(es_version: u16, serial: u32, ts_start: u32, ts_end: u32) -> Certificate {
+        Certificate {
+            es_version,
+            resolver_pk: [7; 32],
+            client_magic: [9; 8],
+            serial,
+            ts_start,
+            ts_end,
+        }
+    }
+
+    fn txt_record(signed: &[u8]) -> Answer {
+        let rdata = [&[signed.len() as u8][..], signed].concat();
+        Answer::new(name_from_str(PROVIDER), TYPE_TXT, CERT_TTL, rdata)
+    }
+
+    fn certificate_query(name: &str) -> Vec<u8> {
+        let question = Question {
+            name: name_from_str(name),
+            tp: TYPE_TXT,
+            class: 1,
+        };
+        DNSPacket::query(0x4242, question).to_bytes()
+    }
+
+    #[test]
+    fn certificate_sign_and_verify() {
+        let provider = generate_provider_key();
+        let signed = certificate(ES_XCHACHA20POLY1305, 3, 10, 20).sign(&provider);
+        assert_eq!(signed.len(), CERT_LEN);
+
+        let verified = Certificate::verify(&signed, &provider.verifying_key()).unwrap();
+        assert_eq!(verified.es_version, ES_XCHACHA20POLY1305);
+        assert_eq!(verified.resolver_pk, [7; 32]);
+        assert_eq!(verified.client_magic, [9; 8]);
+        assert_eq!(
+            (verified.serial, verified.ts_start, verified.ts_end),
+            (3, 10, 20)
+        );
+        assert!(verified.is_valid_at(15) && !verified.is_valid_at(21));
+    }
+
+    #[test]
+    fn certificate_verify_rejects_forgeries() {
+        let provider = generate_provider_key();
+        let signed = certificate(ES_XCHACHA20POLY1305, 3, 10, 20).sign(&provider);
+
+        let other = generate_provider_key().verifying_key();
+        assert!(matches!(
+            Certificate::verify(&signed, &other),
+            Err(DnsCryptError::NoCertificate)
+        ));
+
+        let mut tampered = signed.clone();
+        tampered[CERT_LEN - 1] ^= 1;
+        assert!(Certificate::verify(&tampered, &provider.verifying_key()).is_err());
+        assert!(matches!(
+            Certificate::verify(&signed[..CERT_LEN - 1], &provider.verifying_key()),
+            Err(DnsCryptError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn best_certificate_prefers_newest_valid() {
+        let provider = generate_provider_key();
+        let now = unix_now();
+        let records: Vec<Answer> = [
+            certificate(ES_XSALSA20POLY1305, 9, now - 10, now + 10),
+            certificate(ES_XCHACHA20POLY1305, 1, now - 10, now + 10),
+            certificate(ES_XCHACHA20POLY1305, 2, now - 10, now + 10),
+            certificate(ES_XCHACHA20POLY1305, 5, now - 20, now - 10),
+        ]
+        .iter()
+        .map(|cert| txt_record(&cert.sign(&provider)))
+        .collect();
+
+        let best = best_certificate(&records, &provider.verifying_key()).unwrap();
+        assert_eq!((best.es_version, best.serial), (ES_XCHACHA20POLY1305, 2));
+        assert!(best_certificate(&records[3..], &provider.verifying_key()).is_err());
+    }
+
+    #[test]
+    fn stamp_parses_published_resolver() {
+        let stamp = Stamp::parse(ADGUARD_STAMP).unwrap();
+        assert_eq!(stamp.addr, "176.103.130.130:5443");
+        assert_eq!(stamp.provider_name, "2.dnscrypt.default.ns1.adguard.com");
+        assert_eq!(
+            hex::encode(stamp.provider_pk.as_bytes()),
+            "d12b47f252dcf2c2bbf8991086eaf79ce4495d8b16c8a0c4322e52ca3f390873"
+        );
+    }
+
+    #[test]
+    fn stamp_round_trip() {
+        let stamp = Stamp {
+            addr: "192.0.2.1:8443".to_string(),
+            provider_pk: generate_provider_key().verifying_key(),
+            provider_name: PROVIDER.to_string(),
+        };
+        let parsed = Stamp::parse(&stamp.encode()).unwrap();
+        assert_eq!(parsed.addr, stamp.addr);
+        assert_eq!(parsed.provider_pk, stamp.provider_pk);
+        assert_eq!(parsed.provider_name, stamp.provider_name);
+    }
+
+    #[test]
+    fn stamp_rejects_other_protocols() {
+        // A DoH stamp, and a DNSCrypt one cut short.
+        for stamp in [
+            "sdns://AgcAAAAAAAAABzEuMC4wLjEAEmRucy5jbG91ZGZsYXJlLmNvbQovZG5zLXF1ZXJ5",
+            "sdns://AQcAAAAAAAAAAAAQMi5kbnNjcnlwdC1jZXJ0Lg",
+            &ADGUARD_STAMP[..60],
+            "tls://192.0.2.1",
+        ] {
+            assert!(Stamp::parse(stamp).is_err(), "{}", stamp);
+        }
+    }
+
+    #[test]
+    fn pad_and_unpad() {
+        for (len, min_len, padded_len) in [(0, 0, 64), (63, 0, 64), (64, 0, 128), (10, 256, 256)] {
+            let message = vec![0xab; len];
+            let padded = pad(&message, min_len);
+            assert_eq!(padded.len(), padded_len);
+            assert_eq!(unpad(&padded).unwrap(), message);
+        }
+        assert!(unpad(&[0xab, 0, 0]).is_err());
+        assert!(unpad(&[0; 64]).is_err());
+    }
+
+    #[test]
+    fn client_session_interoperates_with_resolver() {
+        let certs = ResolverCerts::new(generate_provider_key(), PROVIDER);
+        let reply = certs
+            .certificate_reply(&certificate_query(PROVIDER), true)
+            .unwrap();
+        let reply = DNSPacket::from_bytes(&reply).unwrap();
+        let cert = best_certificate(&reply.answers, &certs.provider.verifying_key()).unwrap();
+
+        let secret = crypto_box::SecretKey::from_bytes(random_bytes());
+        let session = ClientSession::new(cert, &secret).unwrap();
+        let query = certificate_query("example.com");
+        let (message, client_nonce) = session.encrypt_query(&query, MIN_UDP_QUERY);
+        assert!(message.len() >= MIN_UDP_QUERY);
+
+        let key = certs.key_for(&message[..8]).unwrap();
+        let exchange = key.open_query(&message).unwrap();
+        assert_eq!(exchange.query, query);
+
+        let response = exchange.seal_reply(b"reply");
+        assert_eq!(response_nonce(&response), Some(client_nonce));
+        assert_eq!(
+            session.decrypt_response(&client_nonce, &response).unwrap(),
+            b"reply"
+        );
+        assert!(
+            session
+                .decrypt_response(&[0; HALF_NONCE], &response)
+                .is_err()
+        );
+
+        let mut tampered = message.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(matches!(
+            key.open_query(&tampered),
+            Err(DnsCryptError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn certificate_reply_refuses_other_queries() {
+        let certs = ResolverCerts::new(generate_provider_key(), PROVIDER);
+        for (name, allowed) in [("example.com", true), (PROVIDER, false)] {
+            let reply = certs
+                .certificate_reply(&certificate_query(name), allowed)
+                .unwrap();
+            let reply = DNSPacket::from_bytes(&reply).unwrap();
+            assert_eq!(reply.header.rcode, RCODE_REFUSED);
+            assert!(reply.answers.is_empty());
+        }
+        assert!(certs.certificate_reply(b"\x00\x01garbage", true).is_none());
+    }
+}
@@ -0,0 +1,630 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use bytes::Bytes;
+use chrono::Local;
+use ipnet::IpNet;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::{Semaphore, mpsc, watch},
+};
+use tokio_rustls::{TlsAcceptor, rustls::ServerConfig};
+use warp::{
+    Filter, Reply,
+    http::{HeaderValue, Response, StatusCode, header},
+};
+
+use crate::odoh::{ODOH_MESSAGE, OdohError, Relay, TargetKey};
+use crate::packet::{
+    Answer, DNSPacket, Question, TYPE_A, TYPE_AAAA, TYPE_ANY, TYPE_CNAME, TYPE_MX, TYPE_NS,
+    TYPE_PTR, TYPE_SOA, TYPE_SRV, TYPE_TXT, check_name, name_from_str, name_to_string,
+};
+use crate::transport::{ConnectionLimits, QUERY_TIMEOUT, Responder, Transport};
+use crate::{Context, metrics, spawn_query};
+
+/// ALPN protocols offered on the HTTPS listener.
+pub const ALPN_DOH: &[&[u8]] = &[b"h2", b"http/1.1"];
+
+const DNS_MESSAGE: &str = "application/dns-message";
+const DNS_JSON: &str = "application/dns-json";
+/// Upper bound of a DNS message; larger POST bodies are refused.
+const MAX_MESSAGE: u64 = 65535;
+
+/// What the HTTPS endpoint serves besides plain RFC 8484 queries.
+#[derive(Default)]
+pub struct DohOptions {
+    /// Proxies whose `X-Forwarded-For` header is believed.
+    pub trusted_proxies: Vec<IpNet>,
+    /// Answers oblivious queries encrypted to this key when set.
+    pub odoh_target: Option<TargetKey>,
+    /// Relays oblivious queries on `/proxy` when set.
+    pub odoh_relay: Option<Relay>,
+}
+
+/// Accepts DNS-over-HTTPS connections (RFC 8484) on `listener`. Without a
+/// TLS config the endpoint speaks plain HTTP, for use behind a reverse
+/// proxy that terminates TLS.
+pub async fn run_listener(
+    listener: TcpListener,
+    tls: Option<Arc<ServerConfig>>,
+    limits: ConnectionLimits,
+    options: Arc<DohOptions>,
+    ctx: Context,
+) {
+    let acceptor = tls.map(TlsAcceptor::from);
+    let slots = Arc::new(Semaphore::new(limits.max_connections));
+    let label = Transport::Https.label();
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                let timestamp = Local::now().format("%H:%M:%S");
+                let _ = ctx
+                    .log_tx
+                    .send(format!("[{}] DoH accept failed: {}", timestamp, e));
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let Ok(slot) = slots.clone().try_acquire_owned() else {
+            metrics::CONNECTION_ERRORS
+                .with_label_values(&[label, "limit"])
+                .inc();
+            continue;
+        };
+
+        let acceptor = acceptor.clone();
+        let routes = routes(ctx.clone(), peer, options.clone());
+        let idle_timeout = limits.idle_timeout;
+        let shutdown = ctx.shutdown.clone();
+        tokio::spawn(async move {
+            metrics::OPEN_CONNECTIONS.with_label_values(&[label]).inc();
+            match acceptor {
+                Some(acceptor) => {
+                    let handshake =
+                        tokio::time::timeout(idle_timeout, acceptor.accept(stream)).await;
+                    match handshake {
+                        Ok(Ok(stream)) => {
+                            let h2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+                            serve_http(stream, h2, idle_timeout, shutdown, routes).await;
+                        }
+                        _ => metrics::CONNECTION_ERRORS
+                            .with_label_values(&[label, "handshake"])
+                            .inc(),
+                    }
+                }
+                None => serve_http(stream, false, idle_timeout, shutdown, routes).await,
+            }
+            metrics::OPEN_CONNECTIONS.with_label_values(&[label]).dec();
+            drop(slot);
+        });
+    }
+}
+
+/// Serves requests on one connection. Once `shutdown` becomes true,
+/// requests under way are answered and the connection is then closed.
+async fn serve_http<IO, F>(
+    io: IO,
+    h2: bool,
+    idle_timeout: Duration,
+    mut shutdown: watch::Receiver<bool>,
+    routes: F,
+) where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Filter<Extract = (warp::reply::Response,), Error = Infallible>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    let connection = hyper::server::conn::Http::new()
+        .http2_only(h2)
+        .http1_header_read_timeout(idle_timeout)
+        .http2_keep_alive_interval(idle_timeout)
+        .serve_connection(io, warp::service(routes));
+    tokio::pin!(connection);
+    tokio::select! {
+        _ = connection.as_mut() => return,
+        Ok(_) = shutdown.wait_for(|stop| *stop) => {}
+    }
+    connection.as_mut().graceful_shutdown();
+    let _ = connection.await;
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct JsonResponse {
+    status: u8,
+    #[serde(rename = "TC")]
+    tc: bool,
+    #[serde(rename = "RD")]
+    rd: bool,
+    #[serde(rename = "RA")]
+    ra: bool,
+    #[serde(rename = "AD")]
+    ad: bool,
+    #[serde(rename = "CD")]
+    cd: bool,
+    question: Vec<JsonQuestion>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    answer: Vec<JsonRecord>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    authority: Vec<JsonRecord>,
+}
+
+#[derive(Serialize)]
+struct JsonQuestion {
+    name: String,
+    #[serde(rename = "type")]
+    tp: u16,
+}
+
+#[derive(Serialize)]
+struct JsonRecord {
+    name: String,
+    #[serde(rename = "type")]
+    tp: u16,
+    #[serde(rename = "TTL")]
+    ttl: u32,
+    data: String,
+}
+
+/// The `/dns-query` routes for one connection from `peer`, plus the ODoH
+/// ones the options enable.
+fn routes(
+    ctx: Context,
+    peer: SocketAddr,
+    options: Arc<DohOptions>,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Infallible> + Clone + Send + Sync + 'static
+{
+    let forwarded_options = options.clone();
+    let client = warp::header::optional::<String>("x-forwarded-for").map(
+        move |forwarded: Option<String>| {
+            client_addr(peer, forwarded, &forwarded_options.trusted_proxies)
+        },
+    );
+    let with_ctx = warp::any().map(move || ctx.clone());
+    let (relay_client, relay_ctx) = (client.clone(), with_ctx.clone());
+    let with_options = warp::any().map(move || options.clone());
+
+    let get = warp::get()
+        .and(warp::query::<HashMap<String, String>>())
+        .and(client.clone())
+        .and(with_ctx.clone())
+        .and_then(|params: HashMap<String, String>, client, ctx| async move {
+            Ok::<_, Infallible>(match (params.get("dns"), params.get("name")) {
+                (Some(dns), _) => match URL_SAFE_NO_PAD.decode(dns.trim_end_matches('=')) {
+                    Ok(query) => wire_response(ctx, client, query).await,
+                    Err(_) => error(
+                        StatusCode::BAD_REQUEST,
+                        "invalid base64url in dns parameter",
+                    ),
+                },
+                (None, Some(name)) => {
+                    let tp = params.get("type").map(String::as_str).unwrap_or("A");
+                    json_response(ctx, client, name, tp).await
+                }
+                (None, None) => error(StatusCode::BAD_REQUEST, "missing dns or name parameter"),
+            })
+        });
+
+    let post =
+        warp::post()
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::body::content_length_limit(MAX_MESSAGE))
+            .and(warp::body::bytes())
+            .and(client)
+            .and(with_ctx)
+            .and(with_options.clone())
+            .and_then(
+                |content_type: Option<String>,
+                 body: Bytes,
+                 client,
+                 ctx,
+                 options: Arc<DohOptions>| async move {
+                    let media_type = media_type(content_type.as_deref());
+                    Ok::<_, Infallible>(match (media_type.as_deref(), &options.odoh_target) {
+                        (Some(DNS_MESSAGE), _) => wire_response(ctx, client, body.to_vec()).await,
+                        (Some(ODOH_MESSAGE), Some(key)) => {
+                            oblivious_response(ctx, client, key, &body).await
+                        }
+                        _ => error(
+                            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                            "expected application/dns-message",
+                        ),
+                    })
+                },
+            );
+
+    let dns_query = warp::path("dns-query")
+        .and(warp::path::end())
+        .and(get.or(post).unify());
+
+    let configs = warp::path!(".well-known" / "odohconfigs")
+        .and(warp::get())
+        .and(with_options.clone())
+        .map(|options: Arc<DohOptions>| match &options.odoh_target {
+            Some(key) => Response::builder()
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .header(header::CACHE_CONTROL, "max-age=86400")
+                .body(key.configs())
+                .unwrap()
+                .into_response(),
+            None => error(StatusCode::NOT_FOUND, "not found"),
+        });
+
+    let relay = warp::path("proxy")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::content_length_limit(MAX_MESSAGE))
+        .and(warp::body::bytes())
+        .and(relay_client)
+        .and(relay_ctx)
+        .and(with_options)
+        .and_then(
+            |params: HashMap<String, String>,
+             content_type: Option<String>,
+             body: Bytes,
+             client: SocketAddr,
+             ctx: Context,
+             options: Arc<DohOptions>| async move {
+                Ok::<_, Infallible>(
+                    relay_response(&options, &ctx, client, &params, content_type, body).await,
+                )
+            },
+        );
+
+    dns_query
+        .or(configs)
+        .unify()
+        .or(relay)
+        .unify()
+        .recover(|_| async { Ok::<_, Infallible>(error(StatusCode::NOT_FOUND, "not found")) })
+        .unify()
+}
+
+/// The address the query is attributed to: the connecting peer, or the
+/// client named in `X-Forwarded-For` when the peer is a trusted proxy.
+fn client_addr(peer: SocketAddr, forwarded: Option<String>, trusted: &[IpNet]) -> SocketAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(&ip.to_canonical()));
+    if !is_trusted(&peer.ip()) {
+        return peer;
+    }
+    // Proxies append, so the first untrusted hop from the right is the
+    // furthest one that can be believed.
+    forwarded
+        .iter()
+        .flat_map(|header| header.rsplit(','))
+        .filter_map(|hop| hop.trim().parse::<IpAddr>().ok())
+        .find(|ip| !is_trusted(ip))
+        .map(|ip| SocketAddr::new(ip, 0))
+        .unwrap_or(peer)
+}
+
+/// The media type of a `Content-Type` header, in lower case and without
+/// parameters such as `charset`.
+fn media_type(content_type: Option<&str>) -> Option<String> {
+    let value = content_type?;
+    let essence = value.split_once(';').map_or(value, |(essence, _)| essence);
+    Some(essence.trim().to_ascii_lowercase())
+}
+
+/// Whether the pipeline would answer `query`. Anything else is refused at
+/// once rather than left to time out.
+fn is_query(query: &[u8]) -> bool {
+    DNSPacket::from_bytes(query).is_ok_and(|packet| !packet.questions.is_empty())
+}
+
+/// Runs `query` through the request pipeline and waits for its reply.
+async fn resolve(ctx: Context, client: SocketAddr, query: Vec<u8>) -> Option<Vec<u8>> {
+    if query.len() < 12 {
+        return None;
+    }
+    let (tx, mut rx) = mpsc::channel(1);
+    let responder = Responder::Channel(Transport::Https, None, tx);
+    spawn_query(query, client, responder, &ctx).await;
+    tokio::time::timeout(QUERY_TIMEOUT, rx.recv())
+        .await
+        .ok()
+        .flatten()
+}
+
+async fn wire_response(ctx: Context, client: SocketAddr, query: Vec<u8>) -> warp::reply::Response {
+    if !is_query(&query) {
+        return error(StatusCode::BAD_REQUEST, "malformed query");
+    }
+    match resolve(ctx, client, query).await {
+        Some(reply) => {
+            let max_age = DNSPacket::from_bytes(&reply).map_or(0, |reply| min_ttl(&reply));
+            Response::builder()
+                .header(header::CONTENT_TYPE, DNS_MESSAGE)
+                .header(header::CACHE_CONTROL, format!("max-age={}", max_age))
+                .body(reply)
+                .unwrap()
+                .into_response()
+        }
+        None => error(StatusCode::BAD_GATEWAY, "no answer"),
+    }
+}
+
+/// Answers an oblivious query. Key mismatches get a 401 so the client
+/// knows to fetch the current configuration (RFC 9230 section 7).
+async fn oblivious_response(
+    ctx: Context,
+    client: SocketAddr,
+    key: &TargetKey,
+    body: &[u8],
+) -> warp::reply::Response {
+    let (query, response_key) = match key.decrypt_query(body) {
+        Ok(decrypted) => decrypted,
+        Err(OdohError::UnknownKey) => return error(StatusCode::UNAUTHORIZED, "unknown key"),
+        Err(_) => return error(StatusCode::BAD_REQUEST, "undecryptable query"),
+    };
+    if !is_query(&query) {
+        return error(StatusCode::BAD_REQUEST, "malformed query");
+    }
+    match resolve(ctx, client, query).await {
+        // Each response is encrypted to one query, so caching it is useless.
+        Some(reply) => Response::builder()
+            .header(header::CONTENT_TYPE, ODOH_MESSAGE)
+            .header(header::CACHE_CONTROL, "no-cache, no-store")
+            .body(response_key.encrypt_response(&reply))
+            .unwrap()
+            .into_response(),
+        None => error(StatusCode::BAD_GATEWAY, "no answer"),
+    }
+}
+
+/// Passes an oblivious query on to the target named in the request. The
+/// client's address and headers stay here; only the body travels on.
+/// Clients the listener's access lists turn away are refused.
+async fn relay_response(
+    options: &DohOptions,
+    ctx: &Context,
+    client: SocketAddr,
+    params: &HashMap<String, String>,
+    content_type: Option<String>,
+    body: Bytes,
+) -> warp::reply::Response {
+    let Some(relay) = &options.odoh_relay else {
+        return error(StatusCode::NOT_FOUND, "not found");
+    };
+    let access = ctx.filters.read().unwrap().access.clone();
+    if let Some(reason) = access.check(Transport::Https, None, client.ip()) {
+        metrics::REFUSED_QUERIES
+            .with_label_values(&[Transport::Https.label(), reason.label()])
+            .inc();
+        return error(StatusCode::FORBIDDEN, "forbidden");
+    }
+    if media_type(content_type.as_deref()).as_deref() != Some(ODOH_MESSAGE) {
+        return error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "expected application/oblivious-dns-message",
+        );
+    }
+    let (Some(host), Some(path)) = (params.get("targethost"), params.get("targetpath")) else {
+        return error(StatusCode::BAD_REQUEST, "missing targethost or targetpath");
+    };
+    if !relay.allows(host, path) {
+        return error(StatusCode::FORBIDDEN, "target not allowed");
+    }
+
+    match relay.forward(host, path, body).await {
+        Ok((status, reply)) => Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, ODOH_MESSAGE)
+            .header(header::CACHE_CONTROL, "no-cache, no-store")
+            .body(reply.to_vec())
+            .unwrap()
+            .into_response(),
+        Err(_) => error(StatusCode::BAD_GATEWAY, "target unreachable"),
+    }
+}
+
+async fn json_response(
+    ctx: Context,
+    client: SocketAddr,
+    name: &str,
+    tp: &str,
+) -> warp::reply::Response {
+    let Some(tp) = parse_type(tp) else {
+        return error(StatusCode::BAD_REQUEST, "unknown record type");
+    };
+    if check_name(name).is_err() {
+        return error(StatusCode::BAD_REQUEST, "invalid name");
+    }
+    let query = DNSPacket::query(
+        0,
+        Question {
+            name: name_from_str(name),
+            tp,
+            class: 1,
+        },
+    );
+
+    let Some(reply) = resolve(ctx, client, query.to_bytes()).await else {
+        return error(StatusCode::BAD_GATEWAY, "no answer");
+    };
+    let Ok(reply) = DNSPacket::from_bytes(&reply) else {
+        return error(StatusCode::BAD_GATEWAY, "malformed answer");
+    };
+    let body = JsonResponse {
+        status: reply.header.rcode,
+        tc: reply.header.tc == 1,
+        rd: reply.header.rd == 1,
+        ra: reply.header.ra == 1,
+        ad: false,
+        cd: false,
+        question: reply
+            .questions
+            .iter()
+            .map(|q| JsonQuestion {
+                name: format!("{}.", q.domain()),
+                tp: q.tp,
+            })
+            .collect(),
+        answer: reply.answers.iter().map(json_record).collect(),
+        authority: reply.authorities.iter().map(json_record).collect(),
+    };
+
+    let mut response = warp::reply::json(&body).into_response();
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(DNS_JSON));
+    response.headers_mut().insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_str(&format!("max-age={}", min_ttl(&reply))).unwrap(),
+    );
+    response
+}
+
+fn error(status: StatusCode, message: &'static str) -> warp::reply::Response {
+    warp::reply::with_status(message, status).into_response()
+}
+
+/// The lowest TTL in a reply, which bounds how long it may be cached.
+fn min_ttl(packet: &DNSPacket) -> u32 {
+    packet
+        .answers
+        .iter()
+        .chain(packet.authorities.iter())
+        .map(|a| a.ttl)
+        .min()
+        .unwrap_or(0)
+}
+
+fn parse_type(tp: &str) -> Option<u16> {
+    if let Ok(code) = tp.parse() {
+        return Some(code);
+    }
+    let code = match tp.to_ascii_uppercase().as_str() {
+        "A" => TYPE_A,
+        "NS" => TYPE_NS,
+        "CNAME" => TYPE_CNAME,
+        "SOA" => TYPE_SOA,
+        "PTR" => TYPE_PTR,
+        "MX" => TYPE_MX,
+        "TXT" => TYPE_TXT,
+        "AAAA" => TYPE_AAAA,
+        "SRV" => TYPE_SRV,
+        "ANY" => TYPE_ANY,
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// Splits an uncompressed wire-format name off the front of `data`.
+fn split_name(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut end = 0;
+    loop {
+        let len = *data.get(end)? as usize;
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        end += 1 + len;
+        if len == 0 {
+            return (end <= data.len()).then(|| data.split_at(end));
+        }
+    }
+}
+
+fn json_record(record: &Answer) -> JsonRecord {
+    let fqdn = |name: &[u8]| format!("{}.", name_to_string(name));
+    let data = match record.tp {
+        TYPE_A | TYPE_AAAA => record.ip().map(|ip| ip.to_string()),
+        TYPE_CNAME | TYPE_NS | TYPE_PTR => Some(fqdn(&record.data)),
+        TYPE_MX if record.data.len() > 2 => {
+            let preference = u16::from_be_bytes([record.data[0], record.data[1]]);
+            Some(format!("{} {}", preference, fqdn(&record.data[2..])))
+        }
+        TYPE_TXT => {
+            let mut strings = Vec::new();
+            let mut rest = &record.data[..];
+            while let Some((&len, tail)) = rest.split_first() {
+                let len = (len as usize).min(tail.len());
+                strings.push(format!("\"{}\"", String::from_utf8_lossy(&tail[..len])));
+                rest = &tail[len..];
+            }
+            Some(strings.join(" "))
+        }
+        TYPE_SOA => split_name(&record.data).and_then(|(mname, rest)| {
+            let (rname, rest) = split_name(rest)?;
+            let fields: Vec<String> = rest
+                .chunks_exact(4)
+                .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]).to_string())
+                .collect();
+            (fields.len() == 5)
+                .then(|| format!("{} {} {}", fqdn(mname), fqdn(rname), fields.join(" ")))
+        }),
+        TYPE_SRV if record.data.len() > 6 => {
+            let field = |i: usize| u16::from_be_bytes([record.data[i], record.data[i + 1]]);
+            Some(format!(
+                "{} {} {} {}",
+                field(0),
+                field(2),
+                field(4),
+                fqdn(&record.data[6..])
+            ))
+        }
+        _ => None,
+    };
+
+    JsonRecord {
+        name: fqdn(&record.name),
+        tp: record.tp,
+        ttl: record.ttl,
+        // Types without a presentation format use the generic RFC 3597 one.
+        data: data.unwrap_or_else(|| {
+            let hex: String = record.data.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("\\# {} {}", record.data.len(), hex)
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn media_type_ignores_parameters_and_case() {
+        assert_eq!(
+            media_type(Some("application/dns-message; charset=utf-8")).as_deref(),
+            Some(DNS_MESSAGE)
+        );
+        assert_eq!(
+            media_type(Some(" Application/DNS-Message ")).as_deref(),
+            Some(DNS_MESSAGE)
+        );
+        assert_eq!(
+            media_type(Some("text/plain")).as_deref(),
+            Some("text/plain")
+        );
+        assert_eq!(media_type(None), None);
+    }
+
+    #[test]
+    fn only_queries_with_a_question_are_resolved() {
+        let question = Question {
+            name: name_from_str("example.com"),
+            tp: TYPE_A,
+            class: 1,
+        };
+        let query = DNSPacket::query(1, question).to_bytes();
+        assert!(is_query(&query));
+        assert!(!is_query(&query[..12]));
+        assert!(!is_query(&query[..query.len() - 1]));
+        assert!(!is_query(b"not dns"));
+    }
+}
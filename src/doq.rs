@@ -0,0 +1,170 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use quinn::{Connection, Endpoint, Incoming, RecvStream, SendStream};
+use tokio::sync::{Semaphore, mpsc};
+use tokio_rustls::rustls::ServerConfig;
+
+use crate::tls::TlsError;
+use crate::transport::{ConnectionLimits, QUERY_TIMEOUT, Responder, Transport};
+use crate::{Context, metrics, spawn_query};
+
+/// ALPN protocol identifier for DNS-over-QUIC (RFC 9250).
+pub const ALPN_DOQ: &[u8] = b"doq";
+
+/// Queries a client may have in flight on one connection.
+const MAX_STREAMS: u32 = 100;
+/// A two-byte length prefix and the largest DNS message it can describe.
+const MAX_FRAME: usize = 2 + 65535;
+
+/// How long closing the endpoint waits for clients to acknowledge.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Error codes from RFC 9250 section 4.3.
+const DOQ_NO_ERROR: u32 = 0x0;
+const DOQ_INTERNAL_ERROR: u32 = 0x1;
+const DOQ_PROTOCOL_ERROR: u32 = 0x2;
+
+/// Builds the QUIC configuration for the listener. 0-RTT data can be
+/// replayed by an attacker, so it is only accepted when `early_data` is set.
+pub fn server_config(
+    tls: &ServerConfig,
+    early_data: bool,
+    idle_timeout: Duration,
+) -> Result<quinn::ServerConfig, TlsError> {
+    let mut tls = tls.clone();
+    tls.max_early_data_size = if early_data { u32::MAX } else { 0 };
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls)?;
+
+    let mut transport = quinn::TransportConfig::default();
+    transport
+        .max_idle_timeout(idle_timeout.try_into().ok())
+        .max_concurrent_bidi_streams(MAX_STREAMS.into())
+        .max_concurrent_uni_streams(0u32.into());
+
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
+}
+
+/// Accepts DNS-over-QUIC connections (RFC 9250) on `endpoint` and answers
+/// every query stream through the same pipeline as plain UDP queries.
+pub async fn run_listener(
+    endpoint: Endpoint,
+    limits: ConnectionLimits,
+    early_data: bool,
+    ctx: Context,
+) {
+    let slots = Arc::new(Semaphore::new(limits.max_connections));
+    let label = Transport::Quic.label();
+
+    while let Some(incoming) = endpoint.accept().await {
+        let Ok(slot) = slots.clone().try_acquire_owned() else {
+            metrics::CONNECTION_ERRORS
+                .with_label_values(&[label, "limit"])
+                .inc();
+            incoming.refuse();
+            continue;
+        };
+
+        let ctx = ctx.clone();
+        let idle_timeout = limits.idle_timeout;
+        tokio::spawn(async move {
+            let Some(connection) = handshake(incoming, early_data, idle_timeout).await else {
+                metrics::CONNECTION_ERRORS
+                    .with_label_values(&[label, "handshake"])
+                    .inc();
+                return;
+            };
+
+            metrics::OPEN_CONNECTIONS.with_label_values(&[label]).inc();
+            let peer = connection.remote_address();
+            let mut shutdown = ctx.shutdown.clone();
+            loop {
+                let (send, recv) = tokio::select! {
+                    stream = connection.accept_bi() => match stream {
+                        Ok(stream) => stream,
+                        Err(_) => break,
+                    },
+                    Ok(_) = shutdown.wait_for(|stop| *stop) => break,
+                };
+                tokio::spawn(serve_query(
+                    connection.clone(),
+                    send,
+                    recv,
+                    peer,
+                    ctx.clone(),
+                ));
+            }
+            metrics::OPEN_CONNECTIONS.with_label_values(&[label]).dec();
+            drop(slot);
+        });
+    }
+}
+
+/// Closes every connection on `endpoint`, telling clients the server is
+/// going away rather than failing, and waits briefly for them to hear it.
+pub async fn close(endpoint: &Endpoint) {
+    endpoint.close(DOQ_NO_ERROR.into(), b"shutting down");
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, endpoint.wait_idle()).await;
+}
+
+/// Completes the handshake, or with 0-RTT enabled hands the connection
+/// over straight away so early queries are answered without waiting.
+async fn handshake(incoming: Incoming, early_data: bool, timeout: Duration) -> Option<Connection> {
+    let connecting = incoming.accept().ok()?;
+    let connecting = if early_data {
+        match connecting.into_0rtt() {
+            Ok((connection, _)) => return Some(connection),
+            Err(connecting) => connecting,
+        }
+    } else {
+        connecting
+    };
+    tokio::time::timeout(timeout, connecting).await.ok()?.ok()
+}
+
+/// Answers the single query a client sends on a stream. Queries carry a
+/// two-byte length prefix and a message ID of 0 (RFC 9250 section 4.2),
+/// which the pipeline echoes back in the reply.
+async fn serve_query(
+    connection: Connection,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    peer: SocketAddr,
+    ctx: Context,
+) {
+    // The client finishes the stream after its query, or resets it to
+    // cancel; either way there is nothing more to read.
+    let Ok(message) = recv.read_to_end(MAX_FRAME).await else {
+        return;
+    };
+
+    let well_formed = message.len() >= 14
+        && u16::from_be_bytes([message[0], message[1]]) as usize == message.len() - 2
+        && message[2..4] == [0, 0];
+    if !well_formed {
+        metrics::CONNECTION_ERRORS
+            .with_label_values(&[Transport::Quic.label(), "protocol"])
+            .inc();
+        connection.close(DOQ_PROTOCOL_ERROR.into(), b"malformed query");
+        return;
+    }
+
+    let (tx, mut rx) = mpsc::channel(1);
+    let responder = Responder::Channel(Transport::Quic, None, tx);
+    spawn_query(message[2..].to_vec(), peer, responder, &ctx).await;
+
+    match tokio::time::timeout(QUERY_TIMEOUT, rx.recv()).await {
+        Ok(Some(reply)) => {
+            let mut frame = Vec::with_capacity(reply.len() + 2);
+            frame.extend_from_slice(&(reply.len() as u16).to_be_bytes());
+            frame.extend_from_slice(&reply);
+            if send.write_all(&frame).await.is_ok() {
+                let _ = send.finish();
+            }
+        }
+        _ => {
+            let _ = send.reset(DOQ_INTERNAL_ERROR.into());
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::Local;
+use tokio::{net::TcpListener, sync::Semaphore};
+use tokio_rustls::{TlsAcceptor, rustls::ServerConfig};
+
+use crate::transport::{self, ConnectionLimits, Transport};
+use crate::{Context, metrics};
+
+/// ALPN protocol identifier for DNS-over-TLS (RFC 7858).
+pub const ALPN_DOT: &[u8] = b"dot";
+
+/// Accepts DNS-over-TLS connections (RFC 7858) on `listener` and answers
+/// them through the same pipeline as plain UDP queries.
+pub async fn run_listener(
+    listener: TcpListener,
+    tls: Arc<ServerConfig>,
+    limits: ConnectionLimits,
+    ctx: Context,
+) {
+    let acceptor = TlsAcceptor::from(tls);
+    let slots = Arc::new(Semaphore::new(limits.max_connections));
+    let label = Transport::Tls.label();
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                let timestamp = Local::now().format("%H:%M:%S");
+                let _ = ctx
+                    .log_tx
+                    .send(format!("[{}] DoT accept failed: {}", timestamp, e));
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let Ok(slot) = slots.clone().try_acquire_owned() else {
+            metrics::CONNECTION_ERRORS
+                .with_label_values(&[label, "limit"])
+                .inc();
+            continue;
+        };
+
+        let acceptor = acceptor.clone();
+        let ctx = ctx.clone();
+        let idle_timeout = limits.idle_timeout;
+        tokio::spawn(async move {
+            let handshake = tokio::time::timeout(idle_timeout, acceptor.accept(stream)).await;
+            let Ok(Ok(stream)) = handshake else {
+                metrics::CONNECTION_ERRORS
+                    .with_label_values(&[label, "handshake"])
+                    .inc();
+                return;
+            };
+
+            metrics::OPEN_CONNECTIONS.with_label_values(&[label]).inc();
+            transport::serve_stream(stream, peer, Transport::Tls, None, ctx, idle_timeout).await;
+            metrics::OPEN_CONNECTIONS.with_label_values(&[label]).dec();
+            drop(slot);
+        });
+    }
+}
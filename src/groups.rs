@@ -0,0 +1,324 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::blocklist::{BlockMode, FilterPolicy};
+use crate::rewrite::{RewriteError, RewriteRules};
+use crate::schedule::TimeWindow;
+
+/// Name of the group clients fall into when no configured group matches.
+pub const DEFAULT_GROUP: &str = "default";
+
+/// How long the kernel ARP table is trusted before it is read again.
+const ARP_REFRESH: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum GroupsError {
+    #[error("failed to read {path}: {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("invalid groups file: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("list name {0} is reserved for the lists given by --blocklists")]
+    ReservedList(String),
+    #[error("group {group}: unknown list {list}")]
+    UnknownList { group: String, list: String },
+    #[error("group {group}: schedule for {list}, which is not one of its blocklists")]
+    UnscheduledList { group: String, list: String },
+    #[error("group {group}: {source}")]
+    Rewrite { group: String, source: RewriteError },
+    #[error("group {group}: invalid client {client}, expected an IP, CIDR or MAC address")]
+    InvalidClient { group: String, client: String },
+}
+
+impl GroupsError {
+    /// The group the error is about, if it is about one.
+    pub fn group(&self) -> Option<&str> {
+        match self {
+            GroupsError::UnknownList { group, .. }
+            | GroupsError::UnscheduledList { group, .. }
+            | GroupsError::Rewrite { group, .. }
+            | GroupsError::InvalidClient { group, .. } => Some(group),
+            GroupsError::Read { .. } | GroupsError::Parse(_) | GroupsError::ReservedList(_) => None,
+        }
+    }
+}
+
+/// Named lists and client groups, as read from the `--groups` TOML file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupsFile {
+    /// List name to the URLs or paths it is built from.
+    #[serde(default)]
+    pub lists: BTreeMap<String, Vec<String>>,
+    /// Custom rewrite rule sets: set name to a map of domain to target.
+    #[serde(default)]
+    pub rewrites: BTreeMap<String, BTreeMap<String, String>>,
+    /// Groups in match order; the first group claiming a client wins.
+    #[serde(default)]
+    pub groups: Vec<GroupConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupConfig {
+    pub name: String,
+    /// IP addresses, CIDR prefixes or MAC addresses of the members.
+    pub clients: Vec<String>,
+    /// Lists to block, or the default policy's lists when omitted.
+    pub blocklists: Option<Vec<String>>,
+    /// Lists to never block, or the default policy's lists when omitted.
+    pub allowlists: Option<Vec<String>>,
+    /// Blocklists that only apply during the given time windows.
+    #[serde(default)]
+    pub schedules: BTreeMap<String, Vec<TimeWindow>>,
+    /// Rewrite rule sets to apply, or the default policy's when omitted.
+    pub rewrites: Option<Vec<String>>,
+    pub block_mode: Option<BlockMode>,
+    pub block_ttl: Option<u32>,
+    pub sink_ipv4: Option<Ipv4Addr>,
+    pub sink_ipv6: Option<Ipv6Addr>,
+    pub block_ede: Option<bool>,
+}
+
+impl GroupsFile {
+    pub fn load(path: &Path) -> Result<Self, GroupsError> {
+        let body = std::fs::read_to_string(path).map_err(|source| GroupsError::Read {
+            path: path.display().to_string(),
+            source,
+        })?;
+        Ok(toml::from_str(&body)?)
+    }
+}
+
+#[derive(Debug)]
+enum ClientMatcher {
+    Net(IpNet),
+    Mac([u8; 6]),
+}
+
+impl ClientMatcher {
+    fn parse(client: &str) -> Option<Self> {
+        if let Ok(ip) = client.parse::<IpAddr>() {
+            return Some(Self::Net(IpNet::from(ip)));
+        }
+        if let Ok(net) = client.parse::<IpNet>() {
+            return Some(Self::Net(net.trunc()));
+        }
+        parse_mac(client).map(Self::Mac)
+    }
+}
+
+pub struct ClientGroup {
+    pub name: String,
+    clients: Vec<ClientMatcher>,
+    pub policy: FilterPolicy,
+}
+
+/// Maps a query's source address to the group whose policy applies.
+pub struct ClientGroups {
+    groups: Vec<ClientGroup>,
+    default: ClientGroup,
+    arp: Option<ArpTable>,
+}
+
+impl ClientGroups {
+    /// Builds the groups in `config`, filling unset fields from `default`.
+    /// `lists` are the names of every configured list.
+    pub fn new(
+        config: &GroupsFile,
+        default: FilterPolicy,
+        lists: &[String],
+    ) -> Result<Self, GroupsError> {
+        // The default list is built from --blocklists; another by its name
+        // would be listed twice and never used.
+        if config.lists.contains_key(DEFAULT_GROUP) {
+            return Err(GroupsError::ReservedList(DEFAULT_GROUP.to_string()));
+        }
+        let mut groups = Vec::with_capacity(config.groups.len());
+
+        for group in &config.groups {
+            let clients = group
+                .clients
+                .iter()
+                .map(|client| {
+                    ClientMatcher::parse(client).ok_or_else(|| GroupsError::InvalidClient {
+                        group: group.name.clone(),
+                        client: client.clone(),
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let blocklists = group
+                .blocklists
+                .clone()
+                .unwrap_or(default.blocklists.clone());
+            let allowlists = group
+                .allowlists
+                .clone()
+                .unwrap_or(default.allowlists.clone());
+            if let Some(list) = blocklists
+                .iter()
+                .chain(allowlists.iter())
+                .find(|name| !lists.contains(name))
+            {
+                return Err(GroupsError::UnknownList {
+                    group: group.name.clone(),
+                    list: list.clone(),
+                });
+            }
+            if let Some(list) = group
+                .schedules
+                .keys()
+                .find(|name| !blocklists.contains(name))
+            {
+                return Err(GroupsError::UnscheduledList {
+                    group: group.name.clone(),
+                    list: list.clone(),
+                });
+            }
+
+            let rewrites = match &group.rewrites {
+                Some(names) => {
+                    RewriteRules::from_sets(names, &config.rewrites).map_err(|source| {
+                        GroupsError::Rewrite {
+                            group: group.name.clone(),
+                            source,
+                        }
+                    })?
+                }
+                None => default.rewrites.clone(),
+            };
+
+            let mut response = default.response.clone();
+            response.mode = group.block_mode.unwrap_or(response.mode);
+            response.ttl = group.block_ttl.unwrap_or(response.ttl);
+            response.sink_ipv4 = group.sink_ipv4.unwrap_or(response.sink_ipv4);
+            response.sink_ipv6 = group.sink_ipv6.unwrap_or(response.sink_ipv6);
+            response.ede = group.block_ede.unwrap_or(response.ede);
+
+            groups.push(ClientGroup {
+                name: group.name.clone(),
+                clients,
+                policy: FilterPolicy {
+                    blocklists,
+                    allowlists,
+                    schedules: group.schedules.clone().into_iter().collect(),
+                    rewrites,
+                    response,
+                },
+            });
+        }
+
+        let uses_mac = groups
+            .iter()
+            .flat_map(|g| g.clients.iter())
+            .any(|c| matches!(c, ClientMatcher::Mac(_)));
+
+        Ok(Self {
+            groups,
+            default: ClientGroup {
+                name: DEFAULT_GROUP.to_string(),
+                clients: Vec::new(),
+                policy: default,
+            },
+            arp: uses_mac.then(ArpTable::new),
+        })
+    }
+
+    /// Whether any group matches clients by MAC address.
+    pub fn uses_mac(&self) -> bool {
+        self.arp.is_some()
+    }
+
+    /// Whether any group has blocklists that only apply at certain times.
+    pub fn uses_schedules(&self) -> bool {
+        self.groups
+            .iter()
+            .any(|group| !group.policy.schedules.is_empty())
+    }
+
+    pub fn resolve(&self, client: IpAddr) -> &ClientGroup {
+        let client = client.to_canonical();
+        let mut mac = None;
+
+        for group in &self.groups {
+            for matcher in &group.clients {
+                let matched = match matcher {
+                    ClientMatcher::Net(net) => net.contains(&client),
+                    ClientMatcher::Mac(expected) => {
+                        if mac.is_none() {
+                            mac = Some(self.arp.as_ref().and_then(|arp| arp.lookup(client)));
+                        }
+                        mac.flatten().as_ref() == Some(expected)
+                    }
+                };
+                if matched {
+                    return group;
+                }
+            }
+        }
+        &self.default
+    }
+}
+
+/// The kernel's IPv4 neighbour table, used to identify clients by MAC.
+struct ArpTable {
+    entries: RwLock<(Instant, HashMap<IpAddr, [u8; 6]>)>,
+}
+
+impl ArpTable {
+    fn new() -> Self {
+        Self {
+            entries: RwLock::new((Instant::now(), read_arp_table())),
+        }
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Option<[u8; 6]> {
+        {
+            let entries = self.entries.read().unwrap();
+            if entries.0.elapsed() < ARP_REFRESH {
+                return entries.1.get(&ip).copied();
+            }
+        }
+        let mut entries = self.entries.write().unwrap();
+        *entries = (Instant::now(), read_arp_table());
+        entries.1.get(&ip).copied()
+    }
+}
+
+/// Parses `/proc/net/arp`, which is empty on platforms without it.
+fn read_arp_table() -> HashMap<IpAddr, [u8; 6]> {
+    let body = std::fs::read_to_string("/proc/net/arp").unwrap_or_default();
+    body.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let ip = fields.first()?.parse().ok()?;
+            let mac = parse_mac(fields.get(3)?)?;
+            (mac != [0; 6]).then_some((ip, mac))
+        })
+        .collect()
+}
+
+fn parse_mac(s: &str) -> Option<[u8; 6]> {
+    let parts: Vec<&str> = s.split([':', '-']).collect();
+    if parts.len() != 6 {
+        return None;
+    }
+    let mut mac = [0u8; 6];
+    for (byte, part) in mac.iter_mut().zip(parts) {
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    Some(mac)
+}
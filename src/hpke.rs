@@ -0,0 +1,380 @@
+//! The base mode of HPKE (RFC 9180) for the one suite ODoH requires:
+//! DHKEM(X25519, HKDF-SHA256), HKDF-SHA256 and AES-128-GCM.
+
+use aes_gcm::{
+    Aes128Gcm, KeyInit, Nonce,
+    aead::{Aead, Payload},
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use thiserror::Error;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub const KEM_X25519_HKDF_SHA256: u16 = 0x0020;
+pub const KDF_HKDF_SHA256: u16 = 0x0001;
+pub const AEAD_AES_128_GCM: u16 = 0x0001;
+
+/// Sizes of the AEAD key and nonce, the KDF output and an encapsulated key.
+pub const NK: usize = 16;
+pub const NN: usize = 12;
+pub const NH: usize = 32;
+pub const NENC: usize = 32;
+
+const MODE_BASE: u8 = 0x00;
+
+#[derive(Debug, Error)]
+pub enum HpkeError {
+    #[error("invalid encapsulated key")]
+    InvalidKey,
+    #[error("decryption failed")]
+    Open,
+}
+
+/// Fills an array from the operating system's random number generator.
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes).expect("system random number generator unavailable");
+    bytes
+}
+
+/// Generates a new X25519 key pair.
+pub fn generate_key() -> (StaticSecret, PublicKey) {
+    let secret = StaticSecret::from(random_bytes::<32>());
+    let public = PublicKey::from(&secret);
+    (secret, public)
+}
+
+/// An encryption context established between a sender and a receiver.
+pub struct Context {
+    key: [u8; NK],
+    base_nonce: [u8; NN],
+    exporter_secret: [u8; NH],
+    seq: u64,
+}
+
+/// Encapsulates a fresh shared secret to `recipient`, returning the
+/// encapsulated key to send along and the sender's context.
+pub fn setup_sender(recipient: &PublicKey, info: &[u8]) -> ([u8; NENC], Context) {
+    let (ephemeral, _) = generate_key();
+    setup_sender_with(&ephemeral, recipient, info)
+}
+
+fn setup_sender_with(
+    ephemeral: &StaticSecret,
+    recipient: &PublicKey,
+    info: &[u8],
+) -> ([u8; NENC], Context) {
+    let enc = PublicKey::from(ephemeral);
+    let dh = ephemeral.diffie_hellman(recipient);
+    let shared_secret = extract_and_expand(dh.as_bytes(), enc.as_bytes(), recipient.as_bytes());
+    (*enc.as_bytes(), key_schedule(&shared_secret, info))
+}
+
+/// Recovers the shared secret from the sender's encapsulated key.
+pub fn setup_receiver(
+    enc: &[u8],
+    secret: &StaticSecret,
+    info: &[u8],
+) -> Result<Context, HpkeError> {
+    let enc: [u8; NENC] = enc.try_into().map_err(|_| HpkeError::InvalidKey)?;
+    let dh = secret.diffie_hellman(&PublicKey::from(enc));
+    // Low-order points give an all-zero secret (RFC 9180 section 7.1.4).
+    if !dh.was_contributory() {
+        return Err(HpkeError::InvalidKey);
+    }
+    let recipient = PublicKey::from(secret);
+    let shared_secret = extract_and_expand(dh.as_bytes(), &enc, recipient.as_bytes());
+    Ok(key_schedule(&shared_secret, info))
+}
+
+impl Context {
+    pub fn seal(&mut self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let nonce = self.next_nonce();
+        seal(&self.key, &nonce, aad, plaintext)
+    }
+
+    pub fn open(&mut self, aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, HpkeError> {
+        let nonce = self.next_nonce();
+        open(&self.key, &nonce, aad, ciphertext)
+    }
+
+    /// Derives a secret of `len` bytes bound to this context.
+    pub fn export(&self, exporter_context: &[u8], len: usize) -> Vec<u8> {
+        let mut out = vec![0; len];
+        labeled_expand(
+            &self.exporter_secret,
+            &hpke_suite_id(),
+            b"sec",
+            exporter_context,
+            &mut out,
+        );
+        out
+    }
+
+    fn next_nonce(&mut self) -> [u8; NN] {
+        let mut nonce = self.base_nonce;
+        for (byte, seq) in nonce[NN - 8..].iter_mut().zip(self.seq.to_be_bytes()) {
+            *byte ^= seq;
+        }
+        self.seq += 1;
+        nonce
+    }
+}
+
+/// AES-128-GCM encryption with an explicit key and nonce.
+pub fn seal(key: &[u8; NK], nonce: &[u8; NN], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    Aes128Gcm::new(key.into())
+        .encrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .expect("AES-GCM encryption of a bounded message")
+}
+
+pub fn open(
+    key: &[u8; NK],
+    nonce: &[u8; NN],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, HpkeError> {
+    Aes128Gcm::new(key.into())
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| HpkeError::Open)
+}
+
+fn kem_suite_id() -> Vec<u8> {
+    [b"KEM".as_slice(), &KEM_X25519_HKDF_SHA256.to_be_bytes()].concat()
+}
+
+fn hpke_suite_id() -> Vec<u8> {
+    [
+        b"HPKE".as_slice(),
+        &KEM_X25519_HKDF_SHA256.to_be_bytes(),
+        &KDF_HKDF_SHA256.to_be_bytes(),
+        &AEAD_AES_128_GCM.to_be_bytes(),
+    ]
+    .concat()
+}
+
+fn labeled_extract(suite_id: &[u8], salt: &[u8], label: &[u8], ikm: &[u8]) -> [u8; NH] {
+    let labeled_ikm = [b"HPKE-v1".as_slice(), suite_id, label, ikm].concat();
+    Hkdf::<Sha256>::extract(Some(salt), &labeled_ikm).0.into()
+}
+
+fn labeled_expand(prk: &[u8; NH], suite_id: &[u8], label: &[u8], info: &[u8], out: &mut [u8]) {
+    let length = (out.len() as u16).to_be_bytes();
+    Hkdf::<Sha256>::from_prk(prk)
+        .expect("PRK of hash length")
+        .expand_multi_info(&[&length, b"HPKE-v1", suite_id, label, info], out)
+        .expect("HKDF output within bounds");
+}
+
+/// DHKEM's ExtractAndExpand with a KEM context of `enc || pkR`.
+fn extract_and_expand(dh: &[u8], enc: &[u8], recipient: &[u8]) -> [u8; NH] {
+    let suite_id = kem_suite_id();
+    let eae_prk = labeled_extract(&suite_id, b"", b"eae_prk", dh);
+    let kem_context = [enc, recipient].concat();
+    let mut shared_secret = [0u8; NH];
+    labeled_expand(
+        &eae_prk,
+        &suite_id,
+        b"shared_secret",
+        &kem_context,
+        &mut shared_secret,
+    );
+    shared_secret
+}
+
+/// The key schedule for the base mode, which uses no PSK.
+fn key_schedule(shared_secret: &[u8; NH], info: &[u8]) -> Context {
+    let suite_id = hpke_suite_id();
+    let psk_id_hash = labeled_extract(&suite_id, b"", b"psk_id_hash", b"");
+    let info_hash = labeled_extract(&suite_id, b"", b"info_hash", info);
+    let key_schedule_context = [&[MODE_BASE][..], &psk_id_hash, &info_hash].concat();
+    let secret = labeled_extract(&suite_id, shared_secret, b"secret", b"");
+
+    let mut context = Context {
+        key: [0; NK],
+        base_nonce: [0; NN],
+        exporter_secret: [0; NH],
+        seq: 0,
+    };
+    labeled_expand(
+        &secret,
+        &suite_id,
+        b"key",
+        &key_schedule_context,
+        &mut context.key,
+    );
+    labeled_expand(
+        &secret,
+        &suite_id,
+        b"base_nonce",
+        &key_schedule_context,
+        &mut context.base_nonce,
+    );
+    labeled_expand(
+        &secret,
+        &suite_id,
+        b"exp",
+        &key_schedule_context,
+        &mut context.exporter_secret,
+    );
+    context
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex<const N: usize>(s: &str) -> [u8; N] {
+        hex::decode(s).unwrap().try_into().unwrap()
+    }
+
+    // RFC 9180 appendix A.1.1: DHKEM(X25519, HKDF-SHA256), HKDF-SHA256,
+    // AES-128-GCM in base mode.
+    const INFO: &str = "4f6465206f6e2061204772656369616e2055726e";
+    const SK_E: &str = "52c4a758a802cd8b936eceea314432798d5baf2d7e9235dc084ab1b9cfa2f736";
+    const PK_E: &str = "37fda3567bdbd628e88668c3c8d7e97d1d1253b6d4ea6d44c150f741f1bf4431";
+    const SK_R: &str = "4612c550263fc8ad58375df3f557aac531d26850903e55a9f23f21d8534e8ac8";
+    const PK_R: &str = "3948cfe0ad1ddb695d780e59077195da6c56506b027329794ab02bca80815c4d";
+    const SHARED_SECRET: &str = "fe0e18c9f024ce43799ae393c7e8fe8fce9d218875e8227b0187c04e7d2ea1fc";
+    const KEY: &str = "4531685d41d65f03dc48f6b8302c05b0";
+    const BASE_NONCE: &str = "56d890e5accaaf011cff4b7d";
+    const EXPORTER_SECRET: &str =
+        "45ff1c2e220db587171952c0592d5f5ebe103f1561a2614e38f2ffd47e99e3f8";
+    const PLAINTEXT: &str = "4265617574792069732074727574682c20747275746820626561757479";
+
+    /// Sequence number, AAD and ciphertext of the vector's encryptions.
+    const ENCRYPTIONS: &[(u64, &str, &str)] = &[
+        (
+            0,
+            "436f756e742d30",
+            "f938558b5d72f1a23810b4be2ab4f84331acc02fc97babc53a52ae8218a355a96d8770ac83d07bea87e13c512a",
+        ),
+        (
+            1,
+            "436f756e742d31",
+            "af2d7e9ac9ae7e270f46ba1f975be53c09f8d875bdc8535458c2494e8a6eab251c03d0c22a56b8ca42c2063b84",
+        ),
+        (
+            2,
+            "436f756e742d32",
+            "498dfcabd92e8acedc281e85af1cb4e3e31c7dc394a1ca20e173cb72516491588d96a19ad4a683518973dcc180",
+        ),
+        (
+            4,
+            "436f756e742d34",
+            "583bd32bc67a5994bb8ceaca813d369bca7b2a42408cddef5e22f880b631215a09fc0012bc69fccaa251c0246d",
+        ),
+        (
+            255,
+            "436f756e742d323535",
+            "7175db9717964058640a3a11fb9007941a5d1757fda1a6935c805c21af32505bf106deefec4a49ac38d71c9e0a",
+        ),
+        (
+            256,
+            "436f756e742d323536",
+            "957f9800542b0b8891badb026d79cc54597cb2d225b54c00c5238c25d05c30e3fbeda97d2e0e1aba483a2df9f2",
+        ),
+    ];
+
+    /// Exporter context and the 32-byte value exported for it.
+    const EXPORTS: &[(&str, &str)] = &[
+        (
+            "",
+            "3853fe2b4035195a573ffc53856e77058e15d9ea064de3e59f4961d0095250ee",
+        ),
+        (
+            "00",
+            "2e8f0b54673c7029649d4eb9d5e33bf1872cf76d623ff164ac185da9e88c21a5",
+        ),
+        (
+            "54657374436f6e74657874",
+            "e9e43065102c3836401bed8c3c3c75ae46be1639869391d62c61f1ec7af54931",
+        ),
+    ];
+
+    fn contexts() -> (Context, Context) {
+        let info = hex::decode(INFO).unwrap();
+        let ephemeral = StaticSecret::from(unhex::<32>(SK_E));
+        let recipient = StaticSecret::from(unhex::<32>(SK_R));
+        assert_eq!(PublicKey::from(&recipient).as_bytes(), &unhex::<32>(PK_R));
+
+        let (enc, sender) = setup_sender_with(&ephemeral, &PublicKey::from(&recipient), &info);
+        assert_eq!(enc, unhex::<32>(PK_E));
+        let receiver = setup_receiver(&enc, &recipient, &info).unwrap();
+        (sender, receiver)
+    }
+
+    #[test]
+    fn key_schedule_matches_rfc_vector() {
+        let shared_secret = extract_and_expand(
+            StaticSecret::from(unhex::<32>(SK_E))
+                .diffie_hellman(&PublicKey::from(unhex::<32>(PK_R)))
+                .as_bytes(),
+            &unhex::<32>(PK_E),
+            &unhex::<32>(PK_R),
+        );
+        assert_eq!(shared_secret, unhex::<32>(SHARED_SECRET));
+
+        let (sender, receiver) = contexts();
+        for context in [sender, receiver] {
+            assert_eq!(context.key, unhex::<16>(KEY));
+            assert_eq!(context.base_nonce, unhex::<12>(BASE_NONCE));
+            assert_eq!(context.exporter_secret, unhex::<32>(EXPORTER_SECRET));
+        }
+    }
+
+    #[test]
+    fn seal_and_open_match_rfc_vector() {
+        let plaintext = hex::decode(PLAINTEXT).unwrap();
+        let (mut sender, mut receiver) = contexts();
+        for &(seq, aad, ciphertext) in ENCRYPTIONS {
+            let aad = hex::decode(aad).unwrap();
+            sender.seq = seq;
+            receiver.seq = seq;
+            let sealed = sender.seal(&aad, &plaintext);
+            assert_eq!(hex::encode(&sealed), ciphertext, "sequence {}", seq);
+            assert_eq!(receiver.open(&aad, &sealed).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn export_matches_rfc_vector() {
+        let (sender, _) = contexts();
+        for &(exporter_context, exported) in EXPORTS {
+            let exporter_context = hex::decode(exporter_context).unwrap();
+            assert_eq!(hex::encode(sender.export(&exporter_context, 32)), exported);
+        }
+    }
+
+    #[test]
+    fn open_rejects_tampering() {
+        let (mut sender, mut receiver) = contexts();
+        let mut sealed = sender.seal(b"aad", b"message");
+        sealed[0] ^= 1;
+        assert!(matches!(
+            receiver.open(b"aad", &sealed),
+            Err(HpkeError::Open)
+        ));
+    }
+
+    #[test]
+    fn receiver_rejects_low_order_keys() {
+        let (secret, _) = generate_key();
+        assert!(matches!(
+            setup_receiver(&[0; NENC], &secret, b""),
+            Err(HpkeError::InvalidKey)
+        ));
+    }
+}
@@ -0,0 +1,405 @@
+//! Plain DNS over UDP and TCP on any number of addresses: IPv4, IPv6 (kept
+//! apart from IPv4 with `IPV6_V6ONLY`) and sockets tied to one interface.
+//! Wildcard UDP sockets learn each query's destination from `IP_PKTINFO`
+//! or `IPV6_RECVPKTINFO` and reply from it, so multi-homed hosts answer
+//! from the address the client asked.
+
+use std::{
+    fmt,
+    io::{self, IoSlice, IoSliceMut},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::fd::AsRawFd,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::Local;
+use nix::libc;
+use nix::sys::socket::{
+    ControlMessage, ControlMessageOwned, MsgFlags, SockaddrStorage, recvmsg, sendmsg, setsockopt,
+    sockopt,
+};
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::Interest;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::Semaphore;
+
+use crate::packet::{DNSPacket, MIN_UDP_PAYLOAD};
+use crate::transport::{self, ConnectionLimits, Responder, Transport};
+use crate::{Context, metrics, spawn_query};
+
+/// Where to accept plain DNS queries: `ADDR`, `ADDR:PORT` or `[ADDR]:PORT`,
+/// optionally followed by `@INTERFACE` to only take queries arriving on
+/// that interface. Without a port, `--port` applies.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ListenAddr {
+    pub ip: IpAddr,
+    pub port: Option<u16>,
+    pub interface: Option<String>,
+}
+
+impl ListenAddr {
+    /// The wildcard addresses used when no listen address is given.
+    pub fn defaults() -> [ListenAddr; 2] {
+        [Ipv4Addr::UNSPECIFIED.into(), Ipv6Addr::UNSPECIFIED.into()].map(|ip| ListenAddr {
+            ip,
+            port: None,
+            interface: None,
+        })
+    }
+
+    pub fn socket_addr(&self, default_port: u16) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port.unwrap_or(default_port))
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, interface) = match s.split_once('@') {
+            Some((_, "")) => return Err(format!("{} names no interface after @", s)),
+            Some((addr, interface)) => (addr, Some(interface.to_string())),
+            None => (s, None),
+        };
+        // Brackets only ever hold an IPv6 address, and only a whole one.
+        let ip = match addr.strip_prefix('[').and_then(|ip| ip.strip_suffix(']')) {
+            Some(ip) => ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6),
+            None => addr.parse().ok(),
+        };
+        let (ip, port) = match (addr.parse::<SocketAddr>(), ip) {
+            (Ok(addr), _) => (addr.ip(), Some(addr.port())),
+            (_, Some(ip)) => (ip, None),
+            _ => return Err(format!("{} is not an address or address:port", addr)),
+        };
+        Ok(ListenAddr {
+            ip,
+            port,
+            interface,
+        })
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.port, self.ip) {
+            (Some(port), ip) => write!(f, "{}", SocketAddr::new(ip, port))?,
+            (None, IpAddr::V6(ip)) => write!(f, "[{}]", ip)?,
+            (None, IpAddr::V4(ip)) => write!(f, "{}", ip)?,
+        }
+        if let Some(interface) = &self.interface {
+            write!(f, "@{}", interface)?;
+        }
+        Ok(())
+    }
+}
+
+/// Binds a TCP socket for plain DNS on the same terms as a UDP one, for
+/// clients retrying truncated answers and those that prefer TCP.
+pub fn bind_tcp(listen: &ListenAddr, default_port: u16) -> io::Result<TcpListener> {
+    let addr = listen.socket_addr(default_port);
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    if let Some(interface) = &listen.interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// The local end of a query received on a wildcard socket.
+#[derive(Debug, Clone, Copy)]
+pub struct PacketInfo {
+    local: IpAddr,
+    interface: u32,
+}
+
+/// A bound UDP socket for plain DNS.
+pub struct UdpListener {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    /// Whether the socket reports each query's destination address.
+    pktinfo: bool,
+}
+
+impl UdpListener {
+    pub fn bind(listen: &ListenAddr, default_port: u16) -> io::Result<Self> {
+        let addr = listen.socket_addr(default_port);
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        if let Some(interface) = &listen.interface {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+        socket.bind(&addr.into())?;
+        Self::from_socket(socket)
+    }
+
+    /// Wraps a socket that is already bound, such as one passed by systemd.
+    pub fn from_socket(socket: Socket) -> io::Result<Self> {
+        let addr = socket
+            .local_addr()?
+            .as_socket()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not an IP socket"))?;
+        // Only a wildcard socket can receive on more than one address.
+        let pktinfo = addr.ip().is_unspecified();
+        if pktinfo {
+            match addr {
+                SocketAddr::V4(_) => setsockopt(&socket, sockopt::Ipv4PacketInfo, &true)?,
+                SocketAddr::V6(_) => setsockopt(&socket, sockopt::Ipv6RecvPacketInfo, &true)?,
+            }
+        }
+        socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(socket.into())?;
+        Ok(Self {
+            addr: socket.local_addr()?,
+            socket,
+            pktinfo,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Receives a query, returning its size, sender and, on wildcard
+    /// sockets, the address it was sent to.
+    pub async fn recv(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<PacketInfo>)> {
+        if !self.pktinfo {
+            let (size, source) = self.socket.recv_from(buf).await?;
+            return Ok((size, source, None));
+        }
+        self.socket
+            .async_io(Interest::READABLE, || {
+                let mut cmsg = nix::cmsg_space!(libc::in6_pktinfo);
+                let mut iov = [IoSliceMut::new(buf)];
+                let fd = self.socket.as_raw_fd();
+                let msg =
+                    recvmsg::<SockaddrStorage>(fd, &mut iov, Some(&mut cmsg), MsgFlags::empty())?;
+                let source = msg
+                    .address
+                    .as_ref()
+                    .and_then(to_socket_addr)
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "query without a source address")
+                    })?;
+                let info = msg.cmsgs()?.find_map(|cmsg| match cmsg {
+                    ControlMessageOwned::Ipv4PacketInfo(info) => Some(PacketInfo {
+                        local: Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr)).into(),
+                        interface: info.ipi_ifindex as u32,
+                    }),
+                    ControlMessageOwned::Ipv6PacketInfo(info) => Some(PacketInfo {
+                        local: Ipv6Addr::from(info.ipi6_addr.s6_addr).into(),
+                        interface: info.ipi6_ifindex,
+                    }),
+                    _ => None,
+                });
+                Ok((msg.bytes, source, info))
+            })
+            .await
+    }
+
+    /// Sends `reply` to `client`, from the address the query arrived on if
+    /// `info` says which one that was.
+    pub async fn send(
+        &self,
+        reply: &[u8],
+        client: SocketAddr,
+        info: Option<PacketInfo>,
+    ) -> io::Result<()> {
+        let Some(info) = info else {
+            return self.socket.send_to(reply, client).await.map(|_| ());
+        };
+        self.socket
+            .async_io(Interest::WRITABLE, || {
+                let iov = [IoSlice::new(reply)];
+                let dest = SockaddrStorage::from(client);
+                let fd = self.socket.as_raw_fd();
+                match info.local {
+                    IpAddr::V4(local) => {
+                        let pktinfo = libc::in_pktinfo {
+                            ipi_ifindex: 0,
+                            ipi_spec_dst: libc::in_addr {
+                                s_addr: u32::from(local).to_be(),
+                            },
+                            ipi_addr: libc::in_addr { s_addr: 0 },
+                        };
+                        let cmsg = [ControlMessage::Ipv4PacketInfo(&pktinfo)];
+                        sendmsg(fd, &iov, &cmsg, MsgFlags::empty(), Some(&dest))?;
+                    }
+                    IpAddr::V6(local) => {
+                        // The interface matters for link-local addresses.
+                        let pktinfo = libc::in6_pktinfo {
+                            ipi6_addr: libc::in6_addr {
+                                s6_addr: local.octets(),
+                            },
+                            ipi6_ifindex: info.interface,
+                        };
+                        let cmsg = [ControlMessage::Ipv6PacketInfo(&pktinfo)];
+                        sendmsg(fd, &iov, &cmsg, MsgFlags::empty(), Some(&dest))?;
+                    }
+                }
+                Ok(())
+            })
+            .await
+    }
+}
+
+fn to_socket_addr(addr: &SockaddrStorage) -> Option<SocketAddr> {
+    if let Some(addr) = addr.as_sockaddr_in() {
+        return Some(SocketAddrV4::from(*addr).into());
+    }
+    addr.as_sockaddr_in6()
+        .map(|addr| SocketAddrV6::from(*addr).into())
+}
+
+/// Answers queries arriving on `listener`. Anything sent from `ignore`,
+/// the plain upstream resolver, is dropped rather than answered.
+pub async fn run_udp(
+    listener: Arc<UdpListener>,
+    buffer_size: usize,
+    ignore: Option<SocketAddr>,
+    ctx: Context,
+) {
+    let mut buf = vec![0; buffer_size];
+    loop {
+        let (size, source, info) = match listener.recv(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                let timestamp = Local::now().format("%H:%M:%S");
+                let _ = ctx.log_tx.send(format!(
+                    "[{}] Receive failed on {}: {}",
+                    timestamp,
+                    listener.local_addr(),
+                    e
+                ));
+                if !transport::is_transient(&e) {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                continue;
+            }
+        };
+
+        if Some(source) == ignore {
+            continue;
+        }
+
+        let data = buf[0..size].to_vec();
+        let max_size =
+            DNSPacket::from_bytes(&data).map_or(MIN_UDP_PAYLOAD, |query| query.udp_payload_size());
+        let responder = Responder::Udp(listener.clone(), info, ctx.limiter.clone(), max_size);
+        spawn_query(data, source, responder, &ctx).await;
+    }
+}
+
+/// Answers length-prefixed queries on TCP connections to `listener`.
+pub async fn run_tcp(listener: TcpListener, limits: ConnectionLimits, ctx: Context) {
+    let slots = Arc::new(Semaphore::new(limits.max_connections));
+    let label = Transport::Tcp.label();
+    let local = listener.local_addr().ok();
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                let timestamp = Local::now().format("%H:%M:%S");
+                let _ = ctx
+                    .log_tx
+                    .send(format!("[{}] TCP accept failed: {}", timestamp, e));
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let Ok(slot) = slots.clone().try_acquire_owned() else {
+            metrics::CONNECTION_ERRORS
+                .with_label_values(&[label, "limit"])
+                .inc();
+            continue;
+        };
+
+        let ctx = ctx.clone();
+        let idle_timeout = limits.idle_timeout;
+        tokio::spawn(async move {
+            metrics::OPEN_CONNECTIONS.with_label_values(&[label]).inc();
+            transport::serve_stream(stream, peer, Transport::Tcp, local, ctx, idle_timeout).await;
+            metrics::OPEN_CONNECTIONS.with_label_values(&[label]).dec();
+            drop(slot);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listen(s: &str) -> Result<ListenAddr, String> {
+        s.parse()
+    }
+
+    #[test]
+    fn parses_listen_addresses() {
+        let parsed = listen("192.168.1.2").unwrap();
+        assert_eq!(parsed.ip, IpAddr::from([192, 168, 1, 2]));
+        assert_eq!((parsed.port, parsed.interface), (None, None));
+
+        let parsed = listen("[fd00::1]:5353@eth0").unwrap();
+        assert_eq!(parsed.ip, "fd00::1".parse::<IpAddr>().unwrap());
+        assert_eq!(parsed.port, Some(5353));
+        assert_eq!(parsed.interface.as_deref(), Some("eth0"));
+
+        assert_eq!(listen("::").unwrap().ip, Ipv6Addr::UNSPECIFIED);
+        assert_eq!(listen("[::1]").unwrap().ip, Ipv6Addr::LOCALHOST);
+        assert_eq!(listen("0.0.0.0@br-lan").unwrap().port, None);
+    }
+
+    #[test]
+    fn rejects_malformed_listen_addresses() {
+        for bad in [
+            "",
+            "localhost",
+            "192.168.1.2@",
+            "192.168.1.2:99999",
+            "[192.168.1.2]",
+            "[::1",
+            "::1]",
+            "fd00::1:53:53:53:53:53:53",
+        ] {
+            assert!(listen(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn displays_what_it_parses() {
+        for s in [
+            "192.168.1.2",
+            "192.168.1.2:53",
+            "[fd00::1]",
+            "[fd00::1]:53@eth0",
+        ] {
+            assert_eq!(listen(s).unwrap().to_string(), s);
+        }
+        let addr = listen("[fd00::1]").unwrap();
+        assert_eq!(addr.socket_addr(53), "[fd00::1]:53".parse().unwrap());
+    }
+}
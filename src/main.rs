@@ -1,41 +1,520 @@
 use chrono::Local;
-use clap::Parser;
-use crate::blocklist::DNSBlocklist;
+use crate::acl::{AccessControl, Acl, DenyAction};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use crate::blocklist::{BlockMode, BlockResponse, DNSBlocklist, FilterPolicy};
 use crate::cache::DNSCache;
-use crate::packet::DNSPacket;
+use crate::config::ConfigFile;
+use crate::dnscrypt::ResolverCerts;
+use crate::doh::DohOptions;
+use crate::groups::{ClientGroups, DEFAULT_GROUP, GroupsFile};
+use crate::listen::{ListenAddr, UdpListener};
+use crate::odoh::{Relay, TargetKey};
+use crate::ratelimit::{RateLimiter, RateLimits};
+use crate::packet::{DNSPacket, Question, RCODE_NOERROR, RCODE_NXDOMAIN, RCODE_REFUSED, RCODE_SERVFAIL, check_name, name_from_str};
+use crate::rebind::RebindProtection;
+use crate::reload::Reloader;
+use crate::rewrite::RewriteRules;
+use crate::tls::SpkiPin;
+use crate::transport::{ConnectionLimits, Responder, Transport};
+use crate::upstream::{Upstream, UpstreamOptions};
+use crate::zone::{LocalZones, ZoneData};
+use tokio_rustls::rustls::ServerConfig;
 use std::collections::HashMap;
-use std::io;
-use std::net::SocketAddr;
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicU16;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
-use tokio::sync::broadcast;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, broadcast, mpsc, oneshot, watch};
 use warp::Filter;
 
+mod acl;
 mod blocklist;
 mod cache;
+mod config;
+mod dhcp;
+mod dnscrypt;
+mod doh;
+mod doq;
+mod dot;
+mod groups;
+mod hpke;
+mod listen;
 mod metrics;
+mod odoh;
+mod privileges;
 mod packet;
+mod ratelimit;
+mod rebind;
+mod reload;
+mod rewrite;
+mod schedule;
+mod shutdown;
+mod tls;
+mod systemd;
+mod transport;
 mod tui;
+mod upstream;
+mod zone;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Address of the upstream DNS resolver
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// TOML configuration file; flags given on the command line override it
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Upstream DNS resolver: host:port for plain DNS, a tls://, https://
+    /// or quic:// URL for an encrypted one, or an sdns:// DNSCrypt stamp
     #[arg(short, long, default_value = "1.1.1.1:53")]
     resolver: String,
 
+    /// Plain DNS server used to look up the upstream resolver's hostname
+    #[arg(long, default_value = "1.1.1.1:53")]
+    bootstrap: SocketAddr,
+
+    /// PEM file of CA certificates to trust for encrypted upstreams instead of the system store
+    #[arg(long)]
+    upstream_ca: Option<PathBuf>,
+
+    /// Base64 SHA-256 of an upstream certificate's public key that must match (repeatable)
+    #[arg(long, value_parser = tls::parse_pin)]
+    upstream_pin: Vec<SpkiPin>,
+
+    /// Number of connections kept open to a tls:// upstream
+    #[arg(long, default_value_t = 2)]
+    upstream_connections: usize,
+
+    /// Queries waiting for the upstream resolver at once (at most 65535, one per
+    /// query ID); more are answered SERVFAIL
+    #[arg(long, default_value_t = 4096, value_parser = clap::value_parser!(u16).range(1..))]
+    upstream_max_pending: u16,
+
     /// Port to listen on for DNS requests
     #[arg(short, long, default_value_t = 53)]
     port: u16,
 
+    /// Address to answer plain DNS on, as ADDR, ADDR:PORT or [ADDR]:PORT with an
+    /// optional @INTERFACE (repeatable); 0.0.0.0 and [::] on --port if not given
+    #[arg(long)]
+    listen: Vec<ListenAddr>,
+
+    /// Access lists of single listen addresses, which only the config file sets
+    #[arg(skip)]
+    listen_access: Vec<(ListenAddr, Acl)>,
+
+    /// Size of the buffer UDP queries are read into, from 512 to 65535
+    #[arg(long, default_value_t = 1232, value_parser = clap::value_parser!(u16).range(512..))]
+    udp_buffer_size: u16,
+
+    /// Maximum number of simultaneous plain DNS TCP connections
+    #[arg(long, default_value_t = 512)]
+    tcp_max_connections: usize,
+
+    /// Seconds a plain DNS TCP connection may stay idle before it is closed
+    #[arg(long, default_value_t = 10)]
+    tcp_idle_timeout: u64,
+
+    /// Client address or CIDR allowed to query; everyone else is turned away (repeatable)
+    #[arg(long, value_parser = parse_net)]
+    allow: Vec<IpNet>,
+
+    /// Client address or CIDR never answered, even if allowed (repeatable)
+    #[arg(long, value_parser = parse_net)]
+    deny: Vec<IpNet>,
+
+    /// What clients that may not query get
+    #[arg(long, value_enum, default_value_t = DenyAction::Refuse)]
+    deny_action: DenyAction,
+
+    /// Queries handled at once; more are answered REFUSED until some finish
+    #[arg(long, default_value_t = 1024)]
+    max_inflight: usize,
+
+    /// Queries per second each client network may send before the rest are dropped; 0 for no limit
+    #[arg(long, default_value_t = 0)]
+    ratelimit: u32,
+
+    /// Queries a client network may send at once after being quiet, by default twice --ratelimit
+    #[arg(long)]
+    ratelimit_burst: Option<u32>,
+
+    /// Prefix length IPv4 clients are grouped by for rate limiting
+    #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u8).range(0..=32))]
+    ratelimit_ipv4_prefix: u8,
+
+    /// Prefix length IPv6 clients are grouped by for rate limiting
+    #[arg(long, default_value_t = 56, value_parser = clap::value_parser!(u8).range(0..=128))]
+    ratelimit_ipv6_prefix: u8,
+
+    /// Client address or CIDR never rate limited (repeatable)
+    #[arg(long, value_parser = parse_net)]
+    ratelimit_exempt: Vec<IpNet>,
+
+    /// Identical UDP responses per second sent to each client network; 0 turns RRL off
+    #[arg(long, default_value_t = 0)]
+    rrl_rate: u32,
+
+    /// Send every Nth response over the RRL limit truncated instead of dropping it; 0 drops all
+    #[arg(long, default_value_t = 2)]
+    rrl_slip: u32,
+
     /// Disable the TUI and run in headless mode
     #[arg(long, default_value_t = false)]
     no_tui: bool,
+
+    /// Shortest time in seconds an answer is cached for, whatever its TTL
+    #[arg(long, default_value_t = 300)]
+    cache_min_ttl: u32,
+
+    /// File the cache is saved to on shutdown and loaded from at startup
+    #[arg(long)]
+    cache_snapshot: Option<PathBuf>,
+
+    /// Seconds to wait on shutdown for replies to queries already forwarded
+    #[arg(long, default_value_t = 5)]
+    shutdown_timeout: u64,
+
+    /// User to switch to once the listening sockets are bound
+    #[arg(long)]
+    user: Option<String>,
+
+    /// Group to switch to, by default the user's primary group
+    #[arg(long)]
+    group: Option<String>,
+
+    /// Directory to chroot into before switching user; later paths are inside it
+    #[arg(long)]
+    chroot: Option<PathBuf>,
+
+    /// Address to serve /metrics and the API on
+    #[arg(long, default_value = "0.0.0.0:3030")]
+    metrics_listen: SocketAddr,
+
+    /// Token the API requires as "Authorization: Bearer TOKEN"; without one the
+    /// API only answers requests from this machine
+    #[arg(long)]
+    api_token: Option<String>,
+
+    /// Number of query log lines buffered for the TUI or stdout
+    #[arg(long, default_value_t = 100)]
+    log_buffer_size: usize,
+
+    /// How to answer queries for blocked domains
+    #[arg(long, value_enum, default_value_t = BlockMode::NullIp)]
+    block_mode: BlockMode,
+
+    /// IPv4 address returned for blocked A queries in sink mode
+    #[arg(long, default_value_t = Ipv4Addr::UNSPECIFIED)]
+    sink_ipv4: Ipv4Addr,
+
+    /// IPv6 address returned for blocked AAAA queries in sink mode
+    #[arg(long, default_value_t = Ipv6Addr::UNSPECIFIED)]
+    sink_ipv6: Ipv6Addr,
+
+    /// TTL in seconds of blocked responses
+    #[arg(long, default_value_t = 300)]
+    block_ttl: u32,
+
+    /// Attach an Extended DNS Error "Blocked" option to blocked responses
+    #[arg(long, default_value_t = false)]
+    block_ede: bool,
+
+    /// URL or local path of a blocklist in hosts or plain-domain format (repeatable)
+    #[arg(
+        long = "blocklist",
+        default_value = "https://raw.githubusercontent.com/StevenBlack/hosts/master/hosts"
+    )]
+    blocklists: Vec<String>,
+
+    /// Seconds between blocklist refreshes
+    #[arg(long, default_value_t = 86400)]
+    blocklist_refresh: u64,
+
+    /// Directory where downloaded blocklists are cached for offline startup
+    #[arg(long, default_value = "/var/cache/rdns")]
+    blocklist_cache_dir: PathBuf,
+
+    /// Do not read or write the on-disk blocklist cache
+    #[arg(long, default_value_t = false)]
+    no_blocklist_cache: bool,
+
+    /// TOML file defining extra lists and per-client filtering groups
+    #[arg(long)]
+    groups: Option<PathBuf>,
+
+    /// Strip private and loopback addresses from answers for public domains
+    #[arg(long, default_value_t = false)]
+    rebind_protection: bool,
+
+    /// Domain allowed to resolve to private addresses despite rebinding protection (repeatable)
+    #[arg(long)]
+    rebind_allow: Vec<String>,
+
+    /// Rewrite rule set applied to every client, e.g. safe-search or youtube-restricted (repeatable)
+    #[arg(long = "rewrite")]
+    rewrites: Vec<String>,
+
+    /// RFC 1035 zone file served authoritatively, as PATH or ORIGIN=PATH (repeatable)
+    #[arg(long = "zone")]
+    zones: Vec<String>,
+
+    /// Domain answered locally from hosts files, e.g. home (repeatable)
+    #[arg(long = "local-domain")]
+    local_domains: Vec<String>,
+
+    /// Hosts file of local names to answer for (repeatable)
+    #[arg(long = "hosts")]
+    hosts_files: Vec<String>,
+
+    /// dnsmasq or ISC dhcpd lease file whose hostnames are served locally (repeatable)
+    #[arg(long)]
+    dhcp_leases: Vec<PathBuf>,
+
+    /// Domain under which DHCP lease hostnames are registered
+    #[arg(long, default_value = "lan")]
+    dhcp_domain: String,
+
+    /// Address to accept DNS-over-TLS connections on, e.g. 0.0.0.0:853
+    #[arg(long)]
+    dot_listen: Option<SocketAddr>,
+
+    /// PEM certificate chain for the encrypted listeners
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for the encrypted listeners
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+
+    /// Maximum number of simultaneous DNS-over-TLS connections
+    #[arg(long, default_value_t = 512)]
+    dot_max_connections: usize,
+
+    /// Seconds a DNS-over-TLS connection may stay idle before it is closed
+    #[arg(long, default_value_t = 30)]
+    dot_idle_timeout: u64,
+
+    /// Client address or CIDR allowed to use DNS-over-TLS, instead of --allow (repeatable)
+    #[arg(long, value_parser = parse_net)]
+    dot_allow: Vec<IpNet>,
+
+    /// Client address or CIDR denied DNS-over-TLS, instead of --deny (repeatable)
+    #[arg(long, value_parser = parse_net)]
+    dot_deny: Vec<IpNet>,
+
+    /// Address to serve DNS-over-HTTPS (/dns-query) on, e.g. 0.0.0.0:443
+    #[arg(long)]
+    doh_listen: Option<SocketAddr>,
+
+    /// Serve DNS-over-HTTPS as plain HTTP, for use behind a TLS-terminating proxy
+    #[arg(long, default_value_t = false)]
+    doh_no_tls: bool,
+
+    /// Proxy address or CIDR whose X-Forwarded-For header is trusted (repeatable)
+    #[arg(long, value_parser = parse_net)]
+    doh_trusted_proxy: Vec<IpNet>,
+
+    /// Maximum number of simultaneous DNS-over-HTTPS connections
+    #[arg(long, default_value_t = 512)]
+    doh_max_connections: usize,
+
+    /// Seconds a DNS-over-HTTPS connection may stay idle before it is closed
+    #[arg(long, default_value_t = 30)]
+    doh_idle_timeout: u64,
+
+    /// Client address or CIDR allowed to use DNS-over-HTTPS, instead of --allow (repeatable)
+    #[arg(long, value_parser = parse_net)]
+    doh_allow: Vec<IpNet>,
+
+    /// Client address or CIDR denied DNS-over-HTTPS, instead of --deny (repeatable)
+    #[arg(long, value_parser = parse_net)]
+    doh_deny: Vec<IpNet>,
+
+    /// UDP address to accept DNS-over-QUIC connections on, e.g. 0.0.0.0:853
+    #[arg(long)]
+    doq_listen: Option<SocketAddr>,
+
+    /// Maximum number of simultaneous DNS-over-QUIC connections
+    #[arg(long, default_value_t = 512)]
+    doq_max_connections: usize,
+
+    /// Seconds a DNS-over-QUIC connection may stay idle before it is closed
+    #[arg(long, default_value_t = 30)]
+    doq_idle_timeout: u64,
+
+    /// Client address or CIDR allowed to use DNS-over-QUIC, instead of --allow (repeatable)
+    #[arg(long, value_parser = parse_net)]
+    doq_allow: Vec<IpNet>,
+
+    /// Client address or CIDR denied DNS-over-QUIC, instead of --deny (repeatable)
+    #[arg(long, value_parser = parse_net)]
+    doq_deny: Vec<IpNet>,
+
+    /// Answer DNS-over-QUIC queries sent as 0-RTT data, which can be replayed
+    #[arg(long, default_value_t = false)]
+    doq_early_data: bool,
+
+    /// Send queries for the https:// resolver through this Oblivious DoH relay
+    #[arg(long)]
+    odoh_relay: Option<String>,
+
+    /// Answer Oblivious DoH queries on the DNS-over-HTTPS listener
+    #[arg(long, default_value_t = false)]
+    odoh_target: bool,
+
+    /// File holding the ODoH target's private key, created if missing
+    #[arg(long)]
+    odoh_key: Option<PathBuf>,
+
+    /// ODoH target host this server relays queries to on /proxy (repeatable)
+    #[arg(long)]
+    odoh_relay_target: Vec<String>,
+
+    /// Address to accept DNSCrypt queries on over UDP and TCP, e.g. 0.0.0.0:443
+    #[arg(long)]
+    dnscrypt_listen: Option<SocketAddr>,
+
+    /// Provider name DNSCrypt certificates are published under
+    #[arg(long, default_value = "2.dnscrypt-cert.rdns")]
+    dnscrypt_provider_name: String,
+
+    /// File holding the DNSCrypt provider's Ed25519 secret key, created if missing
+    #[arg(long)]
+    dnscrypt_key: Option<PathBuf>,
+
+    /// Maximum number of simultaneous DNSCrypt TCP connections
+    #[arg(long, default_value_t = 512)]
+    dnscrypt_max_connections: usize,
+
+    /// Seconds a DNSCrypt TCP connection may stay idle before it is closed
+    #[arg(long, default_value_t = 30)]
+    dnscrypt_idle_timeout: u64,
+
+    /// Client address or CIDR allowed to use DNSCrypt, instead of --allow (repeatable)
+    #[arg(long, value_parser = parse_net)]
+    dnscrypt_allow: Vec<IpNet>,
+
+    /// Client address or CIDR denied DNSCrypt, instead of --deny (repeatable)
+    #[arg(long, value_parser = parse_net)]
+    dnscrypt_deny: Vec<IpNet>,
 }
 
-async fn run_metrics_server() {
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Check the configuration file and flags for errors, then exit
+    CheckConfig {
+        /// Configuration file to check instead of the one given with --config
+        path: Option<PathBuf>,
+    },
+}
+
+/// Parses a CIDR prefix, or a single address as a host prefix.
+fn parse_net(s: &str) -> Result<IpNet, String> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("{} is not an IP address or CIDR prefix", s))
+}
+
+/// How long a forwarded query waits for its reply before the client is
+/// told SERVFAIL; shorter than [`transport::QUERY_TIMEOUT`] so that
+/// listeners waiting on a reply get the SERVFAIL rather than giving up.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(4);
+
+/// A forwarded query waiting for the upstream reply.
+struct PendingQuery {
+    client: SocketAddr,
+    responder: Responder,
+    client_id: u16,
+    started: Instant,
+    /// The question the client asked, to answer it if upstream can't.
+    question: Question,
+    /// The question the client asked, if a rewrite rule replaced it.
+    rewritten_from: Option<Question>,
+}
+
+type PendingMap = Arc<Mutex<HashMap<u16, PendingQuery>>>;
+
+/// Per-client filtering settings, swapped as a whole when the
+/// configuration is reloaded.
+struct Filters {
+    groups: ClientGroups,
+    rebind: Option<RebindProtection>,
+    access: AccessControl,
+}
+
+/// The rate limits set by the flags.
+fn rate_limits(args: &Args) -> RateLimits {
+    RateLimits {
+        queries_per_second: args.ratelimit,
+        burst: args.ratelimit_burst.unwrap_or(args.ratelimit.saturating_mul(2)),
+        responses_per_second: args.rrl_rate,
+        slip: args.rrl_slip,
+        ipv4_prefix: args.ratelimit_ipv4_prefix,
+        ipv6_prefix: args.ratelimit_ipv6_prefix,
+        exempt: args.ratelimit_exempt.clone(),
+    }
+}
+
+impl Filters {
+    fn new(args: &Args, groups: ClientGroups) -> Self {
+        let access = AccessControl::new(Acl::new(args.allow.clone(), args.deny.clone()), args.deny_action)
+            .with_listener(Transport::Tls, Acl::new(args.dot_allow.clone(), args.dot_deny.clone()))
+            .with_listener(Transport::Https, Acl::new(args.doh_allow.clone(), args.doh_deny.clone()))
+            .with_listener(Transport::Quic, Acl::new(args.doq_allow.clone(), args.doq_deny.clone()))
+            .with_listener(
+                Transport::DnsCrypt,
+                Acl::new(args.dnscrypt_allow.clone(), args.dnscrypt_deny.clone()),
+            );
+        let access = args.listen_access.iter().fold(access, |access, (listen, acl)| {
+            access.with_address(listen.socket_addr(args.port), acl.clone())
+        });
+        Self {
+            groups,
+            rebind: args
+                .rebind_protection
+                .then(|| RebindProtection::new(args.rebind_allow.clone())),
+            access,
+        }
+    }
+}
+
+type SharedFilters = Arc<RwLock<Arc<Filters>>>;
+
+/// Shared state handed to every request handler.
+#[derive(Clone)]
+struct Context {
+    upstream: Arc<Upstream>,
+    cache: Arc<DNSCache>,
+    pending: PendingMap,
+    transaction_id: Arc<AtomicU16>,
+    blocklist: Arc<DNSBlocklist>,
+    filters: SharedFilters,
+    zones: Arc<LocalZones>,
+    limiter: Arc<RateLimiter>,
+    /// Permits for queries being handled; none left means overloaded.
+    inflight: Arc<Semaphore>,
+    max_pending: usize,
+    log_tx: broadcast::Sender<String>,
+    /// Becomes true on shutdown, when connections stop taking new queries.
+    shutdown: watch::Receiver<bool>,
+}
+
+/// Longest pause the API accepts: a day.
+const MAX_PAUSE_SECONDS: u32 = 24 * 3600;
+
+/// Compares secrets without giving away how much of them matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn run_metrics_server(blocklist: Arc<DNSBlocklist>, reloader: Arc<Reloader>, addr: SocketAddr, api_token: Option<String>) {
     let metrics_route = warp::path("metrics").and(warp::get()).map(|| {
         use prometheus::Encoder;
         let encoder = prometheus::TextEncoder::new();
@@ -43,26 +522,94 @@ async fn run_metrics_server() {
         encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
         String::from_utf8(buffer).unwrap()
     });
-    warp::serve(metrics_route).run(([0, 0, 0, 0], 3030)).await;
-}
 
-async fn process_resolver_responses(
-    resolver_socket: Arc<UdpSocket>,
-    client_socket: Arc<UdpSocket>,
-    pending: Arc<Mutex<HashMap<u16, (SocketAddr, u16, Instant)>>>,
-    cache: Arc<DNSCache>,
-    log_tx: broadcast::Sender<String>,
-    resolver_addr: String,
-) {
-    let mut buf = [0; 512];
+    // The API changes what gets blocked, so it answers only requests with
+    // the token, or without one set, those from this machine.
+    let api = warp::addr::remote()
+        .and(warp::header::optional::<String>("authorization"))
+        .map(move |remote: Option<SocketAddr>, authorization: Option<String>| match &api_token {
+            Some(token) => authorization
+                .as_deref()
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes())),
+            None => remote.is_some_and(|addr| addr.ip().to_canonical().is_loopback()),
+        });
+    let forbidden = || warp::reply::with_status("forbidden\n".to_string(), warp::http::StatusCode::FORBIDDEN);
 
-    loop {
-        let (size, source) = resolver_socket.recv_from(&mut buf).await.unwrap();
-        if source.to_string() != resolver_addr {
-            continue;
-        }
+    // POST /api/blocking/pause?seconds=600 pauses blocking for everyone.
+    let pause_blocklist = blocklist.clone();
+    let pause_route = warp::path!("api" / "blocking" / "pause")
+        .and(warp::post())
+        .and(api.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .map(move |allowed: bool, params: HashMap<String, String>| {
+            if !allowed {
+                return forbidden();
+            }
+            let seconds = match params.get("seconds") {
+                Some(seconds) => seconds.parse::<u32>().ok().filter(|s| (1..=MAX_PAUSE_SECONDS).contains(s)),
+                None => Some(600),
+            };
+            let Some(until) = seconds.and_then(|s| Local::now().checked_add_signed(chrono::TimeDelta::seconds(s.into()))) else {
+                let message = format!("seconds must be between 1 and {}\n", MAX_PAUSE_SECONDS);
+                return warp::reply::with_status(message, warp::http::StatusCode::BAD_REQUEST);
+            };
+            pause_blocklist.pause_until(until);
+            warp::reply::with_status(format!("blocking paused until {}\n", until.format("%H:%M:%S")), warp::http::StatusCode::OK)
+        });
+
+    let resume_route = warp::path!("api" / "blocking" / "resume")
+        .and(warp::post())
+        .and(api.clone())
+        .map(move |allowed: bool| {
+            if !allowed {
+                return forbidden();
+            }
+            blocklist.resume();
+            warp::reply::with_status("blocking resumed\n".to_string(), warp::http::StatusCode::OK)
+        });
 
-        let mut packet = DNSPacket::from_bytes(&buf[0..size]);
+    // POST /api/reload does what SIGHUP does and reports whether it worked.
+    let reload_route = warp::path!("api" / "reload")
+        .and(warp::post())
+        .and(api)
+        .then(move |allowed: bool| {
+            let reloader = reloader.clone();
+            async move {
+                if !allowed {
+                    return forbidden();
+                }
+                match reloader.reload().await {
+                    Ok(()) => warp::reply::with_status("configuration reloaded\n".to_string(), warp::http::StatusCode::OK),
+                    Err(e) => warp::reply::with_status(format!("reload failed: {}\n", e), warp::http::StatusCode::UNPROCESSABLE_ENTITY),
+                }
+            }
+        });
+
+    warp::serve(metrics_route.or(pause_route).or(resume_route).or(reload_route))
+        .run(addr)
+        .await;
+}
+
+async fn process_resolver_responses(ctx: Context, mut replies: mpsc::Receiver<Vec<u8>>) {
+    let Context {
+        pending,
+        cache,
+        blocklist,
+        filters,
+        log_tx,
+        ..
+    } = ctx;
+
+    while let Some(reply) = replies.recv().await {
+        let mut packet = match DNSPacket::from_bytes(&reply) {
+            Ok(packet) => packet,
+            Err(e) => {
+                let timestamp = Local::now().format("%H:%M:%S");
+                let _ = log_tx.send(format!("[{}] Dropped malformed upstream reply: {}", timestamp, e));
+                continue;
+            }
+        };
 
         let original_packet_id = packet.header.packet_id;
         let pending_entry = {
@@ -70,25 +617,72 @@ async fn process_resolver_responses(
             pending_map.remove(&original_packet_id)
         };
 
-        if let Some((forwaridng_address, tid, start_time)) = pending_entry {
+        if let Some(PendingQuery {
+            client: forwaridng_address,
+            responder,
+            client_id: tid,
+            started: start_time,
+            rewritten_from,
+            ..
+        }) = pending_entry
+        {
             let latency = start_time.elapsed();
             metrics::RESPONSE_TIME.observe(latency.as_secs_f64());
             metrics::record_latency(latency.as_millis() as u64);
             packet.header.packet_id = tid;
-            client_socket
-                .send_to(&packet.to_bytes(), forwaridng_address)
-                .await
-                .unwrap();
+            let filters = filters.read().unwrap().clone();
 
-            if !packet.questions.is_empty() {
-                 // Clean up question string for display
-                 let q_name = packet.questions[0].to_string().replace("question=", "");
+            if let Some(rebind) = &filters.rebind
+                && !packet.questions.is_empty()
+            {
+                let stripped = rebind.strip(&packet.questions[0], &mut packet.answers);
+                if stripped > 0 {
+                    metrics::REBIND_STRIPPED.inc_by(stripped as f64);
+                    packet.update_counts();
+                    let timestamp = Local::now().format("%H:%M:%S");
+                    let _ = log_tx.send(format!("[{}] [{}] {} -> stripped {} private address(es)", timestamp, forwaridng_address, packet.questions[0].domain(), stripped));
+                }
+            }
+
+            // Cache the reply before the per-client answer checks below,
+            // which are repeated on every cache hit. A truncated reply is
+            // missing records, so it is passed on but never cached.
+            if !packet.questions.is_empty() && packet.header.tc == 0 {
+                cache.insert(packet.questions[0].clone(), packet.answers.clone());
+            }
+
+            if let Some(original) = rewritten_from
+                && !packet.questions.is_empty()
+            {
+                restore_rewritten(&mut packet, original);
+            }
+
+            let group = filters.groups.resolve(forwaridng_address.ip());
+            let answer_block = if packet.questions.is_empty() {
+                None
+            } else {
+                blocklist.check_answers(&packet.answers, &group.policy)
+            };
+            if let Some(reason) = &answer_block {
+                metrics::BLOCKED_REQUESTS.inc();
+                metrics::ANSWER_BLOCKS.with_label_values(&[reason.label()]).inc();
+                group.policy.response.apply(&mut packet);
+            }
+
+            if let Err(e) = responder.send(packet.to_bytes(), forwaridng_address).await {
                  let timestamp = Local::now().format("%H:%M:%S");
-                 let _ = log_tx.send(format!("[{}] [{}] {} -> FORWARDED ({}ms)", timestamp, forwaridng_address, q_name, start_time.elapsed().as_millis()));
+                 let _ = log_tx.send(format!("[{}] Failed to send response to {}: {}", timestamp, forwaridng_address, e));
+                 continue;
             }
 
             if !packet.questions.is_empty() {
-                cache.insert(packet.questions[0].clone(), packet.answers.clone());
+                 // Clean up question string for display
+                 let q_name = packet.questions[0].domain();
+                 let timestamp = Local::now().format("%H:%M:%S");
+                 let _ = match answer_block {
+                     Some(reason) => log_tx.send(format!("[{}] [{}] {} -> BLOCKED ({}, {})", timestamp, forwaridng_address, q_name, group.name, reason)),
+                     None => log_tx.send(format!("[{}] [{}] {} -> FORWARDED ({}ms)", timestamp, forwaridng_address, q_name, start_time.elapsed().as_millis())),
+                 };
             }
         } else {
              let timestamp = Local::now().format("%H:%M:%S");
@@ -97,57 +691,169 @@ async fn process_resolver_responses(
     }
 }
 
+/// Hands a query to the pipeline in a task of its own, holding one of the
+/// in-flight permits until it is answered or forwarded. With none left the
+/// query is shed instead, answered REFUSED at once so the client moves on
+/// to its next server rather than queueing without bound.
+async fn spawn_query(data: Vec<u8>, source: SocketAddr, responder: Responder, ctx: &Context) {
+    if let Some(permit) = admit(ctx) {
+        tokio::spawn(handle_dns_request(data, source, responder, ctx.clone(), permit));
+        return;
+    }
+
+    let access = ctx.filters.read().unwrap().access.clone();
+    let denied = access
+        .check(responder.transport(), responder.local_addr(), source.ip())
+        .is_some();
+    if denied && access.action == DenyAction::Drop {
+        return;
+    }
+    let Ok(mut packet) = DNSPacket::from_bytes(&data) else {
+        return;
+    };
+    if packet.questions.is_empty() {
+        return;
+    }
+    packet.make_response(RCODE_REFUSED, Vec::new());
+    let _ = responder.send(packet.to_bytes(), source).await;
+}
+
+/// Claims an in-flight permit for a query about to get a task of its own,
+/// counting the query as shed when none is left.
+fn admit(ctx: &Context) -> Option<OwnedSemaphorePermit> {
+    let permit = ctx.inflight.clone().try_acquire_owned().ok();
+    if permit.is_none() {
+        metrics::SHED_QUERIES.with_label_values(&["inflight"]).inc();
+    }
+    permit
+}
+
 async fn handle_dns_request(
     data: Vec<u8>,
-    client_socket: Arc<UdpSocket>,
     source: SocketAddr,
-    resolver_socket: Arc<UdpSocket>,
-    cache: Arc<DNSCache>,
-    pending: Arc<Mutex<HashMap<u16, (SocketAddr, u16, Instant)>>>,
-    transaction_id: Arc<AtomicU16>,
-    blocklist: Arc<DNSBlocklist>,
-    log_tx: broadcast::Sender<String>,
-    resolver_addr: String,
+    responder: Responder,
+    ctx: Context,
+    _permit: OwnedSemaphorePermit,
 ) {
+    let Context {
+        upstream,
+        cache,
+        pending,
+        transaction_id,
+        blocklist,
+        filters,
+        zones,
+        limiter,
+        max_pending,
+        log_tx,
+        ..
+    } = ctx;
     let start = Instant::now();
     let _timer = metrics::RESPONSE_TIME.start_timer();
-    let mut packet = DNSPacket::from_bytes(&data);
+    let transport = responder.transport();
+    metrics::QUERIES.with_label_values(&[transport.label()]).inc();
+    let filters = filters.read().unwrap().clone();
 
-    if packet.questions.len() == 0 {
+    // Clients that may not query learn nothing, not even about local names.
+    if let Some(reason) = filters.access.check(transport, responder.local_addr(), source.ip()) {
+        metrics::REFUSED_QUERIES.with_label_values(&[transport.label(), reason.label()]).inc();
+        if filters.access.action == DenyAction::Drop {
+            return;
+        }
+        let Ok(mut packet) = DNSPacket::from_bytes(&data) else {
+            return;
+        };
+        if packet.questions.is_empty() {
+            return;
+        }
+        packet.make_response(RCODE_REFUSED, Vec::new());
+        if let Err(e) = responder.send(packet.to_bytes(), source).await {
+            let timestamp = Local::now().format("%H:%M:%S");
+            let _ = log_tx.send(format!("[{}] Failed to send refused response: {}", timestamp, e));
+        } else {
+            let timestamp = Local::now().format("%H:%M:%S");
+            let _ = log_tx.send(format!("[{}] [{}] {} -> REFUSED ({})", timestamp, source, packet.questions[0].domain(), reason.label()));
+        }
         return;
     }
 
-    let q_name = packet.questions[0].to_string().replace("question=", "");
+    if let Some(limited) = limiter.check_query(source.ip()) {
+        metrics::RATE_LIMITED.with_label_values(&["query", "dropped"]).inc();
+        if limited.first {
+            let timestamp = Local::now().format("%H:%M:%S");
+            let _ = log_tx.send(format!("[{}] Rate limiting queries from {}", timestamp, limited.network));
+        }
+        return;
+    }
+
+    let Ok(mut packet) = DNSPacket::from_bytes(&data) else {
+        return;
+    };
+
+    if packet.questions.is_empty() {
+        return;
+    }
+
+    let q_name = packet.questions[0].domain();
 
     if packet.questions.len() > 1 {
         let timestamp = Local::now().format("%H:%M:%S");
         let _ = log_tx.send(format!("[{}] Received {} questions from {}, processing first", timestamp, packet.questions.len(), source));
     }
 
-    if blocklist.contains(&packet.questions[0]) {
+    // Local names are ours to answer, before any filtering or forwarding.
+    if let Some(local) = zones.lookup(&packet.questions[0]) {
+        metrics::LOCAL_ANSWERS.inc();
+        let latency = start.elapsed();
+        metrics::record_latency(latency.as_millis() as u64);
+
+        let rcode = local.rcode;
+        packet.make_response(rcode, local.answers);
+        packet.header.aa = 1;
+        packet.authorities = local.authorities;
+        packet.update_counts();
+
+        if let Err(e) = responder.send(packet.to_bytes(), source).await {
+            let timestamp = Local::now().format("%H:%M:%S");
+            let _ = log_tx.send(format!("[{}] Failed to send local response: {}", timestamp, e));
+        } else {
+            let timestamp = Local::now().format("%H:%M:%S");
+            let outcome = match rcode {
+                RCODE_NXDOMAIN => "NXDOMAIN",
+                _ if packet.answers.is_empty() => "NODATA",
+                _ => "ANSWER",
+            };
+            let _ = log_tx.send(format!("[{}] [{}] {} -> LOCAL {} ({}µs)", timestamp, source, q_name, outcome, latency.as_micros()));
+        }
+        return;
+    }
+
+    let group = filters.groups.resolve(source.ip());
+
+    if blocklist.is_blocked(&packet.questions[0], &group.policy) {
         metrics::BLOCKED_REQUESTS.inc();
         let latency = start.elapsed();
         metrics::record_latency(latency.as_millis() as u64);
 
-        packet.answers = vec![packet.questions[0].to_blocked_answer()];
-        packet.header.qr = 1;
-        packet.header.ra = 1;
-        packet.header.ancount = packet.answers.len() as u16;
-        packet.header.nscount = 0;
-        packet.header.arcount = 0;
-        packet.authorities = Vec::new();
-        packet.resources = Vec::new();
+        group.policy.response.apply(&mut packet);
 
-        if let Err(e) = client_socket.send_to(&packet.to_bytes(), source).await {
+        if let Err(e) = responder.send(packet.to_bytes(), source).await {
             let timestamp = Local::now().format("%H:%M:%S");
             let _ = log_tx.send(format!("[{}] Failed to send blocked response: {}", timestamp, e));
         } else {
             let timestamp = Local::now().format("%H:%M:%S");
-            let _ = log_tx.send(format!("[{}] [{}] {} -> BLOCKED", timestamp, source, q_name));
+            let _ = log_tx.send(format!("[{}] [{}] {} -> BLOCKED ({})", timestamp, source, q_name, group.name));
         }
         return;
     }
 
+    // Rewritten questions are resolved (and cached) under their target name.
+    let rewritten_from = group.policy.rewrites.target(&q_name).map(|target| {
+        let original = packet.questions[0].clone();
+        packet.questions[0].name = name_from_str(target);
+        original
+    });
+
     let cached_answers = cache.get(&packet.questions[0]);
 
     if let Some(answers) = cached_answers {
@@ -155,21 +861,34 @@ async fn handle_dns_request(
         let latency = start.elapsed();
         metrics::record_latency(latency.as_millis() as u64);
 
-        packet.answers = answers;
-        packet.header.qr = 1;
-        packet.header.ra = 1;
-        packet.header.ancount = packet.answers.len() as u16;
-        packet.header.nscount = 0;
-        packet.header.arcount = 0;
-        packet.authorities = Vec::new();
-        packet.resources = Vec::new();
+        let answer_block = blocklist.check_answers(&answers, &group.policy);
+        match &answer_block {
+            Some(reason) => {
+                metrics::BLOCKED_REQUESTS.inc();
+                metrics::ANSWER_BLOCKS.with_label_values(&[reason.label()]).inc();
+                // The block answers the question the client asked.
+                if let Some(original) = rewritten_from {
+                    packet.questions[0] = original;
+                }
+                group.policy.response.apply(&mut packet);
+            }
+            None => {
+                packet.make_response(RCODE_NOERROR, answers);
+                if let Some(original) = rewritten_from {
+                    restore_rewritten(&mut packet, original);
+                }
+            }
+        }
 
-        if let Err(e) = client_socket.send_to(&packet.to_bytes(), source).await {
+        if let Err(e) = responder.send(packet.to_bytes(), source).await {
              let timestamp = Local::now().format("%H:%M:%S");
              let _ = log_tx.send(format!("[{}] Failed to send cached response: {}", timestamp, e));
         } else {
              let timestamp = Local::now().format("%H:%M:%S");
-             let _ = log_tx.send(format!("[{}] [{}] {} -> CACHE HIT ({}µs)", timestamp, source, q_name, latency.as_micros()));
+             let _ = match answer_block {
+                 Some(reason) => log_tx.send(format!("[{}] [{}] {} -> BLOCKED ({}, {})", timestamp, source, q_name, group.name, reason)),
+                 None => log_tx.send(format!("[{}] [{}] {} -> CACHE HIT ({}µs)", timestamp, source, q_name, latency.as_micros())),
+             };
         }
         return;
     }
@@ -177,23 +896,99 @@ async fn handle_dns_request(
     metrics::CACHE_MISSES.inc();
 
     let original_id = packet.header.packet_id;
-    let new_id = transaction_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let question = rewritten_from.clone().unwrap_or_else(|| packet.questions[0].clone());
 
-    {
+    let new_id = {
         let mut pending_map = pending.lock().unwrap();
-        pending_map.insert(new_id, (source, original_id, Instant::now()));
-    }
+        if pending_map.len() >= max_pending {
+            None
+        } else {
+            // Skip IDs still waiting after the counter wrapped around; with
+            // fewer than 65536 pending there is always a free one.
+            let mut new_id = transaction_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            while pending_map.contains_key(&new_id) {
+                new_id = transaction_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+            pending_map.insert(
+                new_id,
+                PendingQuery {
+                    client: source,
+                    responder: responder.clone(),
+                    client_id: original_id,
+                    started: Instant::now(),
+                    question,
+                    rewritten_from: rewritten_from.clone(),
+                },
+            );
+            Some(new_id)
+        }
+    };
+
+    let Some(new_id) = new_id else {
+        metrics::SHED_QUERIES.with_label_values(&["upstream"]).inc();
+        if let Some(original) = rewritten_from {
+            packet.questions[0] = original;
+        }
+        packet.make_response(RCODE_SERVFAIL, Vec::new());
+        let _ = responder.send(packet.to_bytes(), source).await;
+        return;
+    };
 
     packet.header.packet_id = new_id;
 
-    if let Err(e) = resolver_socket
-        .send_to(&packet.to_bytes(), &resolver_addr)
-        .await
-    {
-         let _ = log_tx.send(format!("Failed to forward request: {}", e));
+    if let Err(e) = upstream.send(packet.to_bytes()).await {
+        let timestamp = Local::now().format("%H:%M:%S");
+        let _ = log_tx.send(format!("[{}] Failed to forward request: {}", timestamp, e));
+        // Tell the client now instead of when the query would expire.
+        let failed = pending.lock().unwrap().remove(&new_id);
+        if let Some(query) = failed {
+            let _ = query.responder.send(servfail(&query), query.client).await;
+        }
     }
 }
 
+/// A SERVFAIL reply to a forwarded query.
+fn servfail(query: &PendingQuery) -> Vec<u8> {
+    let mut packet = DNSPacket::query(query.client_id, query.question.clone());
+    packet.make_response(RCODE_SERVFAIL, Vec::new());
+    packet.to_bytes()
+}
+
+/// Answers SERVFAIL to forwarded queries upstream has not replied to in
+/// time, so lost replies neither leave clients waiting nor pile up.
+async fn expire_pending(pending: PendingMap, log_tx: broadcast::Sender<String>) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let expired: Vec<PendingQuery> = {
+            let mut pending_map = pending.lock().unwrap();
+            let ids: Vec<u16> = pending_map
+                .iter()
+                .filter(|(_, query)| query.started.elapsed() >= PENDING_TIMEOUT)
+                .map(|(&id, _)| id)
+                .collect();
+            ids.iter().filter_map(|id| pending_map.remove(id)).collect()
+        };
+        for query in expired {
+            metrics::UPSTREAM_TIMEOUTS.inc();
+            let timestamp = Local::now().format("%H:%M:%S");
+            let _ = match query.responder.send(servfail(&query), query.client).await {
+                Ok(()) => log_tx.send(format!("[{}] [{}] {} -> SERVFAIL (upstream timeout)", timestamp, query.client, query.question.domain())),
+                Err(e) => log_tx.send(format!("[{}] Failed to send timeout response: {}", timestamp, e)),
+            };
+        }
+    }
+}
+
+/// Puts the client's original question back into a reply resolved for its
+/// rewrite target, answering it with a CNAME to that target.
+fn restore_rewritten(packet: &mut DNSPacket, original: Question) {
+    let ttl = packet.answers.iter().map(|a| a.ttl).min().unwrap_or(300);
+    let cname = rewrite::cname_answer(&original, &packet.questions[0], ttl);
+    packet.questions[0] = original;
+    packet.answers.insert(0, cname);
+    packet.update_counts();
+}
+
 async fn cleanup_cache(cache: Arc<DNSCache>) {
     loop {
         tokio::time::sleep(Duration::from_secs(10)).await;
@@ -201,97 +996,491 @@ async fn cleanup_cache(cache: Arc<DNSCache>) {
     }
 }
 
+/// Reads the command line in `matches` and merges in the configuration
+/// file, whose settings apply wherever the matching flag was not given.
+fn load_args(matches: &ArgMatches) -> anyhow::Result<(Args, Option<ConfigFile>)> {
+    let mut args = Args::from_arg_matches(matches)?;
+    if let Some(Command::CheckConfig { path: Some(path) }) = &args.command {
+        args.config = Some(path.clone());
+    }
+    let config = match &args.config {
+        Some(path) => Some(ConfigFile::load(path)?),
+        None => None,
+    };
+    if let Some(config) = &config {
+        config.apply(&mut args, matches);
+    }
+    Ok((args, config))
+}
+
+/// Lists, client groups and local data built from the settings.
+struct Policy {
+    lists: Vec<(String, Vec<String>)>,
+    groups: ClientGroups,
+    zones: ZoneData,
+}
+
+fn load_policy(args: &Args, config: Option<&ConfigFile>) -> anyhow::Result<Policy> {
+    // Groups from --groups replace any defined in the configuration file.
+    let groups_file = match (&args.groups, config) {
+        (Some(path), _) => GroupsFile::load(path)?,
+        (None, Some(config)) => config.groups_file(),
+        (None, None) => GroupsFile::default(),
+    };
+    let mut lists = vec![(DEFAULT_GROUP.to_string(), args.blocklists.clone())];
+    lists.extend(groups_file.lists.clone());
+    let list_names: Vec<String> = lists.iter().map(|(name, _)| name.clone()).collect();
+
+    let default_policy = FilterPolicy {
+        blocklists: vec![DEFAULT_GROUP.to_string()],
+        allowlists: Vec::new(),
+        schedules: HashMap::new(),
+        rewrites: RewriteRules::from_sets(&args.rewrites, &groups_file.rewrites)?,
+        response: BlockResponse {
+            mode: args.block_mode,
+            sink_ipv4: args.sink_ipv4,
+            sink_ipv6: args.sink_ipv6,
+            ttl: args.block_ttl,
+            ede: args.block_ede,
+        },
+    };
+    let groups = ClientGroups::new(&groups_file, default_policy, &list_names).map_err(
+        |e| match config {
+            Some(config) if args.groups.is_none() => anyhow::Error::from(config.group_error(e)),
+            _ => e.into(),
+        },
+    )?;
+    if let Some(root) = &args.chroot {
+        check_chroot(root, &groups)?;
+    }
+    for domain in &args.local_domains {
+        check_name(domain).map_err(|e| anyhow::anyhow!("invalid --local-domain: {}", e))?;
+    }
+    dhcp::check_domain(&args.dhcp_domain).map_err(|e| anyhow::anyhow!("invalid --dhcp-domain: {}", e))?;
+    let mut local_domains = args.local_domains.clone();
+    if !args.dhcp_leases.is_empty() {
+        local_domains.push(args.dhcp_domain.clone());
+    }
+    let zones = ZoneData::load(&args.zones, &local_domains, &args.hosts_files)?;
+    Ok(Policy { lists, groups, zones })
+}
+
+/// Fails if `groups` need files after startup that `--chroot` would hide:
+/// the kernel's ARP table for MAC addresses, and the local time zone for
+/// schedules unless TZ names it.
+fn check_chroot(root: &Path, groups: &ClientGroups) -> anyhow::Result<()> {
+    let arp = privileges::chrooted_path(root, "/proc/net/arp");
+    if groups.uses_mac() && !arp.exists() {
+        anyhow::bail!("groups with MAC addresses need {} with --chroot; mount /proc inside it", arp.display());
+    }
+    let localtime = privileges::chrooted_path(root, "/etc/localtime");
+    if groups.uses_schedules() && std::env::var_os("TZ").is_none() && !localtime.exists() {
+        anyhow::bail!("schedules need {} with --chroot; copy /etc/localtime there or set TZ", localtime.display());
+    }
+    Ok(())
+}
+
+/// Certificates for the encrypted listeners that are enabled.
+struct ListenerTls {
+    dot: Option<Arc<ServerConfig>>,
+    doq: Option<Arc<ServerConfig>>,
+    doh: Option<Arc<ServerConfig>>,
+}
+
+fn load_listener_tls(args: &Args) -> anyhow::Result<ListenerTls> {
+    let server_tls = |flag: &str, alpn: &[&[u8]]| {
+        let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) else {
+            anyhow::bail!("{} needs --tls-cert and --tls-key", flag);
+        };
+        Ok(tls::server_config(cert, key, alpn)?)
+    };
+
+    if args.doh_listen.is_none() && (args.odoh_target || !args.odoh_relay_target.is_empty()) {
+        anyhow::bail!("--odoh-target and --odoh-relay-target need --doh-listen");
+    }
+    let doh = if args.doh_listen.is_some() && !args.doh_no_tls {
+        Some(server_tls("--doh-listen without --doh-no-tls", doh::ALPN_DOH)?)
+    } else {
+        None
+    };
+    Ok(ListenerTls {
+        dot: args
+            .dot_listen
+            .map(|_| server_tls("--dot-listen", &[dot::ALPN_DOT]))
+            .transpose()?,
+        doq: args
+            .doq_listen
+            .map(|_| server_tls("--doq-listen", &[doq::ALPN_DOQ]))
+            .transpose()?,
+        doh,
+    })
+}
+
+/// Builds everything the settings describe that can be checked without
+/// binding sockets or touching the network.
+fn check_config(args: &Args, config: Option<&ConfigFile>) -> anyhow::Result<()> {
+    load_policy(args, config)?;
+    load_listener_tls(args)?;
+    upstream::check_url(&args.resolver)?;
+    if let Some(relay) = &args.odoh_relay {
+        reqwest::Url::parse(relay).map_err(|e| anyhow::anyhow!("invalid --odoh-relay {}: {}", relay, e))?;
+    }
+    Ok(())
+}
+
 #[tokio::main]
-async fn main() -> io::Result<()> {
-    let args = Args::parse();
+async fn main() -> anyhow::Result<()> {
+    let matches = Args::command().get_matches();
+    let (args, config) = load_args(&matches)?;
+    if let Some(Command::CheckConfig { .. }) = args.command {
+        check_config(&args, config.as_ref())?;
+        match &args.config {
+            Some(path) => println!("{}: configuration OK", path.display()),
+            None => println!("configuration OK"),
+        }
+        return Ok(());
+    }
 
     // Channel for log messages
-    let (log_tx, _) = broadcast::channel(100);
+    let (log_tx, _) = broadcast::channel(args.log_buffer_size.max(1));
+    // Registered before anything slow so an early signal can't kill us.
+    let hangup = signal(SignalKind::hangup())?;
+    let terminate = signal(SignalKind::terminate())?;
+    let interrupt = signal(SignalKind::interrupt())?;
+    let (shutdown_tx, _) = watch::channel(false);
 
-    tokio::spawn(run_metrics_server());
+    let blocklist_cache_dir = (!args.no_blocklist_cache).then(|| args.blocklist_cache_dir.clone());
+    let Policy { lists, groups, zones } = load_policy(&args, config.as_ref())?;
+    let filters: SharedFilters = Arc::new(RwLock::new(Arc::new(Filters::new(&args, groups))));
+    let zones = Arc::new(LocalZones::new(zones));
 
-    let blocklist = Arc::new(DNSBlocklist::new());
+    let mut blocklist = DNSBlocklist::new(lists, blocklist_cache_dir);
+    // Load cached lists before binding so nothing slips through at boot.
+    let cache_results = blocklist.load_cache();
+    let blocklist = Arc::new(blocklist);
+    let cache = Arc::new(DNSCache::new(args.cache_min_ttl));
+    let limiter = Arc::new(RateLimiter::new(rate_limits(&args)));
+    let snapshot_result = args.cache_snapshot.clone().map(|path| {
+        let loaded = cache.load(&path);
+        (path, loaded)
+    });
 
-    let client_socket = UdpSocket::bind(format!("0.0.0.0:{}", args.port)).await?;
-    let client_socket_ref = Arc::new(client_socket);
+    let reloader = Arc::new(Reloader {
+        matches,
+        current: tokio::sync::Mutex::new(args.clone()),
+        blocklist: blocklist.clone(),
+        filters: filters.clone(),
+        zones: zones.clone(),
+        cache: cache.clone(),
+        limiter: limiter.clone(),
+        log_tx: log_tx.clone(),
+    });
+    tokio::spawn(run_metrics_server(blocklist.clone(), reloader.clone(), args.metrics_listen, args.api_token.clone()));
+
+    // Sockets passed by systemd enable their listener as if its address
+    // had been given. This copy of the settings is not the reloader's, so
+    // a reload doesn't see them as changed.
+    let mut activated = systemd::listen_fds()?;
+    let mut args = args;
+    if let Some(socket) = &activated.dot {
+        args.dot_listen = Some(systemd::socket_addr(socket)?);
+    }
+    if let Some(socket) = &activated.doh {
+        args.doh_listen = Some(systemd::socket_addr(socket)?);
+    }
+    if let Some(socket) = &activated.doq {
+        args.doq_listen = Some(systemd::socket_addr(socket)?);
+    }
+    if let Some(socket) = activated.dnscrypt_udp.as_ref().or(activated.dnscrypt_tcp.as_ref()) {
+        args.dnscrypt_listen = Some(systemd::socket_addr(socket)?);
+    }
+    let listener_tls = load_listener_tls(&args)?;
+
+    let mut udp_listeners = Vec::new();
+    let mut tcp_listeners = Vec::new();
+    for socket in std::mem::take(&mut activated.dns) {
+        udp_listeners.push(Arc::new(UdpListener::from_socket(socket)?));
+    }
+    for socket in std::mem::take(&mut activated.dns_tcp) {
+        let addr = systemd::socket_addr(&socket)?;
+        tcp_listeners.push(systemd::tcp_listener(Some(socket), addr).await?);
+    }
+    // Plain DNS is served over UDP and TCP on every address. IPv6 is only
+    // optional when it was not asked for explicitly.
+    let bind = |listen: &ListenAddr| -> std::io::Result<_> {
+        Ok((UdpListener::bind(listen, args.port)?, listen::bind_tcp(listen, args.port)?))
+    };
+    let mut startup_warnings = Vec::new();
+    if args.listen.is_empty() && udp_listeners.is_empty() && tcp_listeners.is_empty() {
+        for listen in ListenAddr::defaults() {
+            match bind(&listen) {
+                Ok((udp, tcp)) => {
+                    udp_listeners.push(Arc::new(udp));
+                    tcp_listeners.push(tcp);
+                }
+                Err(e) if listen.ip.is_ipv6() => startup_warnings.push(format!("Not listening on {}: {}", listen.socket_addr(args.port), e)),
+                Err(e) => anyhow::bail!("failed to listen on {}: {}", listen.socket_addr(args.port), e),
+            }
+        }
+    }
+    for listen in &args.listen {
+        let (udp, tcp) = bind(listen).map_err(|e| anyhow::anyhow!("failed to listen on {}: {}", listen, e))?;
+        udp_listeners.push(Arc::new(udp));
+        tcp_listeners.push(tcp);
+    }
+
+    let dot_listener = match (args.dot_listen, listener_tls.dot) {
+        (Some(addr), Some(tls)) => Some((systemd::tcp_listener(activated.dot.take(), addr).await?, tls)),
+        _ => None,
+    };
+
+    let doq_idle_timeout = Duration::from_secs(args.doq_idle_timeout.max(1));
+    let doq_endpoint = match (args.doq_listen, listener_tls.doq) {
+        (Some(addr), Some(tls)) => {
+            let config = doq::server_config(&tls, args.doq_early_data, doq_idle_timeout)?;
+            let socket = systemd::udp_socket(activated.doq.take(), addr)?;
+            Some(quinn::Endpoint::new(quinn::EndpointConfig::default(), Some(config), socket, Arc::new(quinn::TokioRuntime))?)
+        }
+        _ => None,
+    };
+
+    let dnscrypt_listener = match args.dnscrypt_listen {
+        Some(addr) => {
+            let provider = match &args.dnscrypt_key {
+                Some(path) => dnscrypt::load_or_create_provider_key(path)?,
+                None => dnscrypt::generate_provider_key(),
+            };
+            let provider_name = dnscrypt::provider_name(&args.dnscrypt_provider_name);
+            let certs = Arc::new(ResolverCerts::new(provider, &provider_name));
+            let socket = systemd::udp_socket(activated.dnscrypt_udp.take(), addr)?;
+            let socket = Arc::new(UdpSocket::from_std(socket)?);
+            let stamp = certs.stamp(socket.local_addr()?);
+            let listener = systemd::tcp_listener(activated.dnscrypt_tcp.take(), addr).await?;
+            Some((socket, listener, certs, stamp))
+        }
+        None => None,
+    };
+
+    let doh_listener = match args.doh_listen {
+        Some(addr) => Some((systemd::tcp_listener(activated.doh.take(), addr).await?, listener_tls.doh)),
+        None => None,
+    };
 
     let blocklist_updater = blocklist.clone();
+    let blocklist_log_tx = log_tx.clone();
+    let blocklist_interval = Duration::from_secs(args.blocklist_refresh.max(1));
     tokio::spawn(async move {
         tokio::time::sleep(Duration::from_millis(500)).await;
-        blocklist_updater.update().await;
+        blocklist::run_refresh(blocklist_updater, blocklist_interval, blocklist_log_tx).await;
     });
 
-    let resolver_socket = UdpSocket::bind("0.0.0.0:0").await?;
-    let resolver_socket_ref = Arc::new(resolver_socket);
+    let odoh_target = match (&args.odoh_key, args.odoh_target) {
+        (_, false) => None,
+        (Some(path), true) => Some(TargetKey::load_or_create(path)?),
+        (None, true) => Some(TargetKey::generate()),
+    };
+
+    // Everything that needs root is bound or read by now.
+    privileges::drop_privileges(args.user.as_deref(), args.group.as_deref(), args.chroot.as_deref())?;
+    if privileges::is_root() {
+        startup_warnings.push("Running as root; pass --user to drop privileges once bound".to_string());
+    }
+
+    let (replies_tx, replies_rx) = mpsc::channel(1024);
+    let upstream_options = UpstreamOptions {
+        bootstrap: args.bootstrap,
+        ca: args.upstream_ca.clone(),
+        pins: args.upstream_pin.clone(),
+        connections: args.upstream_connections,
+        odoh_relay: args.odoh_relay.clone(),
+    };
+    let upstream =
+        Upstream::connect(&args.resolver, &upstream_options, replies_tx, log_tx.clone()).await?;
+    let odoh_relay = if args.odoh_relay_target.is_empty() {
+        None
+    } else {
+        Some(Relay {
+            client: upstream::https_client(&upstream_options)?,
+            targets: args.odoh_relay_target.clone(),
+        })
+    };
 
-    let cache = Arc::new(DNSCache::new());
-    let pending: Arc<Mutex<HashMap<u16, (SocketAddr, u16, Instant)>>> =
-        Arc::new(Mutex::new(HashMap::new()));
+    let pending: PendingMap = Arc::new(Mutex::new(HashMap::new()));
     let transaction_id: Arc<AtomicU16> = Arc::new(AtomicU16::new(0));
 
-    let client_socket_clone = client_socket_ref.clone();
-    let resolver_socket_clone = resolver_socket_ref.clone();
-    let pending_clone = pending.clone();
-    let cache_clone = cache.clone();
-    let log_tx_clone = log_tx.clone();
-    let resolver_addr = args.resolver.clone();
-
-    tokio::spawn(process_resolver_responses(
-        resolver_socket_clone,
-        client_socket_clone,
-        pending_clone,
-        cache_clone,
-        log_tx_clone,
-        resolver_addr,
-    ));
+    let ctx = Context {
+        upstream: Arc::new(upstream),
+        cache: cache.clone(),
+        pending,
+        transaction_id,
+        blocklist: blocklist.clone(),
+        filters,
+        zones: zones.clone(),
+        limiter: limiter.clone(),
+        inflight: Arc::new(Semaphore::new(args.max_inflight.max(1))),
+        max_pending: args.upstream_max_pending as usize,
+        log_tx: log_tx.clone(),
+        shutdown: shutdown_tx.subscribe(),
+    };
+
+    tokio::spawn(process_resolver_responses(ctx.clone(), replies_rx));
+    tokio::spawn(expire_pending(ctx.pending.clone(), log_tx.clone()));
+
+    // Accept loops of the other listeners, stopped on shutdown.
+    let mut listeners = Vec::new();
+
+    if let Some((listener, tls)) = dot_listener {
+        let limits = ConnectionLimits {
+            max_connections: args.dot_max_connections,
+            idle_timeout: Duration::from_secs(args.dot_idle_timeout.max(1)),
+        };
+        listeners.push(tokio::spawn(dot::run_listener(listener, tls, limits, ctx.clone())));
+    }
+
+    if let Some((listener, tls)) = doh_listener {
+        let limits = ConnectionLimits {
+            max_connections: args.doh_max_connections,
+            idle_timeout: Duration::from_secs(args.doh_idle_timeout.max(1)),
+        };
+        let options = DohOptions {
+            trusted_proxies: args.doh_trusted_proxy.clone(),
+            odoh_target,
+            odoh_relay,
+        };
+        listeners.push(tokio::spawn(doh::run_listener(listener, tls, limits, Arc::new(options), ctx.clone())));
+    }
+
+    if let Some(endpoint) = doq_endpoint.clone() {
+        let limits = ConnectionLimits {
+            max_connections: args.doq_max_connections,
+            idle_timeout: doq_idle_timeout,
+        };
+        listeners.push(tokio::spawn(doq::run_listener(endpoint, limits, args.doq_early_data, ctx.clone())));
+    }
+
+    let mut dnscrypt_stamp = None;
+    if let Some((socket, listener, certs, stamp)) = dnscrypt_listener {
+        let limits = ConnectionLimits {
+            max_connections: args.dnscrypt_max_connections,
+            idle_timeout: Duration::from_secs(args.dnscrypt_idle_timeout.max(1)),
+        };
+        tokio::spawn(dnscrypt::run_rotation(certs.clone()));
+        listeners.push(tokio::spawn(dnscrypt::run_udp(socket.clone(), certs.clone(), ctx.clone())));
+        listeners.push(tokio::spawn(dnscrypt::run_tcp(listener, certs, limits, ctx.clone())));
+        dnscrypt_stamp = Some(stamp);
+    }
 
     let cache_cleanup = cache.clone();
     tokio::spawn(cleanup_cache(cache_cleanup));
+    tokio::spawn(ratelimit::run_cleanup(limiter));
 
-    // Spawn TUI only if not disabled
-    if !args.no_tui {
-        let tui_blocklist = blocklist.clone();
-        let tui_rx = log_tx.subscribe();
-        tokio::spawn(async move {
-            if let Err(e) = tui::run(tui_rx, tui_blocklist).await {
-                eprintln!("TUI error: {}", e);
-            }
-            // If TUI exits (user pressed 'q'), we might want to shutdown the whole app.
-            // For now, let's just exit the process.
-            std::process::exit(0);
-        });
+    // The log goes to the TUI unless it is disabled, and to stdout once
+    // the TUI has closed, so shutdown messages are still seen.
+    let (log_finished, log_done) = oneshot::channel();
+    let mut log_rx = log_tx.subscribe();
+    let tui = (!args.no_tui).then(|| (blocklist.clone(), shutdown_tx.clone()));
+    let log_writer = tokio::spawn(async move {
+        if let Some((tui_blocklist, tui_shutdown)) = tui
+            && let Err(e) = tui::run(&mut log_rx, tui_blocklist, tui_shutdown).await
+        {
+            eprintln!("TUI error: {}", e);
+        }
+        shutdown::write_log(log_rx, log_done).await;
+    });
+
+    // Started once the log has a reader so the first load is reported.
+    if !args.dhcp_leases.is_empty() {
+        tokio::spawn(dhcp::run_watcher(
+            args.dhcp_leases.clone(),
+            args.dhcp_domain.trim_matches('.').to_ascii_lowercase(),
+            zones.clone(),
+            log_tx.clone(),
+        ));
     }
 
-    let mut buf = [0; 512];
+    tokio::spawn(reload::run_on_hangup(hangup, reloader));
+    tokio::spawn(shutdown::run_on_signal(terminate, interrupt, shutdown_tx.clone(), log_tx.clone()));
 
-    loop {
-        let (size, source) = client_socket_ref.recv_from(&mut buf).await?;
+    if let Some(stamp) = dnscrypt_stamp {
+        let timestamp = Local::now().format("%H:%M:%S");
+        let _ = log_tx.send(format!("[{}] DNSCrypt stamp: {}", timestamp, stamp));
+    }
+
+    for (url, result) in cache_results {
+        let timestamp = Local::now().format("%H:%M:%S");
+        let _ = match result {
+            Ok(count) => log_tx.send(format!("[{}] Blocklist {} loaded from cache ({} domains)", timestamp, url, count)),
+            Err(e) => log_tx.send(format!("[{}] Blocklist {} has no usable cache: {}", timestamp, url, e)),
+        };
+    }
 
-        if source.to_string() == args.resolver {
-            continue;
+    match snapshot_result {
+        Some((path, Ok(count))) => {
+            let timestamp = Local::now().format("%H:%M:%S");
+            let _ = log_tx.send(format!("[{}] Cache loaded from {} ({} entries)", timestamp, path.display(), count));
+        }
+        Some((_, Err(e))) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Some((path, Err(e))) => {
+            let timestamp = Local::now().format("%H:%M:%S");
+            let _ = log_tx.send(format!("[{}] Failed to load cache from {}: {}", timestamp, path.display(), e));
         }
+        None => {}
+    }
 
-        let data = buf[0..size].to_vec();
-        let client_socket_clone = client_socket_ref.clone();
-        let resolver_socket_clone = resolver_socket_ref.clone();
-        let pending_clone = pending.clone();
-        let cache_clone = cache.clone();
-        let transaction_id_clone = transaction_id.clone();
-        let blocklist_clone = blocklist.clone();
-        let log_tx_clone = log_tx.clone();
-        let resolver_addr = args.resolver.clone();
-
-        tokio::spawn(handle_dns_request(
-            data,
-            client_socket_clone,
-            source,
-            resolver_socket_clone,
-            cache_clone,
-            pending_clone,
-            transaction_id_clone,
-            blocklist_clone,
-            log_tx_clone,
-            resolver_addr,
-        ));
+    if let Err(e) = systemd::notify("READY=1") {
+        let timestamp = Local::now().format("%H:%M:%S");
+        let _ = log_tx.send(format!("[{}] Failed to notify systemd: {}", timestamp, e));
+    }
+
+    for warning in startup_warnings {
+        let timestamp = Local::now().format("%H:%M:%S");
+        let _ = log_tx.send(format!("[{}] {}", timestamp, warning));
+    }
+
+    let buffer_size = args.udp_buffer_size as usize;
+    let resolver_addr = args.resolver.parse().ok();
+    for listener in udp_listeners {
+        listeners.push(tokio::spawn(listen::run_udp(listener, buffer_size, resolver_addr, ctx.clone())));
+    }
+    for listener in tcp_listeners {
+        let limits = ConnectionLimits {
+            max_connections: args.tcp_max_connections,
+            idle_timeout: Duration::from_secs(args.tcp_idle_timeout.max(1)),
+        };
+        listeners.push(tokio::spawn(listen::run_tcp(listener, limits, ctx.clone())));
+    }
+
+    let _ = shutdown_tx.subscribe().wait_for(|stop| *stop).await;
+
+    let _ = systemd::notify("STOPPING=1");
+    for listener in listeners {
+        listener.abort();
+    }
+    if let Some(endpoint) = &doq_endpoint {
+        endpoint.set_server_config(None);
     }
-}
\ No newline at end of file
+
+    let timeout = Duration::from_secs(args.shutdown_timeout);
+    let abandoned = shutdown::drain(&ctx.pending, timeout).await;
+    if abandoned > 0 {
+        let timestamp = Local::now().format("%H:%M:%S");
+        let _ = log_tx.send(format!("[{}] Gave up on {} queries still waiting for upstream", timestamp, abandoned));
+    }
+    if let Some(endpoint) = &doq_endpoint {
+        doq::close(endpoint).await;
+    }
+
+    if let Some(path) = &args.cache_snapshot {
+        let timestamp = Local::now().format("%H:%M:%S");
+        let _ = match cache.save(path) {
+            Ok(count) => log_tx.send(format!("[{}] Cache saved to {} ({} entries)", timestamp, path.display(), count)),
+            Err(e) => log_tx.send(format!("[{}] Failed to save cache to {}: {}", timestamp, path.display(), e)),
+        };
+    }
+
+    let timestamp = Local::now().format("%H:%M:%S");
+    let _ = log_tx.send(format!("[{}] Stopped", timestamp));
+    let _ = log_finished.send(());
+    let _ = log_writer.await;
+    Ok(())
+}
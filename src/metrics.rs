@@ -1,17 +1,89 @@
 use lazy_static::lazy_static;
-use prometheus::{Counter, Histogram, register_counter, register_histogram};
+use prometheus::{
+    Counter, CounterVec, Gauge, GaugeVec, Histogram, register_counter, register_counter_vec,
+    register_gauge, register_gauge_vec, register_histogram,
+};
 use std::sync::Mutex;
 use std::collections::VecDeque;
 
 lazy_static! {
+    pub static ref QUERIES: CounterVec = register_counter_vec!(
+        "dns_queries",
+        "Number of queries received by transport",
+        &["transport"]
+    )
+    .unwrap();
+    pub static ref OPEN_CONNECTIONS: GaugeVec = register_gauge_vec!(
+        "dns_open_connections",
+        "Number of open client connections by transport",
+        &["transport"]
+    )
+    .unwrap();
+    pub static ref CONNECTION_ERRORS: CounterVec = register_counter_vec!(
+        "dns_connection_errors",
+        "Number of client connections refused or dropped, by transport and reason",
+        &["transport", "reason"]
+    )
+    .unwrap();
+    pub static ref REFUSED_QUERIES: CounterVec = register_counter_vec!(
+        "dns_refused_queries",
+        "Number of queries refused or dropped unanswered, by transport and reason",
+        &["transport", "reason"]
+    )
+    .unwrap();
+    pub static ref RATE_LIMITED: CounterVec = register_counter_vec!(
+        "dns_rate_limited",
+        "Number of queries dropped or answered truncated by rate limiting, by limit and action",
+        &["limit", "action"]
+    )
+    .unwrap();
+    pub static ref SHED_QUERIES: CounterVec = register_counter_vec!(
+        "dns_shed_queries",
+        "Number of queries turned away because too many were in flight or waiting for upstream",
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_TIMEOUTS: Counter = register_counter!(
+        "dns_upstream_timeouts",
+        "Number of forwarded queries answered with SERVFAIL after upstream never replied"
+    )
+    .unwrap();
     pub static ref CACHE_HITS: Counter =
         register_counter!("dns_cache_hits", "Number of cache hits").unwrap();
     pub static ref CACHE_MISSES: Counter =
         register_counter!("dns_cache_misses", "Number of cache misses").unwrap();
     pub static ref RESPONSE_TIME: Histogram =
         register_histogram!("dns_response_time_seconds", "Response time in seconds").unwrap();
+    pub static ref LOCAL_ANSWERS: Counter =
+        register_counter!("dns_local_answers", "Number of queries answered from local zones").unwrap();
+    pub static ref DHCP_LEASES: Gauge =
+        register_gauge!("dns_dhcp_leases", "Number of hosts registered from DHCP leases").unwrap();
     pub static ref BLOCKED_REQUESTS: Counter =
         register_counter!("dns_blocked_requests", "Number of blocked DNS requests").unwrap();
+    pub static ref ANSWER_BLOCKS: CounterVec = register_counter_vec!(
+        "dns_answer_blocks",
+        "Number of replies blocked because of their answers rather than the question",
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref REBIND_STRIPPED: Counter = register_counter!(
+        "dns_rebind_stripped",
+        "Number of private addresses removed from public answers by rebinding protection"
+    )
+    .unwrap();
+    pub static ref BLOCKLIST_UPDATES: CounterVec = register_counter_vec!(
+        "dns_blocklist_updates",
+        "Number of blocklist source refreshes by outcome",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref BLOCKLIST_DOMAINS: Gauge =
+        register_gauge!("dns_blocklist_domains", "Number of domains in the active blocklist").unwrap();
+    pub static ref BLOCKLIST_LAST_SUCCESS: Gauge = register_gauge!(
+        "dns_blocklist_last_success_timestamp_seconds",
+        "Unix time of the last refresh in which every blocklist source succeeded"
+    )
+    .unwrap();
     pub static ref RECENT_LATENCIES: Mutex<VecDeque<u64>> = Mutex::new(VecDeque::with_capacity(100));
 }
 
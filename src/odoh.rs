@@ -0,0 +1,461 @@
+//! Oblivious DNS over HTTPS (RFC 9230): queries are encrypted to the
+//! target's public key and sent through a relay, so the relay sees who is
+//! asking but not what, and the target sees what is asked but not by whom.
+
+use std::{io, path::Path};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bytes::Bytes;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use thiserror::Error;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::hpke::{
+    self, AEAD_AES_128_GCM, HpkeError, KDF_HKDF_SHA256, KEM_X25519_HKDF_SHA256, NH, NK, NN,
+};
+
+pub const ODOH_MESSAGE: &str = "application/oblivious-dns-message";
+
+const ODOH_VERSION: u16 = 0x0001;
+const MESSAGE_QUERY: u8 = 0x01;
+const MESSAGE_RESPONSE: u8 = 0x02;
+/// Plaintext DNS messages are padded to a multiple of this many bytes so
+/// their length says less about the name being looked up.
+const PADDING_BLOCK: usize = 128;
+
+#[derive(Debug, Error)]
+pub enum OdohError {
+    #[error("malformed oblivious message")]
+    Malformed,
+    #[error("query encrypted to an unknown key")]
+    UnknownKey,
+    #[error("no supported ODoH configuration offered")]
+    NoSupportedConfig,
+    #[error(transparent)]
+    Hpke(#[from] HpkeError),
+    #[error("failed to access ODoH key {path}: {source}")]
+    KeyFile { path: String, source: io::Error },
+    #[error("{0} does not hold a base64 X25519 private key")]
+    InvalidKeyFile(String),
+}
+
+/// The target's key pair and the configuration advertising it.
+pub struct TargetKey {
+    secret: StaticSecret,
+    contents: Vec<u8>,
+    key_id: Vec<u8>,
+}
+
+impl TargetKey {
+    pub fn generate() -> Self {
+        Self::from_secret(hpke::generate_key().0)
+    }
+
+    /// Loads the private key stored at `path`, creating the file with a new
+    /// key if it does not exist yet, so clients' cached configs stay valid
+    /// across restarts.
+    pub fn load_or_create(path: &Path) -> Result<Self, OdohError> {
+        let key_error = |source| OdohError::KeyFile {
+            path: path.display().to_string(),
+            source,
+        };
+        match std::fs::read_to_string(path) {
+            Ok(body) => {
+                let bytes: [u8; 32] = STANDARD
+                    .decode(body.trim())
+                    .ok()
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .ok_or_else(|| OdohError::InvalidKeyFile(path.display().to_string()))?;
+                Ok(Self::from_secret(StaticSecret::from(bytes)))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let key = Self::generate();
+                let encoded = format!("{}\n", STANDARD.encode(key.secret.as_bytes()));
+                write_private(path, encoded.as_bytes()).map_err(key_error)?;
+                Ok(key)
+            }
+            Err(e) => Err(key_error(e)),
+        }
+    }
+
+    fn from_secret(secret: StaticSecret) -> Self {
+        let contents = config_contents(&PublicKey::from(&secret));
+        let key_id = key_id(&contents);
+        TargetKey {
+            secret,
+            contents,
+            key_id,
+        }
+    }
+
+    /// The `ObliviousDoHConfigs` structure served at
+    /// `/.well-known/odohconfigs`.
+    pub fn configs(&self) -> Vec<u8> {
+        let mut config = Vec::with_capacity(self.contents.len() + 4);
+        config.extend_from_slice(&ODOH_VERSION.to_be_bytes());
+        config.extend_from_slice(&(self.contents.len() as u16).to_be_bytes());
+        config.extend_from_slice(&self.contents);
+
+        let mut configs = (config.len() as u16).to_be_bytes().to_vec();
+        configs.extend_from_slice(&config);
+        configs
+    }
+
+    /// Decrypts an oblivious query, returning the DNS message inside and
+    /// the key its response must be encrypted with.
+    pub fn decrypt_query(&self, body: &[u8]) -> Result<(Vec<u8>, ResponseKey), OdohError> {
+        let (message_type, key_id, encrypted) = decode_message(body).ok_or(OdohError::Malformed)?;
+        if message_type != MESSAGE_QUERY {
+            return Err(OdohError::Malformed);
+        }
+        if key_id != self.key_id.as_slice() {
+            return Err(OdohError::UnknownKey);
+        }
+        if encrypted.len() < hpke::NENC {
+            return Err(OdohError::Malformed);
+        }
+
+        let (enc, ciphertext) = encrypted.split_at(hpke::NENC);
+        let mut context = hpke::setup_receiver(enc, &self.secret, b"odoh query")?;
+        let plaintext = context.open(&query_aad(key_id), ciphertext)?;
+        let dns = unpad(&plaintext).ok_or(OdohError::Malformed)?.to_vec();
+        let key = ResponseKey {
+            secret: context.export(b"odoh response", NK),
+            query_plaintext: plaintext,
+        };
+        Ok((dns, key))
+    }
+}
+
+/// A target's public configuration, as fetched by a client.
+pub struct TargetConfig {
+    public_key: PublicKey,
+    key_id: Vec<u8>,
+}
+
+impl TargetConfig {
+    /// Picks the first configuration in `ObliviousDoHConfigs` that uses
+    /// the suite we implement, skipping versions and suites we don't know.
+    pub fn parse(configs: &[u8]) -> Result<Self, OdohError> {
+        let (list, _) = split_vector(configs).ok_or(OdohError::Malformed)?;
+        let mut rest = list;
+        while rest.len() >= 4 {
+            let version = u16::from_be_bytes([rest[0], rest[1]]);
+            let (contents, tail) = split_vector(&rest[2..]).ok_or(OdohError::Malformed)?;
+            rest = tail;
+            if version != ODOH_VERSION || contents.len() < 8 {
+                continue;
+            }
+
+            let field = |i: usize| u16::from_be_bytes([contents[i], contents[i + 1]]);
+            let suite = (field(0), field(2), field(4));
+            if suite != (KEM_X25519_HKDF_SHA256, KDF_HKDF_SHA256, AEAD_AES_128_GCM) {
+                continue;
+            }
+            let (public_key, _) = split_vector(&contents[6..]).ok_or(OdohError::Malformed)?;
+            let Ok(public_key) = <[u8; 32]>::try_from(public_key) else {
+                continue;
+            };
+            return Ok(TargetConfig {
+                public_key: PublicKey::from(public_key),
+                key_id: key_id(contents),
+            });
+        }
+        Err(OdohError::NoSupportedConfig)
+    }
+
+    /// Encrypts a DNS query for the target, returning the oblivious message
+    /// and the key that will open its response.
+    pub fn encrypt_query(&self, dns: &[u8]) -> (Vec<u8>, ResponseKey) {
+        let plaintext = pad(dns);
+        let (enc, mut context) = hpke::setup_sender(&self.public_key, b"odoh query");
+        let ciphertext = context.seal(&query_aad(&self.key_id), &plaintext);
+        let encrypted = [&enc[..], &ciphertext].concat();
+        let key = ResponseKey {
+            secret: context.export(b"odoh response", NK),
+            query_plaintext: plaintext,
+        };
+        (encode_message(MESSAGE_QUERY, &self.key_id, &encrypted), key)
+    }
+}
+
+/// Secret shared by both ends of a query, protecting its response
+/// (RFC 9230 section 6.4).
+pub struct ResponseKey {
+    secret: Vec<u8>,
+    query_plaintext: Vec<u8>,
+}
+
+impl ResponseKey {
+    pub fn encrypt_response(&self, dns: &[u8]) -> Vec<u8> {
+        let nonce: [u8; NK] = hpke::random_bytes();
+        let (key, aead_nonce) = self.derive(&nonce);
+        let ciphertext = hpke::seal(&key, &aead_nonce, &response_aad(&nonce), &pad(dns));
+        encode_message(MESSAGE_RESPONSE, &nonce, &ciphertext)
+    }
+
+    pub fn decrypt_response(&self, body: &[u8]) -> Result<Vec<u8>, OdohError> {
+        let (message_type, nonce, ciphertext) = decode_message(body).ok_or(OdohError::Malformed)?;
+        if message_type != MESSAGE_RESPONSE {
+            return Err(OdohError::Malformed);
+        }
+        let (key, aead_nonce) = self.derive(nonce);
+        let plaintext = hpke::open(&key, &aead_nonce, &response_aad(nonce), ciphertext)?;
+        Ok(unpad(&plaintext).ok_or(OdohError::Malformed)?.to_vec())
+    }
+
+    fn derive(&self, nonce: &[u8]) -> ([u8; NK], [u8; NN]) {
+        let salt = [
+            &self.query_plaintext[..],
+            &(nonce.len() as u16).to_be_bytes(),
+            nonce,
+        ]
+        .concat();
+        let prk = Hkdf::<Sha256>::new(Some(&salt), &self.secret);
+        let mut key = [0u8; NK];
+        let mut aead_nonce = [0u8; NN];
+        prk.expand(b"odoh key", &mut key)
+            .expect("HKDF output within bounds");
+        prk.expand(b"odoh nonce", &mut aead_nonce)
+            .expect("HKDF output within bounds");
+        (key, aead_nonce)
+    }
+}
+
+/// Forwards oblivious messages to the targets it has been told to serve,
+/// without passing on anything about the client.
+pub struct Relay {
+    pub client: reqwest::Client,
+    pub targets: Vec<String>,
+}
+
+impl Relay {
+    /// Whether `target_host` is one we relay to and `target_path` is usable.
+    pub fn allows(&self, target_host: &str, target_path: &str) -> bool {
+        target_path.starts_with('/')
+            && self
+                .targets
+                .iter()
+                .any(|target| target.eq_ignore_ascii_case(target_host))
+    }
+
+    /// Sends `body` to `https://<target_host><target_path>`, returning the
+    /// target's status and body.
+    pub async fn forward(
+        &self,
+        target_host: &str,
+        target_path: &str,
+        body: Bytes,
+    ) -> Result<(u16, Bytes), reqwest::Error> {
+        let response = self
+            .client
+            .post(format!("https://{}{}", target_host, target_path))
+            .header("content-type", ODOH_MESSAGE)
+            .header("accept", ODOH_MESSAGE)
+            .body(body)
+            .send()
+            .await?;
+        let status = response.status().as_u16();
+        Ok((status, response.bytes().await?))
+    }
+}
+
+/// `ObliviousDoHConfigContents` for our one suite and `public_key`.
+fn config_contents(public_key: &PublicKey) -> Vec<u8> {
+    let mut contents = Vec::with_capacity(40);
+    contents.extend_from_slice(&KEM_X25519_HKDF_SHA256.to_be_bytes());
+    contents.extend_from_slice(&KDF_HKDF_SHA256.to_be_bytes());
+    contents.extend_from_slice(&AEAD_AES_128_GCM.to_be_bytes());
+    contents.extend_from_slice(&(public_key.as_bytes().len() as u16).to_be_bytes());
+    contents.extend_from_slice(public_key.as_bytes());
+    contents
+}
+
+/// Identifies a configuration: `Expand(Extract("", contents), "odoh key id", Nh)`.
+fn key_id(contents: &[u8]) -> Vec<u8> {
+    let mut id = vec![0; NH];
+    Hkdf::<Sha256>::new(None, contents)
+        .expand(b"odoh key id", &mut id)
+        .expect("HKDF output within bounds");
+    id
+}
+
+fn query_aad(key_id: &[u8]) -> Vec<u8> {
+    [
+        &[MESSAGE_QUERY][..],
+        &(key_id.len() as u16).to_be_bytes(),
+        key_id,
+    ]
+    .concat()
+}
+
+fn response_aad(nonce: &[u8]) -> Vec<u8> {
+    [
+        &[MESSAGE_RESPONSE][..],
+        &(nonce.len() as u16).to_be_bytes(),
+        nonce,
+    ]
+    .concat()
+}
+
+/// `ObliviousDoHMessage`: a type, a key ID (or response nonce) and the
+/// encrypted message, the latter two with 16-bit length prefixes.
+fn encode_message(message_type: u8, key_id: &[u8], encrypted: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(5 + key_id.len() + encrypted.len());
+    message.push(message_type);
+    message.extend_from_slice(&(key_id.len() as u16).to_be_bytes());
+    message.extend_from_slice(key_id);
+    message.extend_from_slice(&(encrypted.len() as u16).to_be_bytes());
+    message.extend_from_slice(encrypted);
+    message
+}
+
+fn decode_message(body: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&message_type, rest) = body.split_first()?;
+    let (key_id, rest) = split_vector(rest)?;
+    let (encrypted, rest) = split_vector(rest)?;
+    rest.is_empty().then_some((message_type, key_id, encrypted))
+}
+
+/// `ObliviousDoHMessagePlaintext`: the DNS message and zero padding, both
+/// length-prefixed.
+fn pad(dns: &[u8]) -> Vec<u8> {
+    let padding = (PADDING_BLOCK - dns.len() % PADDING_BLOCK) % PADDING_BLOCK;
+    let mut plaintext = Vec::with_capacity(dns.len() + padding + 4);
+    plaintext.extend_from_slice(&(dns.len() as u16).to_be_bytes());
+    plaintext.extend_from_slice(dns);
+    plaintext.extend_from_slice(&(padding as u16).to_be_bytes());
+    plaintext.resize(plaintext.len() + padding, 0);
+    plaintext
+}
+
+fn unpad(plaintext: &[u8]) -> Option<&[u8]> {
+    let (dns, rest) = split_vector(plaintext)?;
+    let (padding, rest) = split_vector(rest)?;
+    (rest.is_empty() && padding.iter().all(|&b| b == 0)).then_some(dns)
+}
+
+/// Splits a vector with a 16-bit length prefix off the front of `data`.
+fn split_vector(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = u16::from_be_bytes([*data.first()?, *data.get(1)?]) as usize;
+    let rest = &data[2..];
+    (rest.len() >= len).then(|| rest.split_at(len))
+}
+
+/// Writes a file only its owner can read.
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY: &[u8] =
+        b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07example\x03com\x00\x00\x01\x00\x01";
+
+    fn client_of(target: &TargetKey) -> TargetConfig {
+        TargetConfig::parse(&target.configs()).unwrap()
+    }
+
+    #[test]
+    fn query_and_response_round_trip() {
+        let target = TargetKey::generate();
+        let (message, client_key) = client_of(&target).encrypt_query(QUERY);
+
+        let (query, target_key) = target.decrypt_query(&message).unwrap();
+        assert_eq!(query, QUERY);
+
+        let response = target_key.encrypt_response(b"response");
+        assert_eq!(client_key.decrypt_response(&response).unwrap(), b"response");
+    }
+
+    #[test]
+    fn messages_are_padded() {
+        let target = TargetKey::generate();
+        let (short, _) = client_of(&target).encrypt_query(&QUERY[..12]);
+        let (long, _) = client_of(&target).encrypt_query(QUERY);
+        assert_eq!(short.len(), long.len());
+    }
+
+    #[test]
+    fn tampered_query_is_rejected() {
+        let target = TargetKey::generate();
+        let (mut message, _) = client_of(&target).encrypt_query(QUERY);
+        let last = message.len() - 1;
+        message[last] ^= 1;
+        assert!(matches!(
+            target.decrypt_query(&message),
+            Err(OdohError::Hpke(HpkeError::Open))
+        ));
+    }
+
+    #[test]
+    fn tampered_response_is_rejected() {
+        let target = TargetKey::generate();
+        let (message, client_key) = client_of(&target).encrypt_query(QUERY);
+        let (_, target_key) = target.decrypt_query(&message).unwrap();
+
+        let mut response = target_key.encrypt_response(b"response");
+        let last = response.len() - 1;
+        response[last] ^= 1;
+        assert!(client_key.decrypt_response(&response).is_err());
+    }
+
+    #[test]
+    fn query_for_another_target_is_rejected() {
+        let target = TargetKey::generate();
+        let other = TargetKey::generate();
+        let (message, _) = client_of(&other).encrypt_query(QUERY);
+        assert!(matches!(
+            target.decrypt_query(&message),
+            Err(OdohError::UnknownKey)
+        ));
+    }
+
+    #[test]
+    fn response_to_another_query_is_rejected() {
+        let target = TargetKey::generate();
+        let (first, _) = client_of(&target).encrypt_query(QUERY);
+        let (_, other_key) = client_of(&target).encrypt_query(QUERY);
+        let (_, target_key) = target.decrypt_query(&first).unwrap();
+
+        let response = target_key.encrypt_response(b"response");
+        assert!(other_key.decrypt_response(&response).is_err());
+    }
+
+    #[test]
+    fn parse_skips_unknown_configs() {
+        let target = TargetKey::generate();
+        let ours = target.configs();
+        // A config of a future version ahead of ours.
+        let unknown = [0x00, 0x02, 0x00, 0x02, 0xab, 0xcd];
+        let list = [&unknown[..], &ours[2..]].concat();
+        let configs = [&(list.len() as u16).to_be_bytes()[..], &list].concat();
+
+        let (message, _) = TargetConfig::parse(&configs).unwrap().encrypt_query(QUERY);
+        assert!(target.decrypt_query(&message).is_ok());
+        assert!(matches!(
+            TargetConfig::parse(&[0x00, 0x06, 0x00, 0x02, 0x00, 0x02, 0xab, 0xcd]),
+            Err(OdohError::NoSupportedConfig)
+        ));
+    }
+
+    #[test]
+    fn unpad_rejects_nonzero_padding() {
+        let mut plaintext = pad(QUERY);
+        assert_eq!(unpad(&plaintext), Some(QUERY));
+        let last = plaintext.len() - 1;
+        plaintext[last] = 1;
+        assert_eq!(unpad(&plaintext), None);
+    }
+}
@@ -1,4 +1,108 @@
 use std::fmt::Display;
+use std::net::IpAddr;
+
+use thiserror::Error;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_NS: u16 = 2;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_MX: u16 = 15;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_OPT: u16 = 41;
+pub const TYPE_ANY: u16 = 255;
+
+pub const CLASS_IN: u16 = 1;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_REFUSED: u8 = 5;
+
+/// EDNS option code for Extended DNS Errors (RFC 8914).
+pub const EDNS_OPTION_EDE: u16 = 15;
+/// Extended DNS Error info-code signalling a policy block.
+pub const EDE_BLOCKED: u16 = 15;
+
+/// Longest name allowed on the wire, length octets included (RFC 1035).
+pub const MAX_NAME_LENGTH: usize = 255;
+/// Longest label allowed on the wire (RFC 1035).
+pub const MAX_LABEL_LENGTH: usize = 63;
+/// Largest reply sent over UDP to a client without EDNS (RFC 1035).
+pub const MIN_UDP_PAYLOAD: usize = 512;
+/// Most compression pointers followed while reading one name; a name has
+/// at most this many labels, so a longer chain must be a loop.
+const MAX_POINTERS: usize = 127;
+
+/// Why a message could not be parsed.
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("message truncated")]
+    Truncated,
+    #[error("name longer than {MAX_NAME_LENGTH} bytes")]
+    NameTooLong,
+    #[error("compression pointer loop")]
+    PointerLoop,
+    #[error("unsupported label type {0:#04x}")]
+    LabelType(u8),
+}
+
+/// Converts an uncompressed wire-format name into its dotted form.
+pub fn name_to_string(name: &[u8]) -> String {
+    let mut n = 0;
+    let mut res: Vec<String> = Vec::new();
+    while n < name.len() {
+        let length = name[n] as usize;
+        if length == 0 {
+            break;
+        }
+        let label = &name[n + 1..n + 1 + length];
+        res.push(String::from_utf8_lossy(label).into_owned());
+        n = n + 1 + length;
+    }
+    res.join(".")
+}
+
+/// Checks that a dotted name fits in wire format, which
+/// [`name_from_str`] assumes.
+pub fn check_name(name: &str) -> Result<(), String> {
+    let mut length = 1;
+    for label in name.trim_end_matches('.').split('.') {
+        if label.len() > MAX_LABEL_LENGTH {
+            return Err(format!(
+                "label {} is longer than {} bytes",
+                label, MAX_LABEL_LENGTH
+            ));
+        }
+        if !label.is_empty() {
+            length += 1 + label.len();
+        }
+    }
+    if length > MAX_NAME_LENGTH {
+        return Err(format!(
+            "name {} is longer than {} bytes",
+            name, MAX_NAME_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+/// Converts a dotted name into uncompressed wire format.
+pub fn name_from_str(name: &str) -> Vec<u8> {
+    let mut res: Vec<u8> = Vec::new();
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() {
+            continue;
+        }
+        res.push(label.len() as u8);
+        res.extend_from_slice(label.as_bytes());
+    }
+    res.push(0);
+    res
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct Header {
@@ -19,13 +123,13 @@ pub struct Header {
 
 impl Header {
     pub fn new(buf: &[u8]) -> Self {
-        return Self {
+        Self {
             packet_id: u16::from_be_bytes([buf[0], buf[1]]),
             qr: (buf[2] >> 7 & 0x01),
             opcode: buf[2] >> 3 & 0b00001111,
             aa: (buf[2] >> 2 & 0x01),
             tc: (buf[2] >> 1 & 0x01),
-            rd: (buf[2] >> 0 & 0x01),
+            rd: (buf[2] & 0x01),
             ra: (buf[3] >> 7 & 0x01),
             z: buf[3] >> 4 & 0b00000111,
             rcode: buf[3] & 0b00001111,
@@ -33,10 +137,10 @@ impl Header {
             ancount: u16::from_be_bytes([buf[6], buf[7]]),
             nscount: u16::from_be_bytes([buf[8], buf[9]]),
             arcount: u16::from_be_bytes([buf[10], buf[11]]),
-        };
+        }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
+    pub fn to_bytes(self) -> Vec<u8> {
         let mut result: Vec<u8> = Vec::new();
         result.extend_from_slice(&self.packet_id.to_be_bytes());
         result.extend_from_slice(
@@ -66,33 +170,15 @@ impl Question {
         res
     }
 
-    pub fn to_blocked_answer(&self) -> Answer {
-        Answer {
-            name: self.name.clone(),
-            tp: self.tp,
-            class: self.class,
-            ttl: 300,
-            length: 4,
-            data: vec![0x00, 0x00, 0x00, 0x00],
-        }
+    /// The queried name in dotted form, without the trailing dot.
+    pub fn domain(&self) -> String {
+        name_to_string(&self.name)
     }
 }
 
 impl Display for Question {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut n = 0;
-        let mut res: Vec<String> = Vec::new();
-        while n < self.name.len() {
-            let length = self.name[n] as usize;
-            if length == 0 {
-                break;
-            }
-            let label = &self.name[n + 1..n + 1 + length];
-            res.push(String::from_utf8_lossy(&label).into_owned());
-            n = n + 1 + length;
-        }
-
-        write!(f, "question={}", res.join("."))
+        write!(f, "question={}", self.domain())
     }
 }
 
@@ -107,6 +193,61 @@ pub struct Answer {
 }
 
 impl Answer {
+    pub fn new(name: Vec<u8>, tp: u16, ttl: u32, data: Vec<u8>) -> Self {
+        Self {
+            name,
+            tp,
+            class: CLASS_IN,
+            ttl,
+            length: data.len() as u16,
+            data,
+        }
+    }
+
+    /// The address carried by an A or AAAA record.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match (self.tp, self.data.len()) {
+            (TYPE_A, 4) => Some(IpAddr::from(<[u8; 4]>::try_from(&self.data[..]).ok()?)),
+            (TYPE_AAAA, 16) => {
+                Some(IpAddr::from(<[u8; 16]>::try_from(&self.data[..]).ok()?).to_canonical())
+            }
+            _ => None,
+        }
+    }
+
+    /// Builds a synthetic SOA record owned by `name` whose MINIMUM field
+    /// tells resolvers how long to cache the negative answer.
+    pub fn soa(name: &[u8], ttl: u32) -> Self {
+        let mut data = name_from_str("rdns");
+        data.extend_from_slice(&name_from_str("hostmaster.rdns"));
+        for field in [1u32, 3600, 600, 86400, ttl] {
+            data.extend_from_slice(&field.to_be_bytes());
+        }
+        Self::new(name.to_vec(), TYPE_SOA, ttl, data)
+    }
+
+    /// Builds an EDNS OPT pseudo-record advertising `payload_size` and
+    /// carrying the already-encoded `options`.
+    pub fn opt(payload_size: u16, options: Vec<u8>) -> Self {
+        Self {
+            name: vec![0],
+            tp: TYPE_OPT,
+            class: payload_size,
+            ttl: 0,
+            length: options.len() as u16,
+            data: options,
+        }
+    }
+
+    /// Encodes an Extended DNS Error option for use in an OPT record.
+    pub fn ede_option(info_code: u16, text: &str) -> Vec<u8> {
+        let mut option = EDNS_OPTION_EDE.to_be_bytes().to_vec();
+        option.extend_from_slice(&(2 + text.len() as u16).to_be_bytes());
+        option.extend_from_slice(&info_code.to_be_bytes());
+        option.extend_from_slice(text.as_bytes());
+        option
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut result = self.name.clone();
         result.extend_from_slice(&self.tp.to_be_bytes());
@@ -114,7 +255,7 @@ impl Answer {
         result.extend_from_slice(&self.ttl.to_be_bytes());
         result.extend_from_slice(&self.length.to_be_bytes());
         result.extend_from_slice(&self.data);
-        return result;
+        result
     }
 }
 
@@ -146,19 +287,49 @@ pub struct DNSPacket {
 
 #[allow(unused)]
 impl DNSPacket {
-    pub fn from_bytes(buf: &[u8]) -> Self {
+    /// Parses a message, rejecting one whose sections run past its end or
+    /// whose names are malformed.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ParseError> {
+        if buf.len() < 12 {
+            return Err(ParseError::Truncated);
+        }
         let header = Header::new(&buf[0..12]);
-        let (questions, offset) = DNSPacket::parse_questions(&buf, 12, header.qdcount);
-        let (answers, offset) = DNSPacket::parse_answers(&buf, offset, header.ancount);
-        let (authorities, offset) = DNSPacket::parse_answers(&buf, offset, header.nscount);
-        let (resources, _) = DNSPacket::parse_answers(&buf, offset, header.arcount);
-        return Self {
+        let (questions, offset) = DNSPacket::parse_questions(buf, 12, header.qdcount)?;
+        let (answers, offset) = DNSPacket::parse_answers(buf, offset, header.ancount)?;
+        let (authorities, offset) = DNSPacket::parse_answers(buf, offset, header.nscount)?;
+        let (resources, _) = DNSPacket::parse_answers(buf, offset, header.arcount)?;
+        Ok(Self {
             header,
             questions,
             answers,
             authorities,
             resources,
-        };
+        })
+    }
+
+    /// Builds a recursive query asking a single question.
+    pub fn query(packet_id: u16, question: Question) -> Self {
+        Self {
+            header: Header {
+                packet_id,
+                qr: 0,
+                opcode: 0,
+                aa: 0,
+                tc: 0,
+                rd: 1,
+                ra: 0,
+                z: 0,
+                rcode: 0,
+                qdcount: 1,
+                ancount: 0,
+                nscount: 0,
+                arcount: 0,
+            },
+            questions: vec![question],
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            resources: Vec::new(),
+        }
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -175,82 +346,133 @@ impl DNSPacket {
         for a in self.resources.iter() {
             result.extend_from_slice(&a.to_bytes());
         }
-        return result;
+        result
+    }
+
+    /// The client's EDNS OPT record, if the query carried one.
+    pub fn edns(&self) -> Option<&Answer> {
+        self.resources.iter().find(|r| r.tp == TYPE_OPT)
+    }
+
+    /// The largest reply the client can take over UDP: the payload size
+    /// its OPT record advertises (RFC 6891), but never less than 512 bytes.
+    pub fn udp_payload_size(&self) -> usize {
+        self.edns()
+            .map_or(0, |opt| opt.class as usize)
+            .max(MIN_UDP_PAYLOAD)
+    }
+
+    /// Turns a query into a response with the given code and answers,
+    /// dropping any authority or additional records the client sent.
+    pub fn make_response(&mut self, rcode: u8, answers: Vec<Answer>) {
+        self.header.qr = 1;
+        self.header.ra = 1;
+        self.header.rcode = rcode;
+        self.answers = answers;
+        self.authorities = Vec::new();
+        self.resources = Vec::new();
+        self.update_counts();
+    }
+
+    /// Syncs the header section counts with the record vectors.
+    pub fn update_counts(&mut self) {
+        self.header.qdcount = self.questions.len() as u16;
+        self.header.ancount = self.answers.len() as u16;
+        self.header.nscount = self.authorities.len() as u16;
+        self.header.arcount = self.resources.len() as u16;
     }
 
     pub fn as_forwards(&self) -> Vec<DNSPacket> {
         let mut res: Vec<DNSPacket> = Vec::new();
         for q in self.questions.iter() {
-            let mut header = self.header.clone();
+            let mut header = self.header;
             header.qdcount = 1;
-            let mut questions: Vec<Question> = Vec::new();
-            questions.push(q.clone());
             res.push(DNSPacket {
                 header,
-                questions,
+                questions: vec![q.clone()],
                 answers: Vec::new(),
                 authorities: Vec::new(),
                 resources: Vec::new(),
             });
         }
-        return res;
+        res
     }
 
-    fn qname(buf: &[u8], start: usize) -> (Vec<u8>, usize) {
+    /// Reads the possibly compressed name at `start`, returning it
+    /// uncompressed along with the number of bytes it takes up there.
+    fn qname(buf: &[u8], start: usize) -> Result<(Vec<u8>, usize), ParseError> {
         let mut name: Vec<u8> = Vec::new();
         let mut n = start;
+        // Where the name ends at `start`, once a pointer has been followed.
+        let mut consumed = None;
+        let mut pointers = 0;
         loop {
-            let b = buf[n];
-            if (b & 0b11000000) == 0b11000000 {
-                let new_start = (((b as u16) & 0x3f) << 8) | (buf[n + 1] as u16);
-                let (common, _) = Self::qname(&buf, new_start as usize);
-                name.extend_from_slice(&common);
-                // +2 because we also read the offset and the pointer;
-                return (name, n + 2 - start);
+            let b = *buf.get(n).ok_or(ParseError::Truncated)?;
+            match b & 0b11000000 {
+                0b11000000 => {
+                    let low = *buf.get(n + 1).ok_or(ParseError::Truncated)?;
+                    pointers += 1;
+                    if pointers > MAX_POINTERS {
+                        return Err(ParseError::PointerLoop);
+                    }
+                    // +2 because we also read the offset and the pointer.
+                    consumed.get_or_insert_with(|| n + 2 - start);
+                    n = ((((b as u16) & 0x3f) << 8) | (low as u16)) as usize;
+                    continue;
+                }
+                0 => {}
+                _ => return Err(ParseError::LabelType(b)),
             }
 
-            if b == 0 {
-                name.push(b);
-                return (name, n + 1 - start);
+            if name.len() + 1 + b as usize > MAX_NAME_LENGTH {
+                return Err(ParseError::NameTooLong);
             }
-
             name.push(b);
+            if b == 0 {
+                return Ok((name, consumed.unwrap_or_else(|| n + 1 - start)));
+            }
             let len = b as usize;
-            name.extend_from_slice(&buf[n + 1..n + 1 + len]);
+            name.extend_from_slice(slice(buf, n + 1, len)?);
             n = n + 1 + len;
         }
     }
 
-    fn parse_questions(buf: &[u8], mut offset: usize, count: u16) -> (Vec<Question>, usize) {
+    fn parse_questions(
+        buf: &[u8],
+        mut offset: usize,
+        count: u16,
+    ) -> Result<(Vec<Question>, usize), ParseError> {
         let mut questions: Vec<Question> = Vec::new();
         for _ in 0..count {
-            let (name, advance) = Self::qname(buf, offset);
+            let (name, advance) = Self::qname(buf, offset)?;
             offset += advance;
+            let fixed = slice(buf, offset, 4)?;
             questions.push(Question {
                 name,
-                tp: u16::from_be_bytes([buf[offset], buf[offset + 1]]),
-                class: u16::from_be_bytes([buf[offset + 2], buf[offset + 3]]),
+                tp: u16::from_be_bytes([fixed[0], fixed[1]]),
+                class: u16::from_be_bytes([fixed[2], fixed[3]]),
             });
             offset += 4;
         }
-        (questions, offset)
+        Ok((questions, offset))
     }
 
-    fn parse_answers(buf: &[u8], mut offset: usize, count: u16) -> (Vec<Answer>, usize) {
+    fn parse_answers(
+        buf: &[u8],
+        mut offset: usize,
+        count: u16,
+    ) -> Result<(Vec<Answer>, usize), ParseError> {
         let mut answers: Vec<Answer> = Vec::new();
         for _ in 0..count {
-            let (name, advance) = Self::qname(buf, offset);
+            let (name, advance) = Self::qname(buf, offset)?;
             offset += advance;
-            let tp = u16::from_be_bytes([buf[offset], buf[offset + 1]]);
-            let class = u16::from_be_bytes([buf[offset + 2], buf[offset + 3]]);
-            let ttl = u32::from_be_bytes([
-                buf[offset + 4],
-                buf[offset + 5],
-                buf[offset + 6],
-                buf[offset + 7],
-            ]);
-            let length = u16::from_be_bytes([buf[offset + 8], buf[offset + 9]]);
+            let fixed = slice(buf, offset, 10)?;
+            let tp = u16::from_be_bytes([fixed[0], fixed[1]]);
+            let class = u16::from_be_bytes([fixed[2], fixed[3]]);
+            let ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+            let length = u16::from_be_bytes([fixed[8], fixed[9]]);
             offset += 10;
+            let rdata = slice(buf, offset, length as usize)?;
 
             // Record types that contain domain names which may use compression:
             // CNAME (5), NS (2), PTR (12), MX (15), SOA (6)
@@ -258,17 +480,17 @@ impl DNSPacket {
             let data = match tp {
                 2 | 5 | 12 => {
                     // NS, CNAME, PTR: data is a single domain name
-                    let (decompressed_name, _) = Self::qname(buf, offset);
+                    let (decompressed_name, _) = Self::qname(buf, offset)?;
                     decompressed_name
                 }
                 15 => {
                     // MX: 2-byte preference + domain name
-                    let mut mx_data = buf[offset..offset + 2].to_vec();
-                    let (decompressed_name, _) = Self::qname(buf, offset + 2);
+                    let mut mx_data = slice(rdata, 0, 2)?.to_vec();
+                    let (decompressed_name, _) = Self::qname(buf, offset + 2)?;
                     mx_data.extend_from_slice(&decompressed_name);
                     mx_data
                 }
-                _ => buf[offset..offset + length as usize].to_vec(),
+                _ => rdata.to_vec(),
             };
 
             offset += length as usize;
@@ -282,6 +504,116 @@ impl DNSPacket {
                 data,
             });
         }
-        (answers, offset)
+        Ok((answers, offset))
+    }
+}
+
+/// The `len` bytes of `buf` at `start`, if the message is that long.
+fn slice(buf: &[u8], start: usize, len: usize) -> Result<&[u8], ParseError> {
+    buf.get(start..start + len).ok_or(ParseError::Truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str) -> Vec<u8> {
+        let question = Question {
+            name: name_from_str(name),
+            tp: TYPE_A,
+            class: CLASS_IN,
+        };
+        DNSPacket::query(0x1234, question).to_bytes()
+    }
+
+    #[test]
+    fn parses_compressed_answers() {
+        let mut buf = query("example.com");
+        buf[7] = 1; // ancount
+        // example.com via a pointer to the question, 1.2.3.4
+        buf.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 1, 2, 3, 4]);
+        let packet = DNSPacket::from_bytes(&buf).unwrap();
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(packet.answers[0].name, name_from_str("example.com"));
+        assert_eq!(packet.answers[0].ip(), Some(IpAddr::from([1, 2, 3, 4])));
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let buf = query("example.com");
+        for len in 0..buf.len() {
+            assert!(
+                DNSPacket::from_bytes(&buf[..len]).is_err(),
+                "length {}",
+                len
+            );
+        }
+
+        let mut buf = buf;
+        buf[7] = 1;
+        buf.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 1, 2]);
+        assert!(matches!(
+            DNSPacket::from_bytes(&buf),
+            Err(ParseError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn rejects_pointer_loops() {
+        let mut buf = query("example.com");
+        buf[7] = 1;
+        let here = buf.len() as u8;
+        buf.extend_from_slice(&[0xc0, here, 0, 1, 0, 1, 0, 0, 0, 60, 0, 0]);
+        assert!(matches!(
+            DNSPacket::from_bytes(&buf),
+            Err(ParseError::PointerLoop)
+        ));
+    }
+
+    #[test]
+    fn reads_udp_payload_size() {
+        let mut packet = DNSPacket::from_bytes(&query("example.com")).unwrap();
+        assert_eq!(packet.udp_payload_size(), MIN_UDP_PAYLOAD);
+        packet.resources.push(Answer::opt(256, Vec::new()));
+        assert_eq!(packet.udp_payload_size(), MIN_UDP_PAYLOAD);
+        packet.resources[0] = Answer::opt(1232, Vec::new());
+        assert_eq!(packet.udp_payload_size(), 1232);
+    }
+
+    #[test]
+    fn checks_name_lengths() {
+        let label = "a".repeat(MAX_LABEL_LENGTH);
+        assert!(check_name(&format!("{}.example", label)).is_ok());
+        assert!(check_name(&format!("a{}.example", label)).is_err());
+
+        // Three 63-byte labels and one of 61 make 3 * 64 + 62 + 1 = 255
+        // bytes, the most there can be.
+        let long = format!("{}.{}", [label.as_str(); 3].join("."), "a".repeat(61));
+        assert!(check_name(&long).is_ok());
+        assert!(check_name(&format!("{}.a", long)).is_err());
+        assert!(check_name(&format!("{}.", long)).is_ok());
+    }
+
+    #[test]
+    fn rejects_overlong_names() {
+        let mut buf = query("example.com");
+        buf[7] = 2; // ancount
+        // A TXT record whose data hides labels that each point back at the
+        // one before, so a name pointing at the last grows past 255 bytes.
+        buf.extend_from_slice(&[0xc0, 12, 0, 16, 0, 1, 0, 0, 0, 60, 1, 59]);
+        let mut previous = 0xc000 | 12u16;
+        for _ in 0..5 {
+            let here = 0xc000 | buf.len() as u16;
+            buf.push(60);
+            buf.extend_from_slice(&[b'a'; 60]);
+            buf.extend_from_slice(&previous.to_be_bytes());
+            previous = here;
+        }
+        buf.extend_from_slice(&previous.to_be_bytes());
+        buf.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 60, 0, 0]);
+        assert!(matches!(
+            DNSPacket::from_bytes(&buf),
+            Err(ParseError::NameTooLong)
+        ));
     }
 }
@@ -0,0 +1,107 @@
+//! Giving up root once the listening sockets are bound: an optional
+//! chroot, then switching to an unprivileged user and group.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use nix::errno::Errno;
+use nix::unistd::{self, Gid, Group, Uid, User};
+use thiserror::Error;
+
+/// Set once the chroot has been entered.
+static CHROOTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Error)]
+pub enum PrivilegeError {
+    #[error("unknown user {0}")]
+    UnknownUser(String),
+    #[error("unknown group {0}")]
+    UnknownGroup(String),
+    #[error("failed to look up {name}: {source}")]
+    Lookup { name: String, source: Errno },
+    #[error("failed to chroot to {path}: {source}")]
+    Chroot { path: PathBuf, source: Errno },
+    #[error("failed to switch to {what}: {source}")]
+    Switch { what: String, source: Errno },
+    #[error("still able to regain root after switching user")]
+    Regained,
+}
+
+/// Chroots to `root` if given, then switches to `user` and `group`. The
+/// group defaults to the user's primary group. Names are looked up before
+/// the chroot, which usually hides `/etc/passwd`.
+pub fn drop_privileges(
+    user: Option<&str>,
+    group: Option<&str>,
+    root: Option<&Path>,
+) -> Result<(), PrivilegeError> {
+    let user = user
+        .map(|name| {
+            User::from_name(name)
+                .map_err(|source| PrivilegeError::Lookup {
+                    name: name.to_string(),
+                    source,
+                })?
+                .ok_or_else(|| PrivilegeError::UnknownUser(name.to_string()))
+        })
+        .transpose()?;
+    let gid = match (group, &user) {
+        (Some(name), _) => Some(
+            Group::from_name(name)
+                .map_err(|source| PrivilegeError::Lookup {
+                    name: name.to_string(),
+                    source,
+                })?
+                .ok_or_else(|| PrivilegeError::UnknownGroup(name.to_string()))?
+                .gid,
+        ),
+        (None, Some(user)) => Some(user.gid),
+        (None, None) => None,
+    };
+
+    if let Some(root) = root {
+        let chroot_error = |source| PrivilegeError::Chroot {
+            path: root.to_path_buf(),
+            source,
+        };
+        unistd::chroot(root).map_err(chroot_error)?;
+        unistd::chdir("/").map_err(chroot_error)?;
+        CHROOTED.store(true, Ordering::Relaxed);
+    }
+
+    // Groups first: once the user is switched we may no longer change them.
+    if let Some(gid) = gid {
+        let switch_error = |source| PrivilegeError::Switch {
+            what: format!("group {}", gid),
+            source,
+        };
+        unistd::setgroups(&[gid]).map_err(switch_error)?;
+        unistd::setgid(gid).map_err(switch_error)?;
+    }
+    if let Some(user) = user {
+        unistd::setuid(user.uid).map_err(|source| PrivilegeError::Switch {
+            what: format!("user {}", user.name),
+            source,
+        })?;
+        if !user.uid.is_root() && unistd::setuid(Uid::from_raw(0)).is_ok() {
+            return Err(PrivilegeError::Regained);
+        }
+    }
+    Ok(())
+}
+
+/// Where the absolute `path` will be found once chrooted to `root`: under
+/// `root` before the chroot, and where it says after.
+pub fn chrooted_path(root: &Path, path: &str) -> PathBuf {
+    let relative = path.trim_start_matches('/');
+    if CHROOTED.load(Ordering::Relaxed) {
+        Path::new("/").join(relative)
+    } else {
+        root.join(relative)
+    }
+}
+
+/// Whether the process currently runs as root.
+pub fn is_root() -> bool {
+    unistd::geteuid().is_root() || unistd::getegid() == Gid::from_raw(0)
+}
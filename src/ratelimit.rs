@@ -0,0 +1,357 @@
+//! Per-client query rate limits and response rate limiting (RRL). Clients
+//! are grouped by network prefix, each with a token bucket for its
+//! queries. Identical UDP responses to one network have buckets of their
+//! own, and once those run dry every `slip`th response is sent truncated
+//! so real clients retry over TCP while spoofed ones get nothing useful.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use ipnet::IpNet;
+
+use crate::packet::{DNSPacket, RCODE_NOERROR, RCODE_NXDOMAIN, TYPE_SOA};
+
+/// Buckets idle for this long are forgotten.
+const IDLE: Duration = Duration::from_secs(60);
+
+/// Most buckets of each kind kept; past it, new keys share one overflow
+/// bucket rather than evicting ones that are being limited.
+const MAX_TRACKED: usize = 100_000;
+
+/// Limits in force, replaced on reload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimits {
+    /// Queries per second per client network, or 0 for no limit.
+    pub queries_per_second: u32,
+    /// Queries a client network may send at once after being quiet.
+    pub burst: u32,
+    /// Identical responses per second per client network, or 0 for no RRL.
+    pub responses_per_second: u32,
+    /// Send every `slip`th rate-limited response truncated; 0 never does.
+    pub slip: u32,
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    /// Networks that are never limited.
+    pub exempt: Vec<IpNet>,
+}
+
+/// What to do with a response to a client over UDP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseVerdict {
+    Send,
+    /// Send it truncated and without records instead.
+    Slip,
+    Drop,
+}
+
+/// A client network whose query was over its limit.
+pub struct Limited {
+    pub network: IpNet,
+    /// Whether this is the first query limited since the client was last
+    /// within its limit for a while.
+    pub first: bool,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+    /// Queries or responses refused since the bucket was last full.
+    limited: u32,
+}
+
+impl TokenBucket {
+    fn new(burst: f64, now: Instant) -> Self {
+        Self {
+            tokens: burst,
+            updated: now,
+            limited: 0,
+        }
+    }
+
+    fn take(&mut self, rate: f64, burst: f64, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+        // A full bucket means the client has calmed down.
+        if self.tokens >= burst {
+            self.limited = 0;
+        }
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            self.limited = self.limited.saturating_add(1);
+            false
+        }
+    }
+}
+
+/// Identical responses: the same answer to the same question for one
+/// client network. Like BIND's RRL, NXDOMAIN and NODATA answers are keyed
+/// by the zone they come from, so random names under one domain share a
+/// bucket.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ResponseKey {
+    network: IpNet,
+    name: Vec<u8>,
+    qtype: u16,
+    rcode: u8,
+}
+
+/// Token buckets by key, bounded to `MAX_TRACKED`.
+struct Buckets<K> {
+    tracked: HashMap<K, TokenBucket>,
+    /// Shared by every key that arrives while `tracked` is full.
+    overflow: Option<TokenBucket>,
+}
+
+impl<K: Eq + std::hash::Hash> Buckets<K> {
+    fn new() -> Self {
+        Self {
+            tracked: HashMap::new(),
+            overflow: None,
+        }
+    }
+
+    /// The bucket for `key`, created full if new.
+    fn get(&mut self, key: K, burst: f64, now: Instant) -> &mut TokenBucket {
+        if self.tracked.len() >= MAX_TRACKED && !self.tracked.contains_key(&key) {
+            return self
+                .overflow
+                .get_or_insert_with(|| TokenBucket::new(burst, now));
+        }
+        self.tracked
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(burst, now))
+    }
+
+    fn retain_active(&mut self, now: Instant) {
+        let active = |bucket: &TokenBucket| now.duration_since(bucket.updated) < IDLE;
+        self.tracked.retain(|_, bucket| active(bucket));
+        if self.overflow.as_ref().is_some_and(|bucket| !active(bucket)) {
+            self.overflow = None;
+        }
+    }
+}
+
+pub struct RateLimiter {
+    limits: RwLock<RateLimits>,
+    queries: Mutex<Buckets<IpNet>>,
+    responses: Mutex<Buckets<ResponseKey>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits: RwLock::new(limits),
+            queries: Mutex::new(Buckets::new()),
+            responses: Mutex::new(Buckets::new()),
+        }
+    }
+
+    pub fn set_limits(&self, limits: RateLimits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    /// Takes a token for a query from `ip`, or says whose limit it is over.
+    pub fn check_query(&self, ip: IpAddr) -> Option<Limited> {
+        let limits = self.limits.read().unwrap();
+        if limits.queries_per_second == 0 {
+            return None;
+        }
+        let network = limits.network(ip)?;
+        let rate = limits.queries_per_second as f64;
+        let burst = limits.burst.max(1) as f64;
+        drop(limits);
+
+        let now = Instant::now();
+        let mut queries = self.queries.lock().unwrap();
+        let bucket = queries.get(network, burst, now);
+        if bucket.take(rate, burst, now) {
+            return None;
+        }
+        Some(Limited {
+            network,
+            first: bucket.limited == 1,
+        })
+    }
+
+    /// Decides whether `reply` may go to `ip` over UDP.
+    pub fn check_response(&self, ip: IpAddr, reply: &[u8]) -> ResponseVerdict {
+        let limits = self.limits.read().unwrap();
+        if limits.responses_per_second == 0 {
+            return ResponseVerdict::Send;
+        }
+        let Some(network) = limits.network(ip) else {
+            return ResponseVerdict::Send;
+        };
+        let rate = limits.responses_per_second as f64;
+        let slip = limits.slip;
+        drop(limits);
+
+        let Ok(packet) = DNSPacket::from_bytes(reply) else {
+            return ResponseVerdict::Send;
+        };
+        let Some(question) = packet.questions.first() else {
+            return ResponseVerdict::Send;
+        };
+        let rcode = packet.header.rcode;
+        let negative =
+            rcode == RCODE_NXDOMAIN || (rcode == RCODE_NOERROR && packet.answers.is_empty());
+        let key = if negative {
+            let zone = packet
+                .authorities
+                .iter()
+                .find(|record| record.tp == TYPE_SOA)
+                .map_or(&question.name, |soa| &soa.name);
+            ResponseKey {
+                network,
+                name: zone.to_ascii_lowercase(),
+                qtype: 0,
+                rcode,
+            }
+        } else {
+            ResponseKey {
+                network,
+                name: question.name.to_ascii_lowercase(),
+                qtype: question.tp,
+                rcode,
+            }
+        };
+
+        let now = Instant::now();
+        let mut responses = self.responses.lock().unwrap();
+        let bucket = responses.get(key, rate, now);
+        if bucket.take(rate, rate, now) {
+            ResponseVerdict::Send
+        } else if slip > 0 && bucket.limited.is_multiple_of(slip) {
+            ResponseVerdict::Slip
+        } else {
+            ResponseVerdict::Drop
+        }
+    }
+
+    /// Forgets buckets that have been idle long enough to be full again.
+    pub fn cleanup(&self, now: Instant) {
+        self.queries.lock().unwrap().retain_active(now);
+        self.responses.lock().unwrap().retain_active(now);
+    }
+}
+
+impl RateLimits {
+    /// The network `ip` is limited as part of, unless it is exempt.
+    fn network(&self, ip: IpAddr) -> Option<IpNet> {
+        let ip = ip.to_canonical();
+        if self.exempt.iter().any(|net| net.contains(&ip)) {
+            return None;
+        }
+        let prefix = match ip {
+            IpAddr::V4(_) => self.ipv4_prefix.min(32),
+            IpAddr::V6(_) => self.ipv6_prefix.min(128),
+        };
+        IpNet::new(ip, prefix).ok().map(|net| net.trunc())
+    }
+}
+
+/// `reply` with its records removed and TC set, asking the client to
+/// retry over TCP.
+pub fn truncated(reply: &[u8]) -> Option<Vec<u8>> {
+    let mut packet = DNSPacket::from_bytes(reply).ok()?;
+    let rcode = packet.header.rcode;
+    packet.make_response(rcode, Vec::new());
+    packet.header.tc = 1;
+    Some(packet.to_bytes())
+}
+
+pub async fn run_cleanup(limiter: Arc<RateLimiter>) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        limiter.cleanup(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{Answer, Question, name_from_str};
+
+    fn limiter(responses_per_second: u32) -> RateLimiter {
+        RateLimiter::new(RateLimits {
+            queries_per_second: 1,
+            burst: 1,
+            responses_per_second,
+            slip: 0,
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+            exempt: Vec::new(),
+        })
+    }
+
+    fn nxdomain(name: &str, zone: &str) -> Vec<u8> {
+        let question = Question {
+            name: name_from_str(name),
+            tp: 1,
+            class: 1,
+        };
+        let mut packet = DNSPacket::query(1, question);
+        packet.make_response(RCODE_NXDOMAIN, Vec::new());
+        packet
+            .authorities
+            .push(Answer::soa(&name_from_str(zone), 300));
+        packet.update_counts();
+        packet.to_bytes()
+    }
+
+    #[test]
+    fn nxdomain_is_limited_per_zone() {
+        let limiter = limiter(1);
+        let client = "192.0.2.1".parse().unwrap();
+        let first = nxdomain("a1.example.com", "example.com");
+        let second = nxdomain("b2.example.com", "example.com");
+        let other = nxdomain("a1.example.net", "example.net");
+        assert_eq!(
+            limiter.check_response(client, &first),
+            ResponseVerdict::Send
+        );
+        assert_eq!(
+            limiter.check_response(client, &second),
+            ResponseVerdict::Drop
+        );
+        assert_eq!(
+            limiter.check_response(client, &other),
+            ResponseVerdict::Send
+        );
+    }
+
+    #[test]
+    fn new_clients_share_a_bucket_when_full() {
+        let limiter = limiter(0);
+        let now = Instant::now();
+        {
+            let mut queries = limiter.queries.lock().unwrap();
+            for i in 0..MAX_TRACKED as u32 {
+                let ip = IpAddr::from((i << 8).to_be_bytes());
+                queries.get(IpNet::from(ip), 1.0, now);
+            }
+        }
+        assert!(
+            limiter
+                .check_query("198.51.100.1".parse().unwrap())
+                .is_none()
+        );
+        let limited = limiter.check_query("203.0.113.1".parse().unwrap());
+        assert!(limited.is_some_and(|limited| limited.first));
+
+        limiter.cleanup(Instant::now() + IDLE);
+        assert!(limiter.queries.lock().unwrap().overflow.is_none());
+        assert!(
+            limiter
+                .check_query("203.0.113.1".parse().unwrap())
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use std::net::IpAddr;
+
+use crate::packet::{Answer, Question};
+
+/// Domains that legitimately resolve to private addresses.
+const LOCAL_SUFFIXES: [&str; 6] = ["localhost", "local", "lan", "home", "home.arpa", "internal"];
+
+/// DNS-rebinding protection: public names must not resolve to addresses
+/// inside the local network, or a web page could use them to reach it.
+pub struct RebindProtection {
+    /// Domains (and their subdomains) exempt from the check.
+    allowed: Vec<String>,
+}
+
+impl RebindProtection {
+    pub fn new(allowed: Vec<String>) -> Self {
+        let allowed = LOCAL_SUFFIXES
+            .iter()
+            .map(|s| s.to_string())
+            .chain(
+                allowed
+                    .into_iter()
+                    .map(|d| d.trim_matches('.').to_ascii_lowercase()),
+            )
+            .collect();
+        Self { allowed }
+    }
+
+    /// Removes A/AAAA records pointing at private, loopback or link-local
+    /// addresses from the answers to `q`. Returns how many were removed.
+    pub fn strip(&self, q: &Question, answers: &mut Vec<Answer>) -> usize {
+        let domain = q.domain().to_ascii_lowercase();
+        let exempt = self
+            .allowed
+            .iter()
+            .any(|allowed| domain == *allowed || domain.ends_with(&format!(".{}", allowed)));
+        if exempt {
+            return 0;
+        }
+
+        let before = answers.len();
+        answers.retain(|answer| !answer.ip().is_some_and(is_internal));
+        before - answers.len()
+    }
+}
+
+/// Whether `ip` belongs to a private, loopback or otherwise local network.
+pub fn is_internal(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let shared = ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64;
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || shared
+        }
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+        }
+    }
+}
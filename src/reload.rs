@@ -0,0 +1,159 @@
+//! Reloading the configuration file, blocklists and local zones while
+//! running, on SIGHUP or `POST /api/reload`. The cache and the queries
+//! waiting for upstream are left alone.
+
+use std::sync::Arc;
+
+use chrono::Local;
+use clap::ArgMatches;
+use tokio::signal::unix::Signal;
+use tokio::sync::{Mutex, broadcast};
+
+use crate::blocklist::DNSBlocklist;
+use crate::cache::DNSCache;
+use crate::ratelimit::RateLimiter;
+use crate::zone::LocalZones;
+use crate::{Args, Filters, Policy, SharedFilters, load_args, load_policy, rate_limits};
+
+/// What a reload rebuilds and where it swaps the result in.
+pub struct Reloader {
+    /// The command line, whose flags keep overriding the file.
+    pub matches: ArgMatches,
+    /// The settings in force, which also serializes reloads.
+    pub current: Mutex<Args>,
+    pub blocklist: Arc<DNSBlocklist>,
+    pub filters: SharedFilters,
+    pub zones: Arc<LocalZones>,
+    pub cache: Arc<DNSCache>,
+    pub limiter: Arc<RateLimiter>,
+    pub log_tx: broadcast::Sender<String>,
+}
+
+impl Reloader {
+    /// Re-reads the configuration and everything it points to. Nothing is
+    /// changed unless all of it loads, in which case the lists, client
+    /// groups, local zones, filtering settings, access lists and rate
+    /// limits are replaced.
+    pub async fn reload(&self) -> anyhow::Result<()> {
+        let mut current = self.current.lock().await;
+        let loaded = load_args(&self.matches).and_then(|(args, config)| {
+            let policy = load_policy(&args, config.as_ref())?;
+            Ok((args, policy))
+        });
+        let (args, policy) = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                self.log(format!(
+                    "Reload failed, keeping the running configuration: {}",
+                    e
+                ));
+                return Err(e);
+            }
+        };
+
+        let Policy {
+            lists,
+            groups,
+            zones,
+        } = policy;
+        for (url, result) in self.blocklist.set_lists(lists).await {
+            if let Err(e) = result {
+                self.log(format!("Blocklist {} has no usable cache: {}", url, e));
+            }
+        }
+        *self.filters.write().unwrap() = Arc::new(Filters::new(&args, groups));
+        self.zones.set_base(zones);
+        self.cache.set_min_ttl(args.cache_min_ttl);
+        self.limiter.set_limits(rate_limits(&args));
+
+        let restart = restart_needed(&current, &args);
+        if restart.is_empty() {
+            self.log("Configuration reloaded".to_string());
+        } else {
+            self.log(format!(
+                "Configuration reloaded; restart to apply {}",
+                restart.join(", ")
+            ));
+        }
+        *current = args;
+        Ok(())
+    }
+
+    fn log(&self, message: String) {
+        let timestamp = Local::now().format("%H:%M:%S");
+        let _ = self.log_tx.send(format!("[{}] {}", timestamp, message));
+    }
+}
+
+/// Flags whose new value only takes effect on a restart, such as the
+/// listeners and the upstream connection.
+fn restart_needed(old: &Args, new: &Args) -> Vec<&'static str> {
+    macro_rules! changed {
+        ($($field:ident => $flag:literal),* $(,)?) => {
+            [$((old.$field != new.$field).then_some($flag)),*]
+                .into_iter()
+                .flatten()
+                .collect()
+        };
+    }
+
+    changed! {
+        port => "--port",
+        listen => "--listen",
+        udp_buffer_size => "--udp-buffer-size",
+        tcp_max_connections => "--tcp-max-connections",
+        tcp_idle_timeout => "--tcp-idle-timeout",
+        resolver => "--resolver",
+        bootstrap => "--bootstrap",
+        upstream_ca => "--upstream-ca",
+        upstream_pin => "--upstream-pin",
+        upstream_connections => "--upstream-connections",
+        upstream_max_pending => "--upstream-max-pending",
+        max_inflight => "--max-inflight",
+        odoh_relay => "--odoh-relay",
+        metrics_listen => "--metrics-listen",
+        api_token => "--api-token",
+        cache_snapshot => "--cache-snapshot",
+        shutdown_timeout => "--shutdown-timeout",
+        no_tui => "--no-tui",
+        user => "--user",
+        group => "--group",
+        chroot => "--chroot",
+        log_buffer_size => "--log-buffer-size",
+        blocklist_refresh => "--blocklist-refresh",
+        blocklist_cache_dir => "--blocklist-cache-dir",
+        no_blocklist_cache => "--no-blocklist-cache",
+        dhcp_leases => "--dhcp-leases",
+        dhcp_domain => "--dhcp-domain",
+        tls_cert => "--tls-cert",
+        tls_key => "--tls-key",
+        dot_listen => "--dot-listen",
+        dot_max_connections => "--dot-max-connections",
+        dot_idle_timeout => "--dot-idle-timeout",
+        doh_listen => "--doh-listen",
+        doh_no_tls => "--doh-no-tls",
+        doh_trusted_proxy => "--doh-trusted-proxy",
+        doh_max_connections => "--doh-max-connections",
+        doh_idle_timeout => "--doh-idle-timeout",
+        doq_listen => "--doq-listen",
+        doq_max_connections => "--doq-max-connections",
+        doq_idle_timeout => "--doq-idle-timeout",
+        doq_early_data => "--doq-early-data",
+        odoh_target => "--odoh-target",
+        odoh_key => "--odoh-key",
+        odoh_relay_target => "--odoh-relay-target",
+        dnscrypt_listen => "--dnscrypt-listen",
+        dnscrypt_provider_name => "--dnscrypt-provider-name",
+        dnscrypt_key => "--dnscrypt-key",
+        dnscrypt_max_connections => "--dnscrypt-max-connections",
+        dnscrypt_idle_timeout => "--dnscrypt-idle-timeout",
+    }
+}
+
+/// Reloads whenever the process receives SIGHUP.
+pub async fn run_on_hangup(mut hangup: Signal, reloader: Arc<Reloader>) {
+    while hangup.recv().await.is_some() {
+        // Failures are logged by `reload`, and the old state stays.
+        let _ = reloader.reload().await;
+    }
+}
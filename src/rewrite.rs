@@ -0,0 +1,193 @@
+use std::collections::{BTreeMap, HashMap};
+
+use thiserror::Error;
+
+use crate::packet::{Answer, Question, TYPE_CNAME, check_name};
+
+const GOOGLE_SAFE_SEARCH: &[(&str, &str)] = &[
+    ("google.com", "forcesafesearch.google.com"),
+    ("www.google.com", "forcesafesearch.google.com"),
+    ("www.google.co.uk", "forcesafesearch.google.com"),
+    ("www.google.ca", "forcesafesearch.google.com"),
+    ("www.google.com.au", "forcesafesearch.google.com"),
+    ("www.google.de", "forcesafesearch.google.com"),
+    ("www.google.fr", "forcesafesearch.google.com"),
+    ("www.google.es", "forcesafesearch.google.com"),
+    ("www.google.it", "forcesafesearch.google.com"),
+    ("www.google.nl", "forcesafesearch.google.com"),
+    ("www.google.co.in", "forcesafesearch.google.com"),
+    ("www.google.co.jp", "forcesafesearch.google.com"),
+    ("www.google.com.br", "forcesafesearch.google.com"),
+    ("www.google.com.mx", "forcesafesearch.google.com"),
+];
+
+const BING_SAFE_SEARCH: &[(&str, &str)] = &[
+    ("bing.com", "strict.bing.com"),
+    ("www.bing.com", "strict.bing.com"),
+];
+
+const DUCKDUCKGO_SAFE_SEARCH: &[(&str, &str)] = &[
+    ("duckduckgo.com", "safe.duckduckgo.com"),
+    ("www.duckduckgo.com", "safe.duckduckgo.com"),
+    ("start.duckduckgo.com", "safe.duckduckgo.com"),
+];
+
+const YOUTUBE_HOSTS: &[&str] = &[
+    "youtube.com",
+    "www.youtube.com",
+    "m.youtube.com",
+    "youtubei.googleapis.com",
+    "youtube.googleapis.com",
+    "www.youtube-nocookie.com",
+];
+
+#[derive(Debug, Error)]
+pub enum RewriteError {
+    #[error("unknown rewrite set {0}")]
+    UnknownSet(String),
+    #[error("rewrite set {set}: invalid target for {domain}: {message}")]
+    InvalidTarget {
+        set: String,
+        domain: String,
+        message: String,
+    },
+}
+
+/// Answers questions for some names with a CNAME to another name, e.g. to
+/// force search engines into their safe-search endpoints.
+#[derive(Debug, Clone, Default)]
+pub struct RewriteRules {
+    exact: HashMap<String, String>,
+    /// Rules written as `*.example.com`, keyed by `example.com`.
+    wildcard: HashMap<String, String>,
+}
+
+impl RewriteRules {
+    /// Combines the named sets, looking each up in `custom` first and then
+    /// among the built-ins. Custom targets must be names that fit on the
+    /// wire, as the rewritten question is sent upstream.
+    pub fn from_sets(
+        names: &[String],
+        custom: &BTreeMap<String, BTreeMap<String, String>>,
+    ) -> Result<Self, RewriteError> {
+        let mut rules = Self::default();
+        for name in names {
+            if let Some(set) = custom.get(name) {
+                for (domain, target) in set {
+                    check_name(target).map_err(|message| RewriteError::InvalidTarget {
+                        set: name.clone(),
+                        domain: domain.clone(),
+                        message,
+                    })?;
+                    rules.insert(domain, target);
+                }
+                continue;
+            }
+            match builtin(name) {
+                Some(set) => {
+                    for (domain, target) in set {
+                        rules.insert(&domain, &target);
+                    }
+                }
+                None => return Err(RewriteError::UnknownSet(name.clone())),
+            }
+        }
+        Ok(rules)
+    }
+
+    fn insert(&mut self, domain: &str, target: &str) {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let target = target.trim_end_matches('.').to_ascii_lowercase();
+        match domain.strip_prefix("*.") {
+            Some(parent) => self.wildcard.insert(parent.to_string(), target),
+            None => self.exact.insert(domain, target),
+        };
+    }
+
+    /// The name `domain` should be answered as, if a rule matches it.
+    pub fn target(&self, domain: &str) -> Option<&str> {
+        let domain = domain.to_ascii_lowercase();
+        if let Some(target) = self.exact.get(&domain) {
+            return Some(target);
+        }
+        let mut parent = domain.as_str();
+        while let Some((_, rest)) = parent.split_once('.') {
+            if let Some(target) = self.wildcard.get(rest) {
+                return Some(target);
+            }
+            parent = rest;
+        }
+        None
+    }
+}
+
+fn builtin(name: &str) -> Option<Vec<(String, String)>> {
+    let pairs = |set: &[(&str, &str)]| {
+        set.iter()
+            .map(|(d, t)| (d.to_string(), t.to_string()))
+            .collect::<Vec<_>>()
+    };
+    let youtube = |target: &str| {
+        YOUTUBE_HOSTS
+            .iter()
+            .map(|d| (d.to_string(), target.to_string()))
+            .collect::<Vec<_>>()
+    };
+
+    let set = match name {
+        "google-safe-search" => pairs(GOOGLE_SAFE_SEARCH),
+        "bing-safe-search" => pairs(BING_SAFE_SEARCH),
+        "duckduckgo-safe-search" => pairs(DUCKDUCKGO_SAFE_SEARCH),
+        "youtube-restricted" => youtube("restrict.youtube.com"),
+        "youtube-moderate" => youtube("restrictmoderate.youtube.com"),
+        "safe-search" => {
+            let mut set = pairs(GOOGLE_SAFE_SEARCH);
+            set.extend(pairs(BING_SAFE_SEARCH));
+            set.extend(pairs(DUCKDUCKGO_SAFE_SEARCH));
+            set.extend(youtube("restrict.youtube.com"));
+            set
+        }
+        _ => return None,
+    };
+    Some(set)
+}
+
+/// The CNAME answer linking the client's `original` question to the
+/// `rewritten` one that was actually resolved.
+pub fn cname_answer(original: &Question, rewritten: &Question, ttl: u32) -> Answer {
+    Answer::new(
+        original.name.clone(),
+        TYPE_CNAME,
+        ttl,
+        rewritten.name.clone(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn custom(target: &str) -> BTreeMap<String, BTreeMap<String, String>> {
+        let set = BTreeMap::from([("*.Example.com.".to_string(), target.to_string())]);
+        BTreeMap::from([("mine".to_string(), set)])
+    }
+
+    #[test]
+    fn combines_custom_and_builtin_sets() {
+        let names = ["mine".to_string(), "bing-safe-search".to_string()];
+        let rules = RewriteRules::from_sets(&names, &custom("Safe.Example.net.")).unwrap();
+        assert_eq!(rules.target("www.EXAMPLE.com"), Some("safe.example.net"));
+        assert_eq!(rules.target("example.com"), None);
+        assert_eq!(rules.target("www.bing.com"), Some("strict.bing.com"));
+
+        let unknown = RewriteRules::from_sets(&["nope".to_string()], &custom("safe.example.net"));
+        assert!(matches!(unknown, Err(RewriteError::UnknownSet(set)) if set == "nope"));
+    }
+
+    #[test]
+    fn rejects_targets_too_long_for_the_wire() {
+        let target = format!("{}.example.net", "a".repeat(64));
+        let rules = RewriteRules::from_sets(&["mine".to_string()], &custom(&target));
+        assert!(matches!(rules, Err(RewriteError::InvalidTarget { .. })));
+    }
+}
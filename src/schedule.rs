@@ -0,0 +1,116 @@
+use chrono::{DateTime, Datelike, Duration, NaiveTime, TimeZone, Weekday};
+use serde::Deserialize;
+
+/// A recurring window of local time, e.g. weekdays from 08:00 to 15:00.
+/// Windows whose end is before their start run past midnight and belong
+/// to the day they start on.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "TimeWindowConfig")]
+pub struct TimeWindow {
+    days: Vec<Weekday>,
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TimeWindowConfig {
+    /// Day names such as "mon" or "friday"; every day when omitted.
+    #[serde(default)]
+    days: Vec<String>,
+    start: String,
+    end: String,
+}
+
+impl TryFrom<TimeWindowConfig> for TimeWindow {
+    type Error = String;
+
+    fn try_from(config: TimeWindowConfig) -> Result<Self, Self::Error> {
+        let days = if config.days.is_empty() {
+            vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+                Weekday::Sat,
+                Weekday::Sun,
+            ]
+        } else {
+            config
+                .days
+                .iter()
+                .map(|day| day.parse().map_err(|_| format!("invalid day {:?}", day)))
+                .collect::<Result<_, _>>()?
+        };
+        let parse_time = |time: &str| {
+            NaiveTime::parse_from_str(time, "%H:%M")
+                .map_err(|_| format!("invalid time {:?}, expected HH:MM", time))
+        };
+
+        let (start, end) = (parse_time(&config.start)?, parse_time(&config.end)?);
+        if start == end {
+            return Err(format!(
+                "window starts and ends at {}, so it would never apply",
+                config.start
+            ));
+        }
+
+        Ok(Self { days, start, end })
+    }
+}
+
+impl TimeWindow {
+    pub fn contains<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> bool {
+        let time = now.time();
+        let today = now.weekday();
+
+        if self.start <= self.end {
+            self.days.contains(&today) && time >= self.start && time < self.end
+        } else {
+            // Overnight: the late part belongs to today, the early part to
+            // a window that started yesterday.
+            let yesterday = (now.date_naive() - Duration::days(1)).weekday();
+            (self.days.contains(&today) && time >= self.start)
+                || (self.days.contains(&yesterday) && time < self.end)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, NaiveDate};
+
+    fn window(days: &[&str], start: &str, end: &str) -> Result<TimeWindow, String> {
+        TimeWindow::try_from(TimeWindowConfig {
+            days: days.iter().map(|day| day.to_string()).collect(),
+            start: start.to_string(),
+            end: end.to_string(),
+        })
+    }
+
+    fn at(day: u32, time: &str) -> DateTime<Local> {
+        // 2024-01-01 was a Monday.
+        let time = NaiveTime::parse_from_str(time, "%H:%M").unwrap();
+        let date = NaiveDate::from_ymd_opt(2024, 1, day).unwrap();
+        Local.from_local_datetime(&date.and_time(time)).unwrap()
+    }
+
+    #[test]
+    fn overnight_windows_belong_to_the_day_they_start() {
+        let window = window(&["fri"], "22:00", "06:00").unwrap();
+        assert!(window.contains(&at(5, "23:00")));
+        assert!(window.contains(&at(6, "05:59")));
+        assert!(!window.contains(&at(6, "06:00")));
+        assert!(!window.contains(&at(5, "05:00")));
+    }
+
+    #[test]
+    fn rejects_invalid_windows() {
+        assert!(window(&[], "08:00", "08:00").is_err());
+        assert!(window(&[], "8am", "15:00").is_err());
+        assert!(window(&["someday"], "08:00", "15:00").is_err());
+        assert!(window(&[], "08:00", "15:00").is_ok());
+    }
+}
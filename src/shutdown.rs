@@ -0,0 +1,73 @@
+//! Stopping cleanly on SIGTERM or SIGINT: new queries are no longer
+//! accepted and those already forwarded get a chance to be answered.
+
+use std::io::Write;
+use std::time::Duration;
+
+use chrono::Local;
+use tokio::signal::unix::Signal;
+use tokio::sync::{broadcast, oneshot, watch};
+use tokio::time::Instant;
+
+use crate::PendingMap;
+
+/// How often `drain` checks whether the pending queries are answered.
+const DRAIN_POLL: Duration = Duration::from_millis(50);
+
+/// Asks for a shutdown on the first SIGTERM or SIGINT.
+pub async fn run_on_signal(
+    mut terminate: Signal,
+    mut interrupt: Signal,
+    shutdown: watch::Sender<bool>,
+    log_tx: broadcast::Sender<String>,
+) {
+    let name = tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    };
+    let timestamp = Local::now().format("%H:%M:%S");
+    let _ = log_tx.send(format!("[{}] Received {}, shutting down", timestamp, name));
+    shutdown.send_replace(true);
+}
+
+/// Waits until every forwarded query has had its reply, or `timeout`
+/// passes. Returns how many were still waiting.
+pub async fn drain(pending: &PendingMap, timeout: Duration) -> usize {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let waiting = pending.lock().unwrap().len();
+        if waiting == 0 || Instant::now() >= deadline {
+            return waiting;
+        }
+        tokio::time::sleep(DRAIN_POLL).await;
+    }
+}
+
+/// Prints the query log to stdout until `finished` fires, then prints
+/// whatever is still queued and flushes.
+pub async fn write_log(
+    mut log_rx: broadcast::Receiver<String>,
+    mut finished: oneshot::Receiver<()>,
+) {
+    loop {
+        tokio::select! {
+            biased;
+            line = log_rx.recv() => match line {
+                Ok(line) => println!("{}", line),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = &mut finished => {
+                loop {
+                    match log_rx.try_recv() {
+                        Ok(line) => println!("{}", line),
+                        Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                        Err(_) => break,
+                    }
+                }
+                break;
+            }
+        }
+    }
+    let _ = std::io::stdout().flush();
+}
@@ -0,0 +1,133 @@
+//! Integration with systemd: readiness notifications for `Type=notify`
+//! services, and listening sockets passed by socket activation.
+
+use std::io;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+use socket2::{Socket, Type};
+use tokio::net::TcpListener;
+
+/// Sends `state` (e.g. `READY=1`) to the service manager. Does nothing
+/// unless systemd passed a notification socket.
+pub fn notify(state: &str) -> io::Result<()> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+    // A leading '@' names a socket in the abstract namespace.
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(&path)?,
+    };
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// First descriptor passed by socket activation (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets passed by systemd socket activation. A socket named `dot`,
+/// `doh`, `doq` or `dnscrypt` (with `FileDescriptorName=`) serves that
+/// listener; any other UDP socket answers plain DNS, as do stream sockets
+/// named `dns`.
+#[derive(Default)]
+pub struct Activated {
+    pub dns: Vec<Socket>,
+    pub dns_tcp: Vec<Socket>,
+    pub dot: Option<Socket>,
+    pub doh: Option<Socket>,
+    pub doq: Option<Socket>,
+    pub dnscrypt_udp: Option<Socket>,
+    pub dnscrypt_tcp: Option<Socket>,
+}
+
+/// Takes the sockets systemd passed to this process, if any.
+pub fn listen_fds() -> io::Result<Activated> {
+    let mut activated = Activated::default();
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let count: RawFd = match std::env::var("LISTEN_FDS") {
+        Ok(count) if for_us => count.parse().unwrap_or(0),
+        _ => return Ok(activated),
+    };
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // SAFETY: systemd hands these descriptors to us alone, and each is
+        // wrapped exactly once.
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        let name = names.next().unwrap_or_default();
+        let udp = socket.r#type()? == Type::DGRAM;
+        let slot = match (name, udp) {
+            ("dot", false) => &mut activated.dot,
+            ("doh", false) => &mut activated.doh,
+            ("doq", true) => &mut activated.doq,
+            ("dnscrypt", true) => &mut activated.dnscrypt_udp,
+            ("dnscrypt", false) => &mut activated.dnscrypt_tcp,
+            ("dns", false) => {
+                activated.dns_tcp.push(socket);
+                continue;
+            }
+            (_, true) => {
+                activated.dns.push(socket);
+                continue;
+            }
+            (name, false) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("don't know what to serve on stream socket {:?}", name),
+                ));
+            }
+        };
+        if slot.replace(socket).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("more than one {} socket passed", name),
+            ));
+        }
+    }
+    Ok(activated)
+}
+
+/// The address an activated socket is bound to.
+pub fn socket_addr(socket: &Socket) -> io::Result<std::net::SocketAddr> {
+    socket.local_addr()?.as_socket().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "passed socket is not an IP socket",
+        )
+    })
+}
+
+/// The activated TCP socket if there is one, otherwise one bound to `addr`.
+pub async fn tcp_listener(
+    activated: Option<Socket>,
+    addr: std::net::SocketAddr,
+) -> io::Result<TcpListener> {
+    match activated {
+        Some(socket) => {
+            socket.set_nonblocking(true)?;
+            TcpListener::from_std(socket.into())
+        }
+        None => TcpListener::bind(addr).await,
+    }
+}
+
+/// The activated UDP socket if there is one, otherwise one bound to `addr`.
+pub fn udp_socket(
+    activated: Option<Socket>,
+    addr: std::net::SocketAddr,
+) -> io::Result<std::net::UdpSocket> {
+    let socket = match activated {
+        Some(socket) => socket.into(),
+        None => std::net::UdpSocket::bind(addr)?,
+    };
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
@@ -0,0 +1,291 @@
+use std::{path::Path, sync::Arc};
+
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio_rustls::rustls::{
+    self, ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+    client::{
+        WebPkiServerVerifier,
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    },
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject},
+};
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("failed to read {path}: {source}")]
+    Pem {
+        path: String,
+        source: rustls::pki_types::pem::Error,
+    },
+    #[error("no certificates found in {0}")]
+    NoCertificates(String),
+    #[error("invalid certificate or key: {0}")]
+    Config(#[from] rustls::Error),
+    #[error("invalid trust anchors: {0}")]
+    Verifier(#[from] rustls::client::VerifierBuilderError),
+    #[error("unusable for QUIC: {0}")]
+    Quic(#[from] quinn::crypto::rustls::NoInitialCipherSuite),
+}
+
+/// SHA-256 digest of a certificate's SubjectPublicKeyInfo.
+pub type SpkiPin = [u8; 32];
+
+/// Builds a server config from a PEM certificate chain and private key,
+/// offering the given ALPN protocols.
+pub fn server_config(
+    cert_path: &Path,
+    key_path: &Path,
+    alpn: &[&[u8]],
+) -> Result<Arc<ServerConfig>, TlsError> {
+    let certs = read_certs(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(pem_error(key_path))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+    Ok(Arc::new(config))
+}
+
+/// Builds a client config for talking to upstream resolvers. Servers are
+/// verified against `ca_path` if given, or the system roots otherwise, and
+/// when `pins` is not empty the leaf certificate's key must match one of them.
+pub fn client_config(
+    ca_path: Option<&Path>,
+    pins: &[SpkiPin],
+    alpn: &[&[u8]],
+) -> Result<ClientConfig, TlsError> {
+    let mut roots = RootCertStore::empty();
+    match ca_path {
+        Some(path) => {
+            for cert in read_certs(path)? {
+                roots.add(cert)?;
+            }
+        }
+        // Unreadable system certificates are skipped, as long as some remain.
+        None => {
+            roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+        }
+    }
+    if roots.is_empty() {
+        let source = ca_path.map_or("the system store".to_string(), |p| p.display().to_string());
+        return Err(TlsError::NoCertificates(source));
+    }
+
+    let verifier = PinnedVerifier {
+        inner: WebPkiServerVerifier::builder(Arc::new(roots)).build()?,
+        pins: pins.to_vec(),
+    };
+    let mut config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+    Ok(config)
+}
+
+/// Parses a pin given as `sha256/<base64>` or plain base64, the format
+/// printed by `openssl x509 -pubkey | openssl pkey -pubin -outform der |
+/// openssl dgst -sha256 -binary | base64`.
+pub fn parse_pin(s: &str) -> Result<SpkiPin, String> {
+    let encoded = s.strip_prefix("sha256/").unwrap_or(s);
+    base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .ok()
+        .and_then(|digest| digest.try_into().ok())
+        .ok_or_else(|| format!("'{}' is not a base64 SHA-256 digest", s))
+}
+
+fn pem_error(path: &Path) -> impl FnOnce(rustls::pki_types::pem::Error) -> TlsError {
+    let path = path.display().to_string();
+    move |source| TlsError::Pem { path, source }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(pem_error(path))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(path.display().to_string()));
+    }
+    Ok(certs)
+}
+
+/// Verifies certificates the usual way, then checks the leaf key against
+/// the configured pins.
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<SpkiPin>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        if self.pins.is_empty() {
+            return Ok(verified);
+        }
+
+        let spki = subject_public_key_info(end_entity).ok_or(rustls::Error::InvalidCertificate(
+            rustls::CertificateError::BadEncoding,
+        ))?;
+        let digest: SpkiPin = Sha256::digest(spki).into();
+        if self.pins.contains(&digest) {
+            Ok(verified)
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Finds the DER-encoded SubjectPublicKeyInfo inside an X.509 certificate.
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    let (_, certificate, _) = der_element(cert)?;
+    let (_, tbs, _) = der_element(certificate)?;
+    let mut rest = tbs;
+    // The version is an optional explicitly tagged [0] field.
+    if rest.first() == Some(&0xa0) {
+        rest = der_element(rest)?.2;
+    }
+    // Skip serialNumber, signature, issuer, validity and subject.
+    for _ in 0..5 {
+        rest = der_element(rest)?.2;
+    }
+    der_element(rest).map(|(element, _, _)| element)
+}
+
+/// Splits the DER element at the start of `data` into the whole element,
+/// its contents and whatever follows it.
+fn der_element(data: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let first = *data.get(1)?;
+    let (len, header) = if first < 0x80 {
+        (first as usize, 2)
+    } else {
+        let octets = (first & 0x7f) as usize;
+        if octets == 0 || octets > 4 {
+            return None;
+        }
+        let mut len = 0usize;
+        for i in 0..octets {
+            len = (len << 8) | *data.get(2 + i)? as usize;
+        }
+        (len, 2 + octets)
+    };
+    let end = header.checked_add(len)?;
+    if end > data.len() {
+        return None;
+    }
+    Some((&data[..end], &data[header..end], &data[end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{
+        CertificateParams, DnType, KeyPair, PKCS_ECDSA_P256_SHA256, PKCS_ECDSA_P384_SHA384,
+        PKCS_ED25519, PublicKeyData, SignatureAlgorithm,
+    };
+
+    fn certificate(alg: &'static SignatureAlgorithm, names: Vec<String>) -> (Vec<u8>, Vec<u8>) {
+        let key = KeyPair::generate_for(alg).unwrap();
+        let cert = CertificateParams::new(names)
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        (cert.der().to_vec(), key.subject_public_key_info())
+    }
+
+    #[test]
+    fn finds_the_key_of_each_algorithm() {
+        for alg in [
+            &PKCS_ECDSA_P256_SHA256,
+            &PKCS_ECDSA_P384_SHA384,
+            &PKCS_ED25519,
+        ] {
+            let (cert, spki) = certificate(alg, vec!["dns.example".to_string()]);
+            assert_eq!(subject_public_key_info(&cert), Some(&spki[..]));
+        }
+    }
+
+    #[test]
+    fn finds_the_key_past_long_fields() {
+        // A subject and a certificate whose lengths need several octets.
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let names: Vec<_> = (0..4000).map(|i| format!("host{}.example", i)).collect();
+        let mut params = CertificateParams::new(names).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "o".repeat(300));
+        let cert = params.self_signed(&key).unwrap();
+        assert!(cert.der().len() > 0xffff);
+        assert_eq!(
+            subject_public_key_info(cert.der()),
+            Some(&key.subject_public_key_info()[..])
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_certificates() {
+        let (cert, _) = certificate(&PKCS_ED25519, vec!["dns.example".to_string()]);
+        for len in [0, 1, 2, 10, cert.len() / 2, cert.len() - 1] {
+            assert_eq!(subject_public_key_info(&cert[..len]), None);
+        }
+    }
+
+    #[test]
+    fn splits_der_lengths() {
+        assert_eq!(
+            der_element(&[0x04, 0x02, 1, 2, 3]),
+            Some((&[0x04, 0x02, 1, 2][..], &[1, 2][..], &[3][..]))
+        );
+        let mut long = vec![0x04, 0x81, 0x80];
+        long.extend([7; 0x80]);
+        assert_eq!(
+            der_element(&long).map(|(_, contents, _)| contents.len()),
+            Some(0x80)
+        );
+        // Indefinite and oversized lengths.
+        assert_eq!(der_element(&[0x30, 0x80, 0, 0]), None);
+        assert_eq!(der_element(&[0x04, 0x85, 1, 0, 0, 0, 0]), None);
+        assert_eq!(der_element(&[0x04, 0x03, 1, 2]), None);
+    }
+}
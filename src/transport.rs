@@ -0,0 +1,293 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, watch},
+};
+
+use crate::listen::{PacketInfo, UdpListener};
+use crate::ratelimit::{RateLimiter, ResponseVerdict, truncated};
+use crate::{Context, metrics, spawn_query};
+
+/// Replies a stream connection may have queued before its writer catches up.
+const STREAM_REPLY_QUEUE: usize = 32;
+/// How long a listener waits for the pipeline to answer a query.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Limits applied to every connection of a stream or HTTP listener.
+pub struct ConnectionLimits {
+    pub max_connections: usize,
+    pub idle_timeout: Duration,
+}
+
+/// How a query reached us, used to label metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+    Quic,
+    DnsCrypt,
+}
+
+impl Transport {
+    pub fn label(self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Tls => "tls",
+            Transport::Https => "https",
+            Transport::Quic => "quic",
+            Transport::DnsCrypt => "dnscrypt",
+        }
+    }
+}
+
+/// Where the reply to a query is written.
+#[derive(Clone)]
+pub enum Responder {
+    /// A plain DNS socket, where on it the query arrived, the limiter its
+    /// replies pass through and the largest reply the client can take.
+    Udp(
+        Arc<UdpListener>,
+        Option<PacketInfo>,
+        Arc<RateLimiter>,
+        usize,
+    ),
+    /// Whoever delivers replies for the connection, such as the writer
+    /// half of a stream or an HTTP request handler, and for plain DNS over
+    /// TCP the address the connection was accepted on.
+    Channel(Transport, Option<SocketAddr>, mpsc::Sender<Vec<u8>>),
+}
+
+impl Responder {
+    pub fn transport(&self) -> Transport {
+        match self {
+            Responder::Udp(..) => Transport::Udp,
+            Responder::Channel(transport, ..) => *transport,
+        }
+    }
+
+    /// The plain DNS address the query arrived on, which may have an
+    /// access list of its own.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Responder::Udp(listener, ..) => Some(listener.local_addr()),
+            Responder::Channel(_, local, _) => *local,
+        }
+    }
+
+    pub async fn send(&self, reply: Vec<u8>, client: SocketAddr) -> io::Result<()> {
+        match self {
+            Responder::Udp(listener, info, limiter, max_size) => {
+                // A reply too large for the client, such as one fetched
+                // again over TCP, only tells it to retry over TCP itself.
+                let reply = if reply.len() > *max_size {
+                    match truncated(&reply) {
+                        Some(reply) => reply,
+                        None => return Ok(()),
+                    }
+                } else {
+                    reply
+                };
+                match limiter.check_response(client.ip(), &reply) {
+                    ResponseVerdict::Send => listener.send(&reply, client, *info).await,
+                    ResponseVerdict::Slip => {
+                        metrics::RATE_LIMITED
+                            .with_label_values(&["response", "slipped"])
+                            .inc();
+                        match truncated(&reply) {
+                            Some(reply) => listener.send(&reply, client, *info).await,
+                            None => Ok(()),
+                        }
+                    }
+                    ResponseVerdict::Drop => {
+                        metrics::RATE_LIMITED
+                            .with_label_values(&["response", "dropped"])
+                            .inc();
+                        Ok(())
+                    }
+                }
+            }
+            Responder::Channel(.., tx) => tx
+                .send(reply)
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "connection closed")),
+        }
+    }
+}
+
+/// Whether a socket error only concerns one packet, such as an ICMP error
+/// reported for an earlier send, so the next receive can go ahead at once.
+/// Anything else is worth a pause before trying again.
+pub fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+    )
+}
+
+/// Reads one length-prefixed message (RFC 1035 section 4.2.2), or `None`
+/// once the peer has closed the connection.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 2];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut message = vec![0; u16::from_be_bytes(len) as usize];
+    reader.read_exact(&mut message).await?;
+    Ok(Some(message))
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, message: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(message.len() + 2);
+    frame.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    frame.extend_from_slice(message);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+/// Answers length-prefixed queries on `stream` until the peer closes it or
+/// stays quiet for `idle_timeout`. Queries are handled concurrently and
+/// their replies written back as they complete, possibly out of order.
+pub async fn serve_stream<S>(
+    stream: S,
+    peer: SocketAddr,
+    transport: Transport,
+    local: Option<SocketAddr>,
+    ctx: Context,
+    idle_timeout: Duration,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let shutdown = ctx.shutdown.clone();
+    serve_frames(
+        stream,
+        transport,
+        local,
+        idle_timeout,
+        shutdown,
+        move |message, responder| {
+            let ctx = ctx.clone();
+            async move { spawn_query(message, peer, responder, &ctx).await }
+        },
+    )
+    .await;
+}
+
+/// Reads length-prefixed messages from `stream` until the peer closes it,
+/// stays quiet for `idle_timeout` or `shutdown` becomes true, passing each
+/// to `handle` along with a responder that writes replies back in the
+/// order they are sent. `local` is the plain DNS address the connection
+/// was accepted on.
+pub async fn serve_frames<S, F, Fut>(
+    stream: S,
+    transport: Transport,
+    local: Option<SocketAddr>,
+    idle_timeout: Duration,
+    mut shutdown: watch::Receiver<bool>,
+    mut handle: F,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    F: FnMut(Vec<u8>, Responder) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (tx, mut rx) = mpsc::channel(STREAM_REPLY_QUEUE);
+    let responder = Responder::Channel(transport, local, tx);
+
+    let reading = tokio::spawn(async move {
+        loop {
+            let read = tokio::time::timeout(idle_timeout, read_frame(&mut reader));
+            let message = tokio::select! {
+                read = read => match read {
+                    Ok(Ok(Some(message))) if message.len() >= 12 => message,
+                    _ => break,
+                },
+                Ok(_) = shutdown.wait_for(|stop| *stop) => break,
+            };
+            handle(message, responder.clone()).await;
+        }
+    });
+
+    // Replies still pending when the reader stops are written as long as
+    // they keep arriving; the channel closes once none are outstanding.
+    while let Ok(Some(reply)) = tokio::time::timeout(idle_timeout, rx.recv()).await {
+        if write_frame(&mut writer, &reply).await.is_err() {
+            metrics::CONNECTION_ERRORS
+                .with_label_values(&[transport.label(), "write"])
+                .inc();
+            break;
+        }
+    }
+    reading.abort();
+    let _ = writer.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{Answer, CLASS_IN, DNSPacket, Question, TYPE_TXT, name_from_str};
+    use crate::ratelimit::RateLimits;
+    use tokio::net::UdpSocket;
+
+    fn large_reply() -> Vec<u8> {
+        let question = Question {
+            name: name_from_str("example.com"),
+            tp: TYPE_TXT,
+            class: CLASS_IN,
+        };
+        let mut packet = DNSPacket::query(7, question);
+        let answers = (0..8)
+            .map(|_| Answer::new(name_from_str("example.com"), TYPE_TXT, 60, vec![b'a'; 200]))
+            .collect();
+        packet.make_response(0, answers);
+        packet.to_bytes()
+    }
+
+    async fn send_udp(reply: Vec<u8>, max_size: usize) -> Vec<u8> {
+        let listener = UdpListener::bind(&"127.0.0.1:0".parse().unwrap(), 0).unwrap();
+        let limiter = RateLimiter::new(RateLimits {
+            queries_per_second: 0,
+            burst: 0,
+            responses_per_second: 0,
+            slip: 0,
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+            exempt: Vec::new(),
+        });
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let responder = Responder::Udp(Arc::new(listener), None, Arc::new(limiter), max_size);
+        responder
+            .send(reply, client.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut buf = vec![0; 65535];
+        let size = client.recv(&mut buf).await.unwrap();
+        buf.truncate(size);
+        buf
+    }
+
+    #[tokio::test]
+    async fn truncates_udp_replies_the_client_cannot_take() {
+        let reply = large_reply();
+        assert!(reply.len() > 1232);
+
+        let sent = send_udp(reply.clone(), 512).await;
+        assert!(sent.len() <= 512);
+        let packet = DNSPacket::from_bytes(&sent).unwrap();
+        assert_eq!(packet.header.tc, 1);
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.questions[0].domain(), "example.com");
+
+        assert_eq!(send_udp(reply.clone(), 4096).await, reply);
+    }
+}
@@ -1,12 +1,13 @@
 use std::{io, sync::Arc, time::{Duration, Instant}};
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyModifiers},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
+use chrono::{Local, TimeDelta};
 use ratatui::{prelude::*, widgets::*};
 use sysinfo::System;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, watch};
 use crate::{blocklist::DNSBlocklist, metrics};
 
 // Tokyo Night Palette
@@ -20,9 +21,12 @@ const TN_MAGENTA: Color = Color::Rgb(187, 154, 247);
 const TN_CYAN: Color = Color::Rgb(125, 207, 255);
 const TN_WHITE: Color = Color::Rgb(169, 177, 214);
 
+/// Runs until the user quits, which asks for a shutdown, or a shutdown is
+/// asked for elsewhere. The terminal is restored either way.
 pub async fn run(
-    mut rx: broadcast::Receiver<String>,
+    rx: &mut broadcast::Receiver<String>,
     blocklist: Arc<DNSBlocklist>,
+    shutdown: watch::Sender<bool>,
 ) -> io::Result<()> {
     // Setup terminal
     enable_raw_mode()?;
@@ -36,7 +40,8 @@ pub async fn run(
     let mut logs: Vec<String> = Vec::new();
     let start_time = Instant::now();
 
-    let res = run_app(&mut terminal, &mut sys, &mut rx, &mut logs, blocklist, start_time).await;
+    let res = run_app(&mut terminal, &mut sys, rx, &mut logs, blocklist, &shutdown, start_time).await;
+    shutdown.send_replace(true);
 
     // Restore terminal
     disable_raw_mode()?;
@@ -60,17 +65,28 @@ async fn run_app(
     rx: &mut broadcast::Receiver<String>,
     logs: &mut Vec<String>,
     blocklist: Arc<DNSBlocklist>,
+    shutdown: &watch::Sender<bool>,
     start_time: Instant,
 ) -> io::Result<()> {
     let mut interval = tokio::time::interval(Duration::from_millis(250));
 
     loop {
+        if *shutdown.borrow() {
+            return Ok(());
+        }
+
         // Handle input (non-blocking)
-        if crossterm::event::poll(Duration::from_millis(0))? {
-             if let Event::Key(key) = event::read()? {
-                if let KeyCode::Char('q') = key.code {
-                    return Ok(());
-                }
+        if crossterm::event::poll(Duration::from_millis(0))?
+            && let Event::Key(key) = event::read()?
+        {
+            match key.code {
+                KeyCode::Char('q') => return Ok(()),
+                // Raw mode turns Ctrl-C into a key press instead of SIGINT.
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+                // Pause blocking for a while, e.g. when troubleshooting a site.
+                KeyCode::Char('p') => blocklist.pause_until(Local::now() + TimeDelta::minutes(10)),
+                KeyCode::Char('r') => blocklist.resume(),
+                _ => {}
             }
         }
 
@@ -191,6 +207,10 @@ fn ui(
         Line::from(vec![
             Span::styled("Blocklist: ", Style::default().fg(TN_FG)),
             Span::styled(format!("{} domains", blocklist.len()), Style::default().fg(TN_RED)),
+            match blocklist.paused_until() {
+                Some(until) => Span::styled(format!(" (paused until {}, 'r' resumes)", until.format("%H:%M")), Style::default().fg(TN_YELLOW)),
+                None => Span::styled(" ('p' pauses 10m)", Style::default().fg(TN_FG)),
+            },
         ]),
     ];
 
@@ -0,0 +1,1188 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use chrono::Local;
+use thiserror::Error;
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    sync::{Mutex, broadcast, mpsc},
+};
+use tokio_rustls::{TlsConnector, rustls::pki_types::ServerName};
+
+use crate::dnscrypt::{self, ClientSession, DnsCryptError, HALF_NONCE, MIN_UDP_QUERY, Stamp};
+use crate::odoh::{ODOH_MESSAGE, OdohError, TargetConfig};
+use crate::packet::{DNSPacket, Question, TYPE_A, TYPE_AAAA, TYPE_TXT, name_from_str};
+use crate::tls::{self, SpkiPin, TlsError};
+use crate::transport::{is_transient, read_frame, write_frame};
+
+/// How long establishing a connection to an upstream may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long an HTTPS or QUIC exchange may take before it is abandoned.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Queries a pooled TLS connection may have queued before its writer.
+const CONNECTION_QUEUE: usize = 64;
+/// Largest reply a plain UDP upstream can send.
+const MAX_UDP_REPLY: usize = 65535;
+
+#[derive(Debug, Error)]
+pub enum UpstreamError {
+    #[error("invalid upstream '{0}'")]
+    InvalidUrl(String),
+    #[error("could not resolve {host} via {server}: {reason}")]
+    Bootstrap {
+        host: String,
+        server: SocketAddr,
+        reason: String,
+    },
+    #[error("failed to connect to {0}")]
+    Connect(String),
+    #[error(transparent)]
+    Tls(#[from] TlsError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error("{0}")]
+    Quic(String),
+    #[error(transparent)]
+    Odoh(#[from] OdohError),
+    #[error(transparent)]
+    DnsCrypt(#[from] DnsCryptError),
+    #[error("an ODoH relay needs an https:// resolver as its target")]
+    RelayTarget,
+}
+
+/// Settings shared by the encrypted upstream protocols.
+pub struct UpstreamOptions {
+    /// Plain DNS server used to look up upstream hostnames.
+    pub bootstrap: SocketAddr,
+    /// PEM file of trusted roots replacing the system store.
+    pub ca: Option<PathBuf>,
+    pub pins: Vec<SpkiPin>,
+    /// Number of TLS connections kept open to the upstream.
+    pub connections: usize,
+    /// ODoH relay to send queries for an https:// upstream through.
+    pub odoh_relay: Option<String>,
+}
+
+/// The resolver queries are forwarded to. Replies arrive on the channel
+/// given to [`Upstream::connect`] in whatever order the upstream sends them.
+pub struct Upstream {
+    url: String,
+    kind: Kind,
+    replies: mpsc::Sender<Vec<u8>>,
+    log_tx: broadcast::Sender<String>,
+}
+
+enum Kind {
+    Udp(Arc<UdpUpstream>),
+    Tls(Arc<TlsPool>),
+    Https(Arc<HttpsUpstream>),
+    Oblivious(Arc<ObliviousUpstream>),
+    Quic(Arc<QuicUpstream>),
+    DnsCrypt(Arc<DnsCryptUpstream>),
+}
+
+impl Upstream {
+    /// Sets up forwarding to `url`, which is `host:port` or `udp://host:port`
+    /// for plain DNS, `tls://host[:port]`, `https://host[:port]/path`,
+    /// `quic://host[:port]` or an `sdns://` DNSCrypt stamp. Encrypted
+    /// connections are opened on first use.
+    pub async fn connect(
+        url: &str,
+        options: &UpstreamOptions,
+        replies: mpsc::Sender<Vec<u8>>,
+        log_tx: broadcast::Sender<String>,
+    ) -> Result<Self, UpstreamError> {
+        let invalid = || UpstreamError::InvalidUrl(url.to_string());
+        let (scheme, rest) = url.split_once("://").unwrap_or(("udp", url));
+
+        let kind = match scheme {
+            "udp" => {
+                let (host, port) = split_host_port(rest, 53).ok_or_else(invalid)?;
+                let ip = bootstrap(&host, options.bootstrap).await?[0];
+                let addr = SocketAddr::new(ip, port);
+                let udp = Arc::new(UdpUpstream {
+                    addr,
+                    socket: UdpSocket::bind(unspecified(addr)).await?,
+                    in_flight: std::sync::Mutex::new(HashMap::new()),
+                });
+                tokio::spawn(udp.clone().read_replies(
+                    url.to_string(),
+                    replies.clone(),
+                    log_tx.clone(),
+                ));
+                Kind::Udp(udp)
+            }
+            "tls" => {
+                let (host, port) = split_host_port(rest, 853).ok_or_else(invalid)?;
+                let config = tls::client_config(options.ca.as_deref(), &options.pins, &[b"dot"])?;
+                Kind::Tls(Arc::new(TlsPool {
+                    server_name: ServerName::try_from(host.clone()).map_err(|_| invalid())?,
+                    host,
+                    port,
+                    bootstrap: options.bootstrap,
+                    connector: TlsConnector::from(Arc::new(config)),
+                    slots: (0..options.connections.max(1))
+                        .map(|_| std::sync::Mutex::new(None))
+                        .collect(),
+                    next: AtomicUsize::new(0),
+                }))
+            }
+            "https" => {
+                let parsed = reqwest::Url::parse(url).map_err(|_| invalid())?;
+                let host = parsed.host_str().ok_or_else(invalid)?;
+                let client = https_client(options)?;
+                match &options.odoh_relay {
+                    Some(relay) => {
+                        let target_host = match parsed.port() {
+                            Some(port) => format!("{}:{}", host, port),
+                            None => host.to_string(),
+                        };
+                        let mut relay = reqwest::Url::parse(relay)
+                            .map_err(|_| UpstreamError::InvalidUrl(relay.clone()))?;
+                        relay
+                            .query_pairs_mut()
+                            .append_pair("targethost", &target_host)
+                            .append_pair("targetpath", parsed.path());
+                        let configs_url = parsed
+                            .join("/.well-known/odohconfigs")
+                            .map_err(|_| invalid())?;
+                        Kind::Oblivious(Arc::new(ObliviousUpstream {
+                            client,
+                            relay,
+                            configs_url,
+                            config: Mutex::new(None),
+                        }))
+                    }
+                    None => Kind::Https(Arc::new(HttpsUpstream {
+                        url: parsed,
+                        client,
+                    })),
+                }
+            }
+            "quic" => {
+                let (host, port) = split_host_port(rest, 853).ok_or_else(invalid)?;
+                let config = tls::client_config(options.ca.as_deref(), &options.pins, &[b"doq"])?;
+                let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(config)
+                    .map_err(|e| UpstreamError::Quic(e.to_string()))?;
+                Kind::Quic(Arc::new(QuicUpstream {
+                    host,
+                    port,
+                    bootstrap: options.bootstrap,
+                    config: quinn::ClientConfig::new(Arc::new(crypto)),
+                    connection: Mutex::new(None),
+                }))
+            }
+            "sdns" => {
+                let stamp = Stamp::parse(url)?;
+                let (host, port) = split_host_port(&stamp.addr, 443).ok_or_else(invalid)?;
+                let ip = bootstrap(&host, options.bootstrap).await?[0];
+                let addr = SocketAddr::new(ip, port);
+                let dnscrypt = Arc::new(DnsCryptUpstream {
+                    addr,
+                    socket: UdpSocket::bind(unspecified(addr)).await?,
+                    stamp,
+                    secret: crypto_box::SecretKey::from_bytes(crate::hpke::random_bytes()),
+                    session: Mutex::new(None),
+                    in_flight: std::sync::Mutex::new(HashMap::new()),
+                });
+                tokio::spawn(dnscrypt.clone().read_replies(
+                    url.to_string(),
+                    replies.clone(),
+                    log_tx.clone(),
+                ));
+                Kind::DnsCrypt(dnscrypt)
+            }
+            _ => return Err(invalid()),
+        };
+        if options.odoh_relay.is_some() && !matches!(kind, Kind::Oblivious(_)) {
+            return Err(UpstreamError::RelayTarget);
+        }
+
+        Ok(Upstream {
+            url: url.to_string(),
+            kind,
+            replies,
+            log_tx,
+        })
+    }
+
+    /// Sends `query` upstream without waiting for its reply. Failures that
+    /// only show up after the query has been handed off are logged.
+    pub async fn send(&self, query: Vec<u8>) -> Result<(), UpstreamError> {
+        match &self.kind {
+            Kind::Udp(udp) => udp.send(query).await?,
+            Kind::Tls(pool) => pool.send(query, &self.replies).await?,
+            Kind::Https(https) => {
+                let https = https.clone();
+                let exchange = async move { https.exchange(query).await };
+                self.spawn_exchange(exchange);
+            }
+            Kind::Oblivious(oblivious) => {
+                let oblivious = oblivious.clone();
+                let exchange = async move { oblivious.exchange(query).await };
+                self.spawn_exchange(exchange);
+            }
+            Kind::Quic(quic) => {
+                let quic = quic.clone();
+                let exchange = async move { quic.exchange(query).await };
+                self.spawn_exchange(exchange);
+            }
+            Kind::DnsCrypt(dnscrypt) => dnscrypt.send(query).await?,
+        }
+        Ok(())
+    }
+
+    fn spawn_exchange<F>(&self, exchange: F)
+    where
+        F: Future<Output = Result<Vec<u8>, UpstreamError>> + Send + 'static,
+    {
+        spawn_exchange(
+            self.url.clone(),
+            self.replies.clone(),
+            self.log_tx.clone(),
+            exchange,
+        );
+    }
+}
+
+/// Runs `exchange` in the background, forwarding its reply or logging
+/// why there is none.
+fn spawn_exchange<F>(
+    url: String,
+    replies: mpsc::Sender<Vec<u8>>,
+    log_tx: broadcast::Sender<String>,
+    exchange: F,
+) where
+    F: Future<Output = Result<Vec<u8>, UpstreamError>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        match exchange.await {
+            Ok(reply) => {
+                let _ = replies.send(reply).await;
+            }
+            Err(e) => {
+                let timestamp = Local::now().format("%H:%M:%S");
+                let _ = log_tx.send(format!("[{}] Upstream {} failed: {}", timestamp, url, e));
+            }
+        }
+    });
+}
+
+/// Checks that `url` names an upstream [`Upstream::connect`] accepts,
+/// without looking anything up.
+pub fn check_url(url: &str) -> Result<(), UpstreamError> {
+    let invalid = || UpstreamError::InvalidUrl(url.to_string());
+    let (scheme, rest) = url.split_once("://").unwrap_or(("udp", url));
+    match scheme {
+        "udp" | "tls" | "quic" => split_host_port(rest, 53).map(drop).ok_or_else(invalid),
+        "https" => reqwest::Url::parse(url)
+            .ok()
+            .and_then(|parsed| parsed.host_str().map(drop))
+            .ok_or_else(invalid),
+        "sdns" => Ok(Stamp::parse(url).map(drop)?),
+        _ => Err(invalid()),
+    }
+}
+
+/// Splits `host[:port]`, accepting bracketed IPv6 addresses.
+fn split_host_port(s: &str, default_port: u16) -> Option<(String, u16)> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Some((addr.ip().to_string(), addr.port()));
+    }
+    if let Some(ip) = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+        return Some((ip.parse::<IpAddr>().ok()?.to_string(), default_port));
+    }
+    if s.parse::<IpAddr>().is_ok() {
+        return Some((s.to_string(), default_port));
+    }
+    let (host, port) = match s.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (s, default_port),
+    };
+    let valid = !host.is_empty() && !host.contains(['/', '[', ']']);
+    valid.then(|| (host.to_string(), port))
+}
+
+/// The wildcard address of the same family as `addr`, for binding to.
+fn unspecified(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+/// Looks up `host` with plain queries to `server`, so that an encrypted
+/// upstream can be named without depending on the system resolver, which
+/// may well be us. IP literals are returned as they are.
+async fn bootstrap(host: &str, server: SocketAddr) -> Result<Vec<IpAddr>, UpstreamError> {
+    if let Ok(ip) = host.parse() {
+        return Ok(vec![ip]);
+    }
+    let failed = |reason: String| UpstreamError::Bootstrap {
+        host: host.to_string(),
+        server,
+        reason,
+    };
+
+    let socket = UdpSocket::bind(unspecified(server)).await?;
+    socket.connect(server).await?;
+    let base_id = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos() as u16);
+
+    let mut addrs = Vec::new();
+    for (offset, tp) in [(0, TYPE_A), (1, TYPE_AAAA)] {
+        let id = base_id.wrapping_add(offset);
+        let question = Question {
+            name: name_from_str(host),
+            tp,
+            class: 1,
+        };
+        socket
+            .send(&DNSPacket::query(id, question).to_bytes())
+            .await?;
+
+        let mut buf = [0; 512];
+        let reply = loop {
+            let size = tokio::time::timeout(CONNECT_TIMEOUT, socket.recv(&mut buf))
+                .await
+                .map_err(|_| failed("timed out".to_string()))??;
+            if size >= 12
+                && u16::from_be_bytes([buf[0], buf[1]]) == id
+                && let Ok(reply) = DNSPacket::from_bytes(&buf[..size])
+            {
+                break reply;
+            }
+        };
+        addrs.extend(
+            reply
+                .answers
+                .iter()
+                .filter(|answer| answer.tp == tp)
+                .filter_map(|answer| answer.ip()),
+        );
+    }
+
+    if addrs.is_empty() {
+        return Err(failed("no addresses".to_string()));
+    }
+    Ok(addrs)
+}
+
+/// Opens a TCP connection to the first of `ips` that answers.
+async fn connect_tcp(host: &str, ips: &[IpAddr], port: u16) -> Result<TcpStream, UpstreamError> {
+    for ip in ips {
+        let addr = SocketAddr::new(*ip, port);
+        if let Ok(Ok(stream)) =
+            tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await
+        {
+            return Ok(stream);
+        }
+    }
+    Err(UpstreamError::Connect(format!("{}:{}", host, port)))
+}
+
+/// Plain DNS over UDP, retrying over TCP when a reply comes back
+/// truncated.
+struct UdpUpstream {
+    addr: SocketAddr,
+    socket: UdpSocket,
+    /// Queries sent and not yet answered, by ID. IDs are unique among
+    /// pending queries, so a reused one replaces a query long since given
+    /// up on and the map never outgrows the ID space.
+    in_flight: std::sync::Mutex<HashMap<u16, Vec<u8>>>,
+}
+
+impl UdpUpstream {
+    async fn send(&self, query: Vec<u8>) -> Result<(), UpstreamError> {
+        if query.len() >= 2 {
+            let id = u16::from_be_bytes([query[0], query[1]]);
+            self.in_flight.lock().unwrap().insert(id, query.clone());
+        }
+        self.socket.send_to(&query, self.addr).await?;
+        Ok(())
+    }
+
+    /// Forwards replies, ignoring anyone but the upstream.
+    async fn read_replies(
+        self: Arc<Self>,
+        url: String,
+        replies: mpsc::Sender<Vec<u8>>,
+        log_tx: broadcast::Sender<String>,
+    ) {
+        // Clients' EDNS payload sizes are passed on, so a reply may be as
+        // large as any datagram.
+        let mut buf = vec![0; MAX_UDP_REPLY];
+        loop {
+            let (size, source) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    log_receive_error(&log_tx, self.addr, &e).await;
+                    continue;
+                }
+            };
+            if source != self.addr || size < 12 {
+                continue;
+            }
+            let reply = buf[..size].to_vec();
+            let id = u16::from_be_bytes([reply[0], reply[1]]);
+            let query = self.in_flight.lock().unwrap().remove(&id);
+
+            // The TC bit: the reply did not fit, so ask again over TCP.
+            if reply[2] & 0x02 != 0
+                && let Some(query) = query
+            {
+                let upstream = self.clone();
+                let exchange = async move { upstream.exchange_tcp(&query).await };
+                spawn_exchange(url.clone(), replies.clone(), log_tx.clone(), exchange);
+                continue;
+            }
+            if replies.send(reply).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn exchange_tcp(&self, query: &[u8]) -> Result<Vec<u8>, UpstreamError> {
+        let host = self.addr.ip().to_string();
+        let mut stream = connect_tcp(&host, &[self.addr.ip()], self.addr.port()).await?;
+        tokio::time::timeout(QUERY_TIMEOUT, async {
+            write_frame(&mut stream, query).await?;
+            read_frame(&mut stream).await
+        })
+        .await
+        .map_err(|_| UpstreamError::Connect(self.addr.to_string()))??
+        .ok_or_else(|| UpstreamError::Connect(self.addr.to_string()))
+    }
+}
+
+/// Logs a failed read of upstream replies, pausing unless the error was
+/// about a single packet.
+async fn log_receive_error(
+    log_tx: &broadcast::Sender<String>,
+    upstream: SocketAddr,
+    e: &io::Error,
+) {
+    let timestamp = Local::now().format("%H:%M:%S");
+    let _ = log_tx.send(format!(
+        "[{}] Receiving from upstream {} failed: {}",
+        timestamp, upstream, e
+    ));
+    if !is_transient(e) {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// DNS-over-TLS (RFC 7858) connections, used in turn so that one slow
+/// connection does not hold up every query. Each connection is pipelined
+/// and reopened the next time it is needed after the server closes it.
+struct TlsPool {
+    host: String,
+    port: u16,
+    server_name: ServerName<'static>,
+    bootstrap: SocketAddr,
+    connector: TlsConnector,
+    /// The queue feeding each connection's writer, once it is open. Slots
+    /// are only locked to copy the queue in or out, never while connecting,
+    /// so a slow handshake does not hold up queries on the slot.
+    slots: Vec<std::sync::Mutex<Option<mpsc::Sender<Vec<u8>>>>>,
+    next: AtomicUsize,
+}
+
+impl TlsPool {
+    async fn send(
+        &self,
+        query: Vec<u8>,
+        replies: &mpsc::Sender<Vec<u8>>,
+    ) -> Result<(), UpstreamError> {
+        let slot = &self.slots[self.next.fetch_add(1, Ordering::Relaxed) % self.slots.len()];
+        let current = slot.lock().unwrap().clone();
+
+        let query = match current {
+            Some(queue) => match queue.send(query).await {
+                Ok(()) => return Ok(()),
+                Err(mpsc::error::SendError(query)) => query,
+            },
+            None => query,
+        };
+
+        // Queries racing here each open a connection; the last one stored
+        // is kept and the others close once their replies are in.
+        let queue = self.open(replies.clone()).await?;
+        queue
+            .send(query)
+            .await
+            .map_err(|_| UpstreamError::Connect(format!("{}:{}", self.host, self.port)))?;
+        *slot.lock().unwrap() = Some(queue);
+        Ok(())
+    }
+
+    /// Connects and starts the tasks that write queries and read replies.
+    /// The returned queue closes once the connection is gone, and the
+    /// connection is closed once the queue is dropped and the replies owed
+    /// have arrived or timed out.
+    async fn open(
+        &self,
+        replies: mpsc::Sender<Vec<u8>>,
+    ) -> Result<mpsc::Sender<Vec<u8>>, UpstreamError> {
+        let ips = bootstrap(&self.host, self.bootstrap).await?;
+        let stream = connect_tcp(&self.host, &ips, self.port).await?;
+        let stream = tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            self.connector.connect(self.server_name.clone(), stream),
+        )
+        .await
+        .map_err(|_| UpstreamError::Connect(format!("{}:{}", self.host, self.port)))??;
+
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(CONNECTION_QUEUE);
+
+        tokio::spawn(async move {
+            let mut reading = tokio::spawn(async move {
+                while let Ok(Some(reply)) = read_frame(&mut reader).await {
+                    if replies.send(reply).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            loop {
+                tokio::select! {
+                    query = rx.recv() => match query {
+                        Some(query) => {
+                            if write_frame(&mut writer, &query).await.is_err() {
+                                break;
+                            }
+                        }
+                        None => {
+                            let _ = tokio::time::timeout(QUERY_TIMEOUT, &mut reading).await;
+                            break;
+                        }
+                    },
+                    _ = &mut reading => break,
+                }
+            }
+            reading.abort();
+        });
+        Ok(tx)
+    }
+}
+
+/// DNS-over-HTTPS (RFC 8484). The client keeps connections alive and
+/// multiplexes concurrent queries over HTTP/2 on its own.
+struct HttpsUpstream {
+    url: reqwest::Url,
+    client: reqwest::Client,
+}
+
+impl HttpsUpstream {
+    async fn exchange(&self, query: Vec<u8>) -> Result<Vec<u8>, UpstreamError> {
+        let response = self
+            .client
+            .post(self.url.clone())
+            .header("content-type", "application/dns-message")
+            .header("accept", "application/dns-message")
+            .body(query)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.bytes().await?.to_vec())
+    }
+}
+
+/// Oblivious DoH (RFC 9230): queries are encrypted to the target's key and
+/// posted to the relay, which passes them on without saying who sent them.
+struct ObliviousUpstream {
+    client: reqwest::Client,
+    /// The relay's URL, with the target given as query parameters.
+    relay: reqwest::Url,
+    configs_url: reqwest::Url,
+    config: Mutex<Option<Arc<TargetConfig>>>,
+}
+
+impl ObliviousUpstream {
+    /// Sends `query` through the relay. A 401 means the target has rotated
+    /// its key, so the configuration is fetched again and the query retried.
+    async fn exchange(&self, query: Vec<u8>) -> Result<Vec<u8>, UpstreamError> {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let config = self.target_config().await?;
+            let (message, key) = config.encrypt_query(&query);
+            let response = self
+                .client
+                .post(self.relay.clone())
+                .header("content-type", ODOH_MESSAGE)
+                .header("accept", ODOH_MESSAGE)
+                .body(message)
+                .send()
+                .await?;
+            if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+                self.config.lock().await.take();
+                if attempts < 2 {
+                    continue;
+                }
+            }
+            let body = response.error_for_status()?.bytes().await?;
+            return Ok(key.decrypt_response(&body)?);
+        }
+    }
+
+    /// The target's configuration, fetched straight from the target. This
+    /// tells it nothing about our queries, which only arrive via the relay.
+    async fn target_config(&self) -> Result<Arc<TargetConfig>, UpstreamError> {
+        let mut current = self.config.lock().await;
+        if let Some(config) = current.as_ref() {
+            return Ok(config.clone());
+        }
+        let body = self
+            .client
+            .get(self.configs_url.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let config = Arc::new(TargetConfig::parse(&body)?);
+        *current = Some(config.clone());
+        Ok(config)
+    }
+}
+
+/// An HTTP client for talking to upstreams and ODoH targets, verifying
+/// them like the other encrypted upstreams and resolving their names
+/// through the bootstrap server.
+pub fn https_client(options: &UpstreamOptions) -> Result<reqwest::Client, UpstreamError> {
+    let config = tls::client_config(options.ca.as_deref(), &options.pins, &[b"h2", b"http/1.1"])?;
+    Ok(reqwest::Client::builder()
+        .tls_backend_preconfigured(config)
+        .dns_resolver(Arc::new(BootstrapResolver(options.bootstrap)))
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(QUERY_TIMEOUT)
+        .build()?)
+}
+
+/// Resolves the DoH server's hostname through the bootstrap server.
+struct BootstrapResolver(SocketAddr);
+
+impl reqwest::dns::Resolve for BootstrapResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let server = self.0;
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let ips = bootstrap(&host, server).await?;
+            let addrs: reqwest::dns::Addrs =
+                Box::new(ips.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addrs)
+        })
+    }
+}
+
+/// DNS-over-QUIC (RFC 9250) over a single connection, with a stream per
+/// query.
+struct QuicUpstream {
+    host: String,
+    port: u16,
+    bootstrap: SocketAddr,
+    config: quinn::ClientConfig,
+    connection: Mutex<Option<quinn::Connection>>,
+}
+
+impl QuicUpstream {
+    /// Sends `query` on a new stream, reconnecting once if the connection
+    /// has gone away since it was last used.
+    async fn exchange(&self, query: Vec<u8>) -> Result<Vec<u8>, UpstreamError> {
+        let mut last_error = None;
+        for _ in 0..2 {
+            let connection = self.connection().await?;
+            match tokio::time::timeout(QUERY_TIMEOUT, Self::query(&connection, &query)).await {
+                Ok(Ok(reply)) => return Ok(reply),
+                Ok(Err(e)) => last_error = Some(e),
+                Err(_) => return Err(UpstreamError::Quic("query timed out".to_string())),
+            }
+            self.connection.lock().await.take();
+        }
+        Err(last_error.unwrap_or_else(|| UpstreamError::Quic("no connection".to_string())))
+    }
+
+    async fn connection(&self) -> Result<quinn::Connection, UpstreamError> {
+        let mut current = self.connection.lock().await;
+        if let Some(connection) = current.as_ref()
+            && connection.close_reason().is_none()
+        {
+            return Ok(connection.clone());
+        }
+
+        let quic_error = |e: &dyn std::fmt::Display| UpstreamError::Quic(e.to_string());
+        let ips = bootstrap(&self.host, self.bootstrap).await?;
+        let addr = SocketAddr::new(ips[0], self.port);
+        let mut endpoint = quinn::Endpoint::client(unspecified(addr))?;
+        endpoint.set_default_client_config(self.config.clone());
+        let connecting = endpoint
+            .connect(addr, &self.host)
+            .map_err(|e| quic_error(&e))?;
+        let connection = tokio::time::timeout(CONNECT_TIMEOUT, connecting)
+            .await
+            .map_err(|_| UpstreamError::Connect(format!("{}:{}", self.host, self.port)))?
+            .map_err(|e| quic_error(&e))?;
+        *current = Some(connection.clone());
+        Ok(connection)
+    }
+
+    /// RFC 9250 section 4.2.1: the message ID is 0 on the wire, so the
+    /// original one is put back into the reply.
+    async fn query(connection: &quinn::Connection, query: &[u8]) -> Result<Vec<u8>, UpstreamError> {
+        let quic_error = |e: &dyn std::fmt::Display| UpstreamError::Quic(e.to_string());
+        let (mut send, mut recv) = connection.open_bi().await.map_err(|e| quic_error(&e))?;
+
+        let mut frame = Vec::with_capacity(query.len() + 2);
+        frame.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(&query[2..]);
+        send.write_all(&frame).await.map_err(|e| quic_error(&e))?;
+        send.finish().map_err(|e| quic_error(&e))?;
+
+        let mut len = [0u8; 2];
+        recv.read_exact(&mut len)
+            .await
+            .map_err(|e| quic_error(&e))?;
+        let mut reply = vec![0; u16::from_be_bytes(len) as usize];
+        recv.read_exact(&mut reply)
+            .await
+            .map_err(|e| quic_error(&e))?;
+        if reply.len() < 12 {
+            return Err(UpstreamError::Quic("short reply".to_string()));
+        }
+        reply[..2].copy_from_slice(&query[..2]);
+        Ok(reply)
+    }
+}
+
+/// DNSCrypt v2 over UDP, retrying over TCP when a reply comes back
+/// truncated. The resolver's certificate is fetched with a plain TXT query
+/// and checked against the provider key in the stamp.
+struct DnsCryptUpstream {
+    addr: SocketAddr,
+    socket: UdpSocket,
+    stamp: Stamp,
+    secret: crypto_box::SecretKey,
+    session: Mutex<Option<Arc<ClientSession>>>,
+    /// Queries sent over UDP, by the client half of their nonce.
+    in_flight: std::sync::Mutex<HashMap<[u8; HALF_NONCE], InFlight>>,
+}
+
+struct InFlight {
+    query: Vec<u8>,
+    session: Arc<ClientSession>,
+    sent: Instant,
+}
+
+impl DnsCryptUpstream {
+    async fn send(&self, query: Vec<u8>) -> Result<(), UpstreamError> {
+        // Queries that went unanswered may have been encrypted to a key the
+        // resolver no longer has, so its certificate is fetched again.
+        let expired = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            let before = in_flight.len();
+            in_flight.retain(|_, pending| pending.sent.elapsed() < QUERY_TIMEOUT);
+            in_flight.len() < before
+        };
+        if expired {
+            self.session.lock().await.take();
+        }
+
+        let session = self.session().await?;
+        let (message, nonce) = session.encrypt_query(&query, MIN_UDP_QUERY);
+        self.in_flight.lock().unwrap().insert(
+            nonce,
+            InFlight {
+                query,
+                session,
+                sent: Instant::now(),
+            },
+        );
+        self.socket.send_to(&message, self.addr).await?;
+        Ok(())
+    }
+
+    async fn session(&self) -> Result<Arc<ClientSession>, UpstreamError> {
+        let mut current = self.session.lock().await;
+        if let Some(session) = current.as_ref()
+            && session.is_valid()
+        {
+            return Ok(session.clone());
+        }
+        let certificate = self.fetch_certificate().await?;
+        let session = Arc::new(ClientSession::new(certificate, &self.secret)?);
+        *current = Some(session.clone());
+        Ok(session)
+    }
+
+    async fn fetch_certificate(&self) -> Result<dnscrypt::Certificate, UpstreamError> {
+        let socket = UdpSocket::bind(unspecified(self.addr)).await?;
+        socket.connect(self.addr).await?;
+        let id = u16::from_be_bytes(crate::hpke::random_bytes());
+        let question = Question {
+            name: name_from_str(&self.stamp.provider_name),
+            tp: TYPE_TXT,
+            class: 1,
+        };
+        socket
+            .send(&DNSPacket::query(id, question).to_bytes())
+            .await?;
+
+        let mut buf = vec![0; 4096];
+        loop {
+            let size = tokio::time::timeout(CONNECT_TIMEOUT, socket.recv(&mut buf))
+                .await
+                .map_err(|_| UpstreamError::Connect(self.addr.to_string()))??;
+            if size >= 12
+                && u16::from_be_bytes([buf[0], buf[1]]) == id
+                && let Ok(reply) = DNSPacket::from_bytes(&buf[..size])
+            {
+                return Ok(dnscrypt::best_certificate(
+                    &reply.answers,
+                    &self.stamp.provider_pk,
+                )?);
+            }
+        }
+    }
+
+    /// Decrypts replies and forwards them, ignoring anything that does not
+    /// answer a query of ours.
+    async fn read_replies(
+        self: Arc<Self>,
+        url: String,
+        replies: mpsc::Sender<Vec<u8>>,
+        log_tx: broadcast::Sender<String>,
+    ) {
+        let mut buf = vec![0; 4096];
+        loop {
+            let (size, source) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    log_receive_error(&log_tx, self.addr, &e).await;
+                    continue;
+                }
+            };
+            if source != self.addr {
+                continue;
+            }
+            let Some(nonce) = dnscrypt::response_nonce(&buf[..size]) else {
+                continue;
+            };
+            let Some(pending) = self.in_flight.lock().unwrap().remove(&nonce) else {
+                continue;
+            };
+            let Ok(reply) = pending.session.decrypt_response(&nonce, &buf[..size]) else {
+                continue;
+            };
+            if reply.len() < 12 {
+                continue;
+            }
+
+            // The TC bit: the reply did not fit, so ask again over TCP.
+            if reply[2] & 0x02 != 0 {
+                let upstream = self.clone();
+                let exchange = async move { upstream.exchange_tcp(&pending.query).await };
+                spawn_exchange(url.clone(), replies.clone(), log_tx.clone(), exchange);
+                continue;
+            }
+            if replies.send(reply).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn exchange_tcp(&self, query: &[u8]) -> Result<Vec<u8>, UpstreamError> {
+        let session = self.session().await?;
+        let (message, nonce) = session.encrypt_query(query, 0);
+        let host = self.addr.ip().to_string();
+        let mut stream = connect_tcp(&host, &[self.addr.ip()], self.addr.port()).await?;
+        let response = tokio::time::timeout(QUERY_TIMEOUT, async {
+            write_frame(&mut stream, &message).await?;
+            read_frame(&mut stream).await
+        })
+        .await
+        .map_err(|_| UpstreamError::Connect(self.addr.to_string()))??
+        .ok_or_else(|| UpstreamError::Connect(self.addr.to_string()))?;
+        Ok(session.decrypt_response(&nonce, &response)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, Issuer, KeyPair};
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+    use tokio_rustls::{TlsAcceptor, rustls};
+
+    /// A CA and a certificate it issued for 127.0.0.1, written out as PEM.
+    struct Pki {
+        _dir: tempfile::TempDir,
+        ca: PathBuf,
+        cert: PathBuf,
+        key: PathBuf,
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let ca_key = KeyPair::generate().unwrap();
+            let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+            ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = ca_params.self_signed(&ca_key).unwrap();
+
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec!["127.0.0.1".to_string()])
+                .unwrap()
+                .signed_by(&key, &Issuer::new(ca_params, ca_key))
+                .unwrap();
+
+            let pki = Pki {
+                ca: dir.path().join("ca.pem"),
+                cert: dir.path().join("cert.pem"),
+                key: dir.path().join("key.pem"),
+                _dir: dir,
+            };
+            std::fs::write(&pki.ca, ca.pem()).unwrap();
+            std::fs::write(&pki.cert, cert.pem()).unwrap();
+            std::fs::write(&pki.key, key.serialize_pem()).unwrap();
+            pki
+        }
+
+        fn server_config(&self, alpn: &[&[u8]]) -> Arc<rustls::ServerConfig> {
+            tls::server_config(&self.cert, &self.key, alpn).unwrap()
+        }
+    }
+
+    fn options(ca: Option<&Pki>) -> UpstreamOptions {
+        UpstreamOptions {
+            bootstrap: "127.0.0.1:9".parse().unwrap(),
+            ca: ca.map(|pki| pki.ca.clone()),
+            pins: Vec::new(),
+            connections: 1,
+            odoh_relay: None,
+        }
+    }
+
+    fn query(id: u16) -> Vec<u8> {
+        let question = Question {
+            name: name_from_str("example.com"),
+            tp: TYPE_A,
+            class: 1,
+        };
+        DNSPacket::query(id, question).to_bytes()
+    }
+
+    /// `query` turned into an empty reply.
+    fn answer(query: &[u8]) -> Vec<u8> {
+        let mut reply = query.to_vec();
+        reply[2] |= 0x80;
+        reply
+    }
+
+    /// Sends the queries with `ids` through `url` and returns the IDs of
+    /// the replies, in order.
+    async fn exchange(url: &str, options: &UpstreamOptions, ids: &[u16]) -> Vec<u16> {
+        let (replies_tx, mut replies) = mpsc::channel(16);
+        let (log_tx, _) = broadcast::channel(16);
+        let upstream = Upstream::connect(url, options, replies_tx, log_tx)
+            .await
+            .unwrap();
+        for &id in ids {
+            upstream.send(query(id)).await.unwrap();
+        }
+        let mut received = Vec::new();
+        for _ in ids {
+            let reply = tokio::time::timeout(QUERY_TIMEOUT, replies.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_ne!(reply[2] & 0x80, 0);
+            received.push(u16::from_be_bytes([reply[0], reply[1]]));
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            while let Ok((size, client)) = server.recv_from(&mut buf).await {
+                let _ = server.send_to(&answer(&buf[..size]), client).await;
+            }
+        });
+
+        let received = exchange(&format!("udp://{}", addr), &options(None), &[1, 2]).await;
+        assert_eq!(received, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn udp_retries_truncated_replies_over_tcp() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let listener = TcpListener::bind(addr).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            while let Ok((size, client)) = server.recv_from(&mut buf).await {
+                let mut reply = answer(&buf[..size]);
+                reply[2] |= 0x02;
+                let _ = server.send_to(&reply, client).await;
+            }
+        });
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                if let Ok(Some(query)) = read_frame(&mut stream).await {
+                    let _ = write_frame(&mut stream, &answer(&query)).await;
+                }
+            }
+        });
+
+        let received = exchange(&addr.to_string(), &options(None), &[7]).await;
+        assert_eq!(received, [7]);
+    }
+
+    #[tokio::test]
+    async fn tls_pipelines_and_reconnects() {
+        let pki = Pki::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = TlsAcceptor::from(pki.server_config(&[b"dot"]));
+        // Each connection answers two queries, the second one first, and
+        // is then closed.
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut stream = acceptor.accept(stream).await.unwrap();
+                let first = read_frame(&mut stream).await.unwrap().unwrap();
+                let second = read_frame(&mut stream).await.unwrap().unwrap();
+                write_frame(&mut stream, &answer(&second)).await.unwrap();
+                write_frame(&mut stream, &answer(&first)).await.unwrap();
+                let _ = stream.shutdown().await;
+            }
+        });
+
+        let url = format!("tls://{}", addr);
+        let options = options(Some(&pki));
+        let (replies_tx, mut replies) = mpsc::channel(16);
+        let (log_tx, _) = broadcast::channel(16);
+        let upstream = Upstream::connect(&url, &options, replies_tx, log_tx)
+            .await
+            .unwrap();
+        for ids in [[1, 2], [3, 4]] {
+            for id in ids {
+                upstream.send(query(id)).await.unwrap();
+            }
+            let mut received = Vec::new();
+            for _ in ids {
+                let reply = tokio::time::timeout(QUERY_TIMEOUT, replies.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                received.push(u16::from_be_bytes([reply[0], reply[1]]));
+            }
+            assert_eq!(received, [ids[1], ids[0]]);
+            // Let the pool notice the connection is gone.
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn tls_rejects_unknown_certificates() {
+        let pki = Pki::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = TlsAcceptor::from(pki.server_config(&[b"dot"]));
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let _ = acceptor.accept(stream).await;
+            }
+        });
+
+        let (replies_tx, _replies) = mpsc::channel(16);
+        let (log_tx, _) = broadcast::channel(16);
+        let other = Pki::new();
+        let upstream = Upstream::connect(
+            &format!("tls://{}", addr),
+            &options(Some(&other)),
+            replies_tx,
+            log_tx,
+        )
+        .await
+        .unwrap();
+        assert!(upstream.send(query(1)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn https() {
+        let pki = Pki::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = TlsAcceptor::from(pki.server_config(&[b"h2", b"http/1.1"]));
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let stream = acceptor.accept(stream).await.unwrap();
+                    let service = hyper::service::service_fn(|request| async move {
+                        let body = hyper::body::to_bytes(request.into_body()).await?;
+                        Ok::<_, hyper::Error>(hyper::Response::new(hyper::Body::from(answer(
+                            &body,
+                        ))))
+                    });
+                    let _ = hyper::server::conn::Http::new()
+                        .serve_connection(stream, service)
+                        .await;
+                });
+            }
+        });
+
+        let url = format!("https://{}/dns-query", addr);
+        let received = exchange(&url, &options(Some(&pki)), &[1, 2]).await;
+        assert_eq!(received.len(), 2);
+        assert!(received.contains(&1) && received.contains(&2));
+    }
+
+    #[tokio::test]
+    async fn quic_restores_message_ids() {
+        let pki = Pki::new();
+        let tls = pki.server_config(&[crate::doq::ALPN_DOQ]);
+        let config = crate::doq::server_config(&tls, false, Duration::from_secs(10)).unwrap();
+        let endpoint = quinn::Endpoint::server(config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                let Ok(connection) = incoming.await else {
+                    continue;
+                };
+                tokio::spawn(async move {
+                    while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+                        let frame = recv.read_to_end(65537).await.unwrap();
+                        assert_eq!(frame[2..4], [0, 0]);
+                        let reply = answer(&frame[2..]);
+                        send.write_all(&(reply.len() as u16).to_be_bytes())
+                            .await
+                            .unwrap();
+                        send.write_all(&reply).await.unwrap();
+                        send.finish().unwrap();
+                    }
+                });
+            }
+        });
+
+        let received = exchange(&format!("quic://{}", addr), &options(Some(&pki)), &[5]).await;
+        assert_eq!(received, [5]);
+    }
+}
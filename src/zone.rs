@@ -0,0 +1,966 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    sync::{Mutex, RwLock},
+};
+
+use thiserror::Error;
+
+use crate::packet::{
+    Answer, Question, RCODE_NOERROR, RCODE_NXDOMAIN, TYPE_A, TYPE_AAAA, TYPE_ANY, TYPE_CNAME,
+    TYPE_MX, TYPE_NS, TYPE_PTR, TYPE_SOA, TYPE_SRV, TYPE_TXT, check_name, name_from_str,
+    name_to_string,
+};
+use crate::rebind::is_internal;
+
+/// TTL of records from hosts files, which have no way to specify one.
+const HOSTS_TTL: u32 = 300;
+/// Longest CNAME chain followed inside local data.
+const MAX_CNAME_CHAIN: usize = 8;
+
+#[derive(Debug, Error)]
+pub enum ZoneError {
+    #[error("failed to read {path}: {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("{path}:{line}: {message}")]
+    Parse {
+        path: String,
+        line: usize,
+        message: String,
+    },
+}
+
+/// An authoritative answer from local data.
+pub struct LocalAnswer {
+    pub rcode: u8,
+    pub answers: Vec<Answer>,
+    pub authorities: Vec<Answer>,
+}
+
+/// A zone loaded from a master file. Every name under `origin` is answered
+/// locally, with NXDOMAIN for names that do not exist.
+#[derive(Clone)]
+pub struct Zone {
+    origin: String,
+    soa: Answer,
+    records: HashMap<String, Vec<Answer>>,
+}
+
+impl Zone {
+    /// An empty zone with a made-up SOA, filled from hosts files.
+    fn synthetic(origin: String) -> Self {
+        let soa = Answer::soa(&name_from_str(&origin), HOSTS_TTL);
+        Self {
+            records: HashMap::from([(origin.clone(), vec![soa.clone()])]),
+            origin,
+            soa,
+        }
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        is_within(name, &self.origin)
+    }
+
+    /// The SOA to put in the authority section of negative answers; its
+    /// TTL is capped by the MINIMUM field as RFC 2308 asks.
+    fn negative_soa(&self) -> Answer {
+        let mut soa = self.soa.clone();
+        let minimum = soa
+            .data
+            .get(soa.data.len() - 4..)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .unwrap_or(soa.ttl);
+        soa.ttl = soa.ttl.min(minimum);
+        soa
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Vec<Answer>> {
+        if let Some(records) = self.records.get(name) {
+            return Some(records);
+        }
+        // Wildcards only apply to names that do not exist at all.
+        if self.has_descendants(name) {
+            return None;
+        }
+        let mut parent = name;
+        while let Some((_, rest)) = parent.split_once('.') {
+            if !is_within(rest, &self.origin) {
+                break;
+            }
+            if let Some(records) = self.records.get(&format!("*.{}", rest)) {
+                return Some(records);
+            }
+            if self.records.contains_key(rest) {
+                break;
+            }
+            parent = rest;
+        }
+        None
+    }
+
+    fn has_descendants(&self, name: &str) -> bool {
+        let suffix = format!(".{}", name);
+        self.records.keys().any(|k| k.ends_with(&suffix))
+    }
+}
+
+/// Locally served records: zones from master files plus single names from
+/// hosts files and DHCP leases. Queries for names outside them go on to
+/// cache and upstream.
+pub struct LocalZones {
+    sources: Mutex<ZoneSources>,
+    data: RwLock<ZoneData>,
+}
+
+/// What the served records are built from.
+struct ZoneSources {
+    /// Records from zone and hosts files.
+    base: ZoneData,
+    /// Names, addresses and TTLs learned from DHCP leases.
+    leases: Vec<(String, IpAddr, u32)>,
+}
+
+impl ZoneSources {
+    fn build(&self) -> ZoneData {
+        let mut data = self.base.clone();
+        for (name, ip, ttl) in &self.leases {
+            data.add_host(name, *ip, *ttl);
+        }
+        data
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct ZoneData {
+    zones: Vec<Zone>,
+    /// Hosts-file names; only these exact names are answered locally.
+    hosts: HashMap<String, Vec<Answer>>,
+    /// Names and TTLs of the forward records for each address, from which
+    /// PTR answers are synthesized.
+    reverse: HashMap<IpAddr, Vec<(String, u32)>>,
+}
+
+impl ZoneData {
+    /// Loads the given master files and hosts files. A zone spec is either
+    /// `origin=path` or a path whose file sets `$ORIGIN`. Each of
+    /// `local_domains` becomes an otherwise empty zone for hosts-file names.
+    pub fn load(
+        zone_specs: &[String],
+        local_domains: &[String],
+        hosts_files: &[String],
+    ) -> Result<Self, ZoneError> {
+        let mut data = Self::default();
+        for spec in zone_specs {
+            let (origin, path) = match spec.split_once('=') {
+                Some((origin, path)) => (Some(normalize(origin)), path),
+                None => (None, spec.as_str()),
+            };
+            data.zones.push(parse_zone_file(Path::new(path), origin)?);
+        }
+        for domain in local_domains {
+            let origin = normalize(domain);
+            if !data.zones.iter().any(|z| z.origin == origin) {
+                data.zones.push(Zone::synthetic(origin));
+            }
+        }
+        // Most specific zone first so lookups pick the deepest match.
+        data.zones
+            .sort_by_key(|z| std::cmp::Reverse(z.origin.matches('.').count()));
+
+        for zone in &data.zones {
+            for (name, records) in &zone.records {
+                if name.starts_with("*.") {
+                    continue;
+                }
+                for record in records {
+                    if let Some(ip) = record.ip() {
+                        data.reverse
+                            .entry(ip)
+                            .or_default()
+                            .push((name.clone(), record.ttl));
+                    }
+                }
+            }
+        }
+        // Zone records come out of a map; keep PTR answers stable.
+        for names in data.reverse.values_mut() {
+            names.sort();
+        }
+
+        for path in hosts_files {
+            data.add_hosts_file(Path::new(path))?;
+        }
+        Ok(data)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.zones.is_empty() && self.hosts.is_empty()
+    }
+
+    fn add_hosts_file(&mut self, path: &Path) -> Result<(), ZoneError> {
+        let body = read(path)?;
+        for (line_no, line) in body.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let ip: IpAddr = fields
+                .next()
+                .and_then(|ip| ip.parse().ok())
+                .ok_or_else(|| parse_error(path, line_no + 1, "expected an IP address"))?;
+            for name in fields {
+                check_name(name).map_err(|message| parse_error(path, line_no + 1, message))?;
+                self.add_host(&normalize(name), ip, HOSTS_TTL);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds an address record for `name`, into the zone holding it if any.
+    fn add_host(&mut self, name: &str, ip: IpAddr, ttl: u32) {
+        let names = self.reverse.entry(ip.to_canonical()).or_default();
+        if !names.iter().any(|(n, _)| n == name) {
+            names.push((name.to_string(), ttl));
+        }
+
+        let record = address_record(name, ip, ttl);
+        match self.zones.iter_mut().find(|z| z.contains(name)) {
+            Some(zone) => zone
+                .records
+                .entry(name.to_string())
+                .or_default()
+                .push(record),
+            None => self.hosts.entry(name.to_string()).or_default().push(record),
+        }
+    }
+
+    fn lookup(&self, q: &Question) -> Option<LocalAnswer> {
+        let name = q.domain().to_ascii_lowercase();
+
+        let zone = self.zones.iter().find(|z| z.contains(&name));
+
+        // Synthesized PTRs fill in for names a reverse zone does not have.
+        let reverse_ip = reverse_to_ip(&name);
+        if let Some(names) = reverse_ip.and_then(|ip| self.reverse.get(&ip))
+            && !zone.is_some_and(|z| z.records.contains_key(&name))
+        {
+            return Some(Self::answer_ptr(q, names));
+        }
+
+        if let Some(zone) = zone {
+            return Some(self.answer_from_zone(zone, q, &name));
+        }
+
+        if let Some(records) = self.hosts.get(&name) {
+            return Some(Self::answer_from_hosts(q, records));
+        }
+
+        // Reverse lookups for private addresses mean nothing to public
+        // servers, so once we serve the LAN's names they stop here.
+        match reverse_ip {
+            Some(ip) if is_internal(ip) && !self.is_empty() => Some(LocalAnswer {
+                rcode: RCODE_NXDOMAIN,
+                answers: Vec::new(),
+                authorities: vec![reverse_soa(ip)],
+            }),
+            _ => None,
+        }
+    }
+
+    fn answer_from_hosts(q: &Question, records: &[Answer]) -> LocalAnswer {
+        let answers: Vec<Answer> = records
+            .iter()
+            .filter(|r| r.tp == q.tp || q.tp == TYPE_ANY)
+            .cloned()
+            .collect();
+        let authorities = if answers.is_empty() {
+            vec![Answer::soa(&q.name, HOSTS_TTL)]
+        } else {
+            Vec::new()
+        };
+        LocalAnswer {
+            rcode: RCODE_NOERROR,
+            answers,
+            authorities,
+        }
+    }
+
+    fn answer_ptr(q: &Question, names: &[(String, u32)]) -> LocalAnswer {
+        if q.tp != TYPE_PTR && q.tp != TYPE_ANY {
+            let ip = reverse_to_ip(&q.domain().to_ascii_lowercase());
+            return LocalAnswer {
+                rcode: RCODE_NOERROR,
+                answers: Vec::new(),
+                authorities: ip.map(reverse_soa).into_iter().collect(),
+            };
+        }
+        let answers = names
+            .iter()
+            .map(|(name, ttl)| Answer::new(q.name.clone(), TYPE_PTR, *ttl, name_from_str(name)))
+            .collect();
+        LocalAnswer {
+            rcode: RCODE_NOERROR,
+            answers,
+            authorities: Vec::new(),
+        }
+    }
+
+    fn answer_from_zone(&self, zone: &Zone, q: &Question, name: &str) -> LocalAnswer {
+        let mut answers = Vec::new();
+        let mut current = name.to_string();
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            let Some(records) = zone.lookup(&current) else {
+                if answers.is_empty() && !zone.has_descendants(&current) {
+                    return LocalAnswer {
+                        rcode: RCODE_NXDOMAIN,
+                        answers,
+                        authorities: vec![zone.negative_soa()],
+                    };
+                }
+                break;
+            };
+
+            let matching: Vec<Answer> = records
+                .iter()
+                .filter(|r| r.tp == q.tp || q.tp == TYPE_ANY)
+                .map(|r| with_owner(r, &current))
+                .collect();
+            if !matching.is_empty() {
+                answers.extend(matching);
+                break;
+            }
+
+            // Follow a CNAME while its target stays inside this zone.
+            let Some(cname) = records.iter().find(|r| r.tp == TYPE_CNAME) else {
+                break;
+            };
+            answers.push(with_owner(cname, &current));
+            current = name_to_string(&cname.data).to_ascii_lowercase();
+            if !zone.contains(&current) {
+                break;
+            }
+        }
+
+        let authorities = if answers.is_empty() {
+            vec![zone.negative_soa()]
+        } else {
+            Vec::new()
+        };
+        LocalAnswer {
+            rcode: RCODE_NOERROR,
+            answers,
+            authorities,
+        }
+    }
+}
+
+impl LocalZones {
+    pub fn new(data: ZoneData) -> Self {
+        Self {
+            data: RwLock::new(data.clone()),
+            sources: Mutex::new(ZoneSources {
+                base: data,
+                leases: Vec::new(),
+            }),
+        }
+    }
+
+    /// Replaces the records learned from DHCP leases with `hosts`, given as
+    /// fully qualified names and their addresses.
+    pub fn set_leases(&self, hosts: &[(String, IpAddr)], ttl: u32) {
+        let mut sources = self.sources.lock().unwrap();
+        sources.leases = hosts
+            .iter()
+            .map(|(name, ip)| (normalize(name), *ip, ttl))
+            .collect();
+        *self.data.write().unwrap() = sources.build();
+    }
+
+    /// Replaces the records from zone and hosts files, keeping the leases.
+    pub fn set_base(&self, base: ZoneData) {
+        let mut sources = self.sources.lock().unwrap();
+        sources.base = base;
+        *self.data.write().unwrap() = sources.build();
+    }
+
+    /// Answers `q` from local data, or `None` if it is not ours to answer.
+    pub fn lookup(&self, q: &Question) -> Option<LocalAnswer> {
+        self.data.read().unwrap().lookup(q)
+    }
+}
+
+/// Wildcard matches are answered with the queried name as owner.
+fn with_owner(record: &Answer, name: &str) -> Answer {
+    let mut record = record.clone();
+    if name_to_string(&record.name).starts_with("*.") {
+        record.name = name_from_str(name);
+    }
+    record
+}
+
+fn address_record(name: &str, ip: IpAddr, ttl: u32) -> Answer {
+    match ip {
+        IpAddr::V4(ip) => Answer::new(name_from_str(name), TYPE_A, ttl, ip.octets().to_vec()),
+        IpAddr::V6(ip) => Answer::new(name_from_str(name), TYPE_AAAA, ttl, ip.octets().to_vec()),
+    }
+}
+
+/// Parses a full `in-addr.arpa` or `ip6.arpa` name into its address.
+fn reverse_to_ip(name: &str) -> Option<IpAddr> {
+    if let Some(labels) = name.strip_suffix(".in-addr.arpa") {
+        let octets: Vec<u8> = labels
+            .split('.')
+            .rev()
+            .map(|l| l.parse().ok())
+            .collect::<Option<_>>()?;
+        let octets: [u8; 4] = octets.try_into().ok()?;
+        return Some(IpAddr::from(octets));
+    }
+    let labels = name.strip_suffix(".ip6.arpa")?;
+    let nibbles: Vec<u8> = labels
+        .split('.')
+        .rev()
+        .map(|l| match l.len() {
+            1 => u8::from_str_radix(l, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    if nibbles.len() != 32 {
+        return None;
+    }
+    let mut octets = [0u8; 16];
+    for (octet, pair) in octets.iter_mut().zip(nibbles.chunks(2)) {
+        *octet = pair[0] << 4 | pair[1];
+    }
+    Some(IpAddr::from(octets).to_canonical())
+}
+
+/// A synthetic SOA for negative reverse answers, owned by the reverse
+/// zone covering the first octet of `ip`.
+fn reverse_soa(ip: IpAddr) -> Answer {
+    let apex = match ip {
+        IpAddr::V4(ip) => format!("{}.in-addr.arpa", ip.octets()[0]),
+        IpAddr::V6(ip) => {
+            let first = ip.octets()[0];
+            format!("{:x}.{:x}.ip6.arpa", first & 0x0f, first >> 4)
+        }
+    };
+    Answer::soa(&name_from_str(&apex), HOSTS_TTL)
+}
+
+fn is_within(name: &str, origin: &str) -> bool {
+    name == origin || name.ends_with(&format!(".{}", origin))
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn read(path: &Path) -> Result<String, ZoneError> {
+    std::fs::read_to_string(path).map_err(|source| ZoneError::Read {
+        path: path.display().to_string(),
+        source,
+    })
+}
+
+fn parse_error(path: &Path, line: usize, message: impl Into<String>) -> ZoneError {
+    ZoneError::Parse {
+        path: path.display().to_string(),
+        line,
+        message: message.into(),
+    }
+}
+
+/// One logical master-file entry: parentheses can span physical lines.
+struct Entry {
+    line: usize,
+    /// Whether the entry starts with whitespace, i.e. reuses the last owner.
+    inherits_owner: bool,
+    tokens: Vec<String>,
+}
+
+/// Splits a master file into entries, handling comments, quoted strings
+/// and parenthesised continuations.
+fn tokenize(body: &str, path: &Path) -> Result<Vec<Entry>, ZoneError> {
+    let mut entries = Vec::new();
+    let mut current: Option<Entry> = None;
+    let mut depth = 0;
+
+    for (index, line) in body.lines().enumerate() {
+        let line_no = index + 1;
+        if depth == 0 {
+            if let Some(entry) = current.take()
+                && !entry.tokens.is_empty()
+            {
+                entries.push(entry);
+            }
+            current = Some(Entry {
+                line: line_no,
+                inherits_owner: line.starts_with([' ', '\t']),
+                tokens: Vec::new(),
+            });
+        }
+        let entry = current.as_mut().unwrap();
+
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                ';' => break,
+                '(' => depth += 1,
+                ')' => {
+                    if depth == 0 {
+                        return Err(parse_error(path, line_no, "unbalanced ')'"));
+                    }
+                    depth -= 1;
+                }
+                '"' => {
+                    let mut token = String::from('"');
+                    loop {
+                        match chars.next() {
+                            Some('\\') => token.extend(chars.next()),
+                            Some('"') => break,
+                            Some(c) => token.push(c),
+                            None => return Err(parse_error(path, line_no, "unterminated string")),
+                        }
+                    }
+                    entry.tokens.push(token);
+                }
+                c if c.is_whitespace() => {}
+                c => {
+                    let mut token = String::from(c);
+                    while let Some(&next) = chars.peek() {
+                        if next.is_whitespace() || matches!(next, ';' | '(' | ')' | '"') {
+                            break;
+                        }
+                        token.push(next);
+                        chars.next();
+                    }
+                    entry.tokens.push(token);
+                }
+            }
+        }
+    }
+
+    if depth != 0 {
+        return Err(parse_error(path, body.lines().count(), "unbalanced '('"));
+    }
+    if let Some(entry) = current
+        && !entry.tokens.is_empty()
+    {
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Parses an RFC 1035 master file holding a single zone.
+fn parse_zone_file(path: &Path, origin: Option<String>) -> Result<Zone, ZoneError> {
+    let body = read(path)?;
+    let entries = tokenize(&body, path)?;
+
+    let mut origin = origin;
+    let mut default_ttl: Option<u32> = None;
+    let mut last_owner: Option<String> = None;
+    let mut last_ttl: Option<u32> = None;
+    let mut zone_origin: Option<String> = None;
+    let mut soa: Option<Answer> = None;
+    let mut records: HashMap<String, Vec<Answer>> = HashMap::new();
+
+    for entry in entries {
+        let err = |message: String| parse_error(path, entry.line, message);
+        let mut tokens = entry.tokens.iter().map(String::as_str).peekable();
+
+        match tokens.peek().copied() {
+            Some("$ORIGIN") => {
+                tokens.next();
+                let name = tokens
+                    .next()
+                    .ok_or_else(|| err("$ORIGIN needs a name".into()))?;
+                origin = Some(absolute(name, origin.as_deref()).map_err(err)?);
+                continue;
+            }
+            Some("$TTL") => {
+                tokens.next();
+                let ttl = tokens
+                    .next()
+                    .and_then(parse_ttl)
+                    .ok_or_else(|| err("$TTL needs a duration".into()))?;
+                default_ttl = Some(ttl);
+                continue;
+            }
+            Some(directive) if directive.starts_with('$') => {
+                return Err(err(format!("unsupported directive {}", directive)));
+            }
+            _ => {}
+        }
+
+        let owner = if entry.inherits_owner {
+            last_owner
+                .clone()
+                .ok_or_else(|| err("record without an owner name".into()))?
+        } else {
+            let name = tokens.next().unwrap();
+            absolute(name, origin.as_deref()).map_err(err)?
+        };
+
+        // TTL and class may come in either order and are both optional.
+        let mut ttl = None;
+        let mut tp = None;
+        for token in tokens.by_ref() {
+            if let Some(value) = parse_ttl(token) {
+                ttl = Some(value);
+            } else if token.eq_ignore_ascii_case("IN") {
+                continue;
+            } else {
+                tp = Some(token.to_ascii_uppercase());
+                break;
+            }
+        }
+        let tp = tp.ok_or_else(|| err("missing record type".into()))?;
+        let ttl = ttl
+            .or(default_ttl)
+            .or(last_ttl)
+            .ok_or_else(|| err("no TTL given and no $TTL set".into()))?;
+        let rdata: Vec<&str> = tokens.collect();
+
+        let (code, data) = encode_rdata(&tp, &rdata, origin.as_deref()).map_err(err)?;
+        let record = Answer::new(name_from_str(&owner), code, ttl, data);
+
+        if code == TYPE_SOA {
+            if soa.is_some() {
+                return Err(err("more than one SOA record".into()));
+            }
+            zone_origin = Some(owner.clone());
+            soa = Some(record.clone());
+        }
+
+        records.entry(owner.clone()).or_default().push(record);
+        last_owner = Some(owner);
+        last_ttl = Some(ttl);
+    }
+
+    let soa = soa.ok_or_else(|| parse_error(path, 1, "zone has no SOA record"))?;
+    let origin = zone_origin.unwrap();
+    if let Some(outside) = records.keys().find(|name| !is_within(name, &origin)) {
+        return Err(parse_error(
+            path,
+            1,
+            format!("{} is outside the zone {}", outside, origin),
+        ));
+    }
+
+    Ok(Zone {
+        origin,
+        soa,
+        records,
+    })
+}
+
+/// Resolves a possibly relative master-file name against `origin`,
+/// refusing names too long for the wire.
+fn absolute(name: &str, origin: Option<&str>) -> Result<String, String> {
+    let name = if name == "@" {
+        origin
+            .map(str::to_string)
+            .ok_or_else(|| "'@' used before $ORIGIN".to_string())?
+    } else if name.ends_with('.') {
+        normalize(name)
+    } else {
+        match origin {
+            Some(origin) => normalize(&format!("{}.{}", name, origin)),
+            None => return Err(format!("relative name {} used before $ORIGIN", name)),
+        }
+    };
+    check_name(&name)?;
+    Ok(name)
+}
+
+/// Parses a TTL given in seconds or with unit suffixes such as `1h30m`.
+fn parse_ttl(token: &str) -> Option<u32> {
+    if !token.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let mut total: u32 = 0;
+    let mut value: u32 = 0;
+    for c in token.chars() {
+        match c.to_ascii_lowercase() {
+            d @ '0'..='9' => value = value.checked_mul(10)?.checked_add(d as u32 - '0' as u32)?,
+            unit => {
+                let factor = match unit {
+                    's' => 1,
+                    'm' => 60,
+                    'h' => 3600,
+                    'd' => 86400,
+                    'w' => 604800,
+                    _ => return None,
+                };
+                total = total.checked_add(value.checked_mul(factor)?)?;
+                value = 0;
+            }
+        }
+    }
+    total.checked_add(value)
+}
+
+fn encode_rdata(tp: &str, rdata: &[&str], origin: Option<&str>) -> Result<(u16, Vec<u8>), String> {
+    let want = |n: usize| {
+        if rdata.len() == n {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} record needs {} field(s), got {}",
+                tp,
+                n,
+                rdata.len()
+            ))
+        }
+    };
+    let name = |s: &str| absolute(s, origin).map(|n| name_from_str(&n));
+    let number = |s: &str| {
+        s.parse::<u16>()
+            .map_err(|_| format!("invalid number {} in {} record", s, tp))
+    };
+
+    match tp {
+        "A" => {
+            want(1)?;
+            let ip: Ipv4Addr = rdata[0]
+                .parse()
+                .map_err(|_| format!("invalid IPv4 address {}", rdata[0]))?;
+            Ok((TYPE_A, ip.octets().to_vec()))
+        }
+        "AAAA" => {
+            want(1)?;
+            let ip: Ipv6Addr = rdata[0]
+                .parse()
+                .map_err(|_| format!("invalid IPv6 address {}", rdata[0]))?;
+            Ok((TYPE_AAAA, ip.octets().to_vec()))
+        }
+        "CNAME" | "NS" | "PTR" => {
+            want(1)?;
+            let code = match tp {
+                "CNAME" => TYPE_CNAME,
+                "NS" => TYPE_NS,
+                _ => TYPE_PTR,
+            };
+            Ok((code, name(rdata[0])?))
+        }
+        "MX" => {
+            want(2)?;
+            let mut data = number(rdata[0])?.to_be_bytes().to_vec();
+            data.extend(name(rdata[1])?);
+            Ok((TYPE_MX, data))
+        }
+        "SRV" => {
+            want(4)?;
+            let mut data = Vec::new();
+            for field in &rdata[..3] {
+                data.extend(number(field)?.to_be_bytes());
+            }
+            data.extend(name(rdata[3])?);
+            Ok((TYPE_SRV, data))
+        }
+        "TXT" => {
+            if rdata.is_empty() {
+                return Err("TXT record needs at least one string".into());
+            }
+            let mut data = Vec::new();
+            for s in rdata {
+                let s = s.strip_prefix('"').unwrap_or(s);
+                if s.len() > 255 {
+                    return Err("TXT string longer than 255 bytes".into());
+                }
+                data.push(s.len() as u8);
+                data.extend_from_slice(s.as_bytes());
+            }
+            Ok((TYPE_TXT, data))
+        }
+        "SOA" => {
+            want(7)?;
+            let mut data = name(rdata[0])?;
+            data.extend(name(rdata[1])?);
+            for field in &rdata[2..] {
+                let value =
+                    parse_ttl(field).ok_or_else(|| format!("invalid SOA field {}", field))?;
+                data.extend(value.to_be_bytes());
+            }
+            Ok((TYPE_SOA, data))
+        }
+        other => Err(format!("unsupported record type {}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const ZONE: &str = "\
+$ORIGIN home.
+$TTL 1h
+@       IN SOA ns hostmaster ( 1 3600 600
+                               86400 60 )
+        IN NS  ns
+ns      IN A   192.168.1.1
+nas     300 IN A 192.168.1.10
+        IN AAAA fd00::10
+files   IN CNAME nas
+*.dev   IN A   192.168.1.20
+txt     IN TXT \"hello world\" second
+";
+
+    fn file(body: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(body.as_bytes()).unwrap();
+        file
+    }
+
+    fn path(file: &tempfile::NamedTempFile) -> String {
+        file.path().display().to_string()
+    }
+
+    fn ask(data: &ZoneData, name: &str, tp: u16) -> Option<LocalAnswer> {
+        data.lookup(&Question {
+            name: name_from_str(name),
+            tp,
+            class: 1,
+        })
+    }
+
+    fn addresses(answer: &LocalAnswer) -> Vec<IpAddr> {
+        answer.answers.iter().filter_map(Answer::ip).collect()
+    }
+
+    /// The line of a parse error, or a panic for anything else.
+    fn error_line(result: Result<ZoneData, ZoneError>) -> usize {
+        match result {
+            Err(ZoneError::Parse { line, .. }) => line,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("loaded"),
+        }
+    }
+
+    #[test]
+    fn answers_from_zone_files() {
+        let zone = file(ZONE);
+        let data = ZoneData::load(&[path(&zone)], &[], &[]).unwrap();
+
+        let nas = ask(&data, "NAS.home", TYPE_A).unwrap();
+        assert_eq!(nas.rcode, RCODE_NOERROR);
+        assert_eq!(addresses(&nas), [IpAddr::from([192, 168, 1, 10])]);
+        assert_eq!(nas.answers[0].ttl, 300);
+
+        let files = ask(&data, "files.home", TYPE_AAAA).unwrap();
+        assert_eq!(files.answers[0].tp, TYPE_CNAME);
+        assert_eq!(addresses(&files), ["fd00::10".parse::<IpAddr>().unwrap()]);
+
+        let wildcard = ask(&data, "app.dev.home", TYPE_A).unwrap();
+        assert_eq!(wildcard.answers[0].name, name_from_str("app.dev.home"));
+        assert_eq!(wildcard.answers[0].ttl, 3600);
+
+        let txt = ask(&data, "txt.home", TYPE_TXT).unwrap();
+        assert_eq!(txt.answers[0].data, b"\x0bhello world\x06second");
+
+        assert!(ask(&data, "example.com", TYPE_A).is_none());
+    }
+
+    #[test]
+    fn negative_answers_carry_the_soa() {
+        let zone = file(ZONE);
+        let data = ZoneData::load(&[path(&zone)], &[], &[]).unwrap();
+
+        let missing = ask(&data, "printer.home", TYPE_A).unwrap();
+        assert_eq!(missing.rcode, RCODE_NXDOMAIN);
+        assert_eq!(missing.authorities[0].tp, TYPE_SOA);
+        // Capped by the SOA's MINIMUM.
+        assert_eq!(missing.authorities[0].ttl, 60);
+
+        let nodata = ask(&data, "ns.home", TYPE_AAAA).unwrap();
+        assert_eq!(nodata.rcode, RCODE_NOERROR);
+        assert!(nodata.answers.is_empty());
+        assert_eq!(nodata.authorities[0].tp, TYPE_SOA);
+
+        // Names with descendants exist, so no wildcard and no NXDOMAIN.
+        let empty = ask(&data, "dev.home", TYPE_A).unwrap();
+        assert_eq!(empty.rcode, RCODE_NOERROR);
+        assert!(empty.answers.is_empty());
+    }
+
+    #[test]
+    fn hosts_files_answer_exact_names_and_reverse_lookups() {
+        let hosts = file("192.168.1.30 printer printer.lan # office\n\nfd00::30 printer\n");
+        let data = ZoneData::load(&[], &["lan".to_string()], &[path(&hosts)]).unwrap();
+
+        let printer = ask(&data, "printer", TYPE_A).unwrap();
+        assert_eq!(addresses(&printer), [IpAddr::from([192, 168, 1, 30])]);
+        let in_domain = ask(&data, "printer.lan", TYPE_AAAA).unwrap();
+        assert_eq!(in_domain.rcode, RCODE_NOERROR);
+        assert!(in_domain.answers.is_empty());
+        assert!(ask(&data, "other.printer", TYPE_A).is_none());
+
+        let ptr = ask(&data, "30.1.168.192.in-addr.arpa", TYPE_PTR).unwrap();
+        let names: Vec<String> = ptr
+            .answers
+            .iter()
+            .map(|r| name_to_string(&r.data))
+            .collect();
+        assert_eq!(names, ["printer", "printer.lan"]);
+
+        // Private addresses we know nothing about stay local.
+        let unknown = ask(&data, "99.1.168.192.in-addr.arpa", TYPE_PTR).unwrap();
+        assert_eq!(unknown.rcode, RCODE_NXDOMAIN);
+        assert!(ask(&data, "8.8.8.8.in-addr.arpa", TYPE_PTR).is_none());
+    }
+
+    #[test]
+    fn leases_are_added_to_the_zone_holding_them() {
+        let zones = LocalZones::new(ZoneData::load(&[], &["lan".to_string()], &[]).unwrap());
+        let laptop = IpAddr::from([192, 168, 1, 50]);
+        zones.set_leases(&[("Laptop.lan".to_string(), laptop)], 60);
+
+        let question = |name: &str| Question {
+            name: name_from_str(name),
+            tp: TYPE_A,
+            class: 1,
+        };
+        let answer = zones.lookup(&question("laptop.lan")).unwrap();
+        assert_eq!(addresses(&answer), [laptop]);
+
+        zones.set_leases(&[], 60);
+        let answer = zones.lookup(&question("laptop.lan")).unwrap();
+        assert_eq!(answer.rcode, RCODE_NXDOMAIN);
+    }
+
+    #[test]
+    fn reports_the_line_of_bad_records() {
+        let zone = file(&ZONE.replace("nas     300 IN A 192.168.1.10", "nas IN A 192.168.1"));
+        assert_eq!(error_line(ZoneData::load(&[path(&zone)], &[], &[])), 7);
+
+        let zone = file(&format!("{}{} IN A 192.168.1.40\n", ZONE, "a".repeat(64)));
+        assert_eq!(error_line(ZoneData::load(&[path(&zone)], &[], &[])), 12);
+
+        let long = vec!["a".repeat(63); 4].join(".");
+        let zone = file(&format!("{}x IN CNAME {}.\n", ZONE, long));
+        assert_eq!(error_line(ZoneData::load(&[path(&zone)], &[], &[])), 12);
+
+        let zone = file("$ORIGIN home.\n@ IN SOA ns hostmaster ( 1 2 3 4\n");
+        assert_eq!(error_line(ZoneData::load(&[path(&zone)], &[], &[])), 2);
+
+        let zone = file("$TTL 60\nnas IN A 192.168.1.10\n");
+        assert_eq!(error_line(ZoneData::load(&[path(&zone)], &[], &[])), 2);
+    }
+
+    #[test]
+    fn reports_the_line_of_bad_hosts() {
+        let hosts = file("192.168.1.30 printer\nprinter 192.168.1.31\n");
+        assert_eq!(error_line(ZoneData::load(&[], &[], &[path(&hosts)])), 2);
+
+        let hosts = file(&format!("\n\n192.168.1.30 {}.lan\n", "a".repeat(64)));
+        assert_eq!(error_line(ZoneData::load(&[], &[], &[path(&hosts)])), 3);
+    }
+}